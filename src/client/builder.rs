@@ -0,0 +1,280 @@
+//!
+//! Provides a builder that configures and creates a ClientEndpoint
+//!
+
+use super::{ClientEndpoint, NotificationHandler, InvalidPayloadHandler, PendingRequests, StreamPayloadHandler, StreamWriter, DEFAULT_PAYLOAD_LOG_LIMIT,
+    DEFAULT_PING_METHOD, NEXT_ENDPOINT_NUMBER};
+use super::id::{IdGenerator, SequentialIds};
+use super::rate_limit::RateLimiter;
+use super::retry::RetryPolicy;
+use transport::{ClientTransport, TransportError, WireLogger};
+use codec::{Codec, JSONCodec};
+use chrono::Duration;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use spawn::{Spawn, ThreadSpawner};
+
+///
+/// Configures and creates a ClientEndpoint
+///
+/// Created by ClientEndpoint::builder. Each method sets one option and returns the builder, so
+/// options can be chained before calling build.
+///
+pub struct ClientEndpointBuilder<T> where T: ClientTransport {
+    /// The transport that the endpoint uses
+    transport: T,
+    /// The codec used to encode and decode messages
+    codec: Arc<dyn Codec>,
+    /// The timeout used by methods that do not take a timeout
+    default_timeout: Duration,
+    /// Timeouts that replace the default timeout for some methods
+    method_timeouts: HashMap<String, Duration>,
+    /// The handler for notifications from the server that do not belong to a subscription
+    notification_handler: Option<Box<dyn NotificationHandler>>,
+    /// The handler for payloads from the server that cannot be handled
+    invalid_payload_handler: Option<Box<dyn InvalidPayloadHandler>>,
+    /// The logger that receives every payload, if any
+    wire_logger: Option<Arc<dyn WireLogger>>,
+    /// The generator that assigns request IDs
+    id_generator: Box<dyn IdGenerator>,
+    /// The maximum number of pending requests, or None for no limit
+    max_pending: Option<usize>,
+    /// The policy used to retry idempotent requests
+    retry_policy: RetryPolicy,
+    /// The method of cancellation notifications, or None to not send them
+    cancel_method: Option<String>,
+    /// If true, synchronous requests include their timeouts
+    send_timeouts: bool,
+    /// If true, queued payloads are written together
+    coalesce_writes: bool,
+    /// The maximum number of characters of an invalid payload to log
+    payload_log_limit: usize,
+    /// The rate limiter, or None for no limit
+    rate_limiter: Option<RateLimiter>,
+    /// Starts the writer thread
+    spawner: Box<dyn Spawn>,
+    /// The method of the request that ClientEndpoint::ping sends
+    ping_method: String,
+}
+
+impl<T> ClientEndpointBuilder<T> where T: ClientTransport {
+    /// Creates a builder with the default options
+    pub fn new(transport: T) -> ClientEndpointBuilder<T> {
+        ClientEndpointBuilder {
+            transport,
+            codec: Arc::new(JSONCodec),
+            default_timeout: Duration::seconds(30),
+            method_timeouts: HashMap::new(),
+            notification_handler: None,
+            invalid_payload_handler: None,
+            wire_logger: None,
+            id_generator: Box::new(SequentialIds::new()),
+            max_pending: None,
+            retry_policy: RetryPolicy::default(),
+            cancel_method: None,
+            send_timeouts: false,
+            coalesce_writes: false,
+            payload_log_limit: DEFAULT_PAYLOAD_LOG_LIMIT,
+            rate_limiter: None,
+            spawner: Box::new(ThreadSpawner),
+            ping_method: DEFAULT_PING_METHOD.to_string(),
+        }
+    }
+
+    ///
+    /// Sets the codec used to encode and decode messages. The default is JSONCodec.
+    ///
+    /// If the codec's payloads may contain the transport's delimiter, build returns an error.
+    ///
+    pub fn codec<C>(mut self, codec: C) -> Self where C: Codec {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    ///
+    /// Sets the timeout that ClientEndpoint::call_with_default_timeout uses
+    ///
+    /// The default is 30 seconds.
+    ///
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = timeout;
+        self
+    }
+
+    ///
+    /// Sets the timeout that ClientEndpoint::call_with_default_timeout uses for one method,
+    /// instead of the default timeout
+    ///
+    /// This can be used when some methods take much longer than others.
+    ///
+    pub fn method_timeout(mut self, method: &str, timeout: Duration) -> Self {
+        self.method_timeouts.insert(method.to_string(), timeout);
+        self
+    }
+
+    ///
+    /// Sets the handler for notifications from the server
+    ///
+    /// Notifications that belong to a subscription (see ClientEndpoint::subscribe) are routed to
+    /// the subscription instead. If no handler is set, other notifications are logged and
+    /// discarded.
+    ///
+    pub fn notification_handler<H>(mut self, handler: H) -> Self where H: NotificationHandler {
+        self.notification_handler = Some(Box::new(handler));
+        self
+    }
+
+    ///
+    /// Sets the handler that is notified when a payload from the server cannot be handled
+    ///
+    /// The handler receives the payload and a PayloadError that describes the problem. If no
+    /// handler is set, these payloads are logged and discarded.
+    ///
+    pub fn invalid_payload_handler<H>(mut self, handler: H) -> Self where H: InvalidPayloadHandler {
+        self.invalid_payload_handler = Some(Box::new(handler));
+        self
+    }
+
+    ///
+    /// Sets a logger that receives every payload that the endpoint sends and receives
+    ///
+    /// This can be used to debug protocol problems. By default, there is no logger.
+    ///
+    pub fn wire_logger<L>(mut self, logger: L) -> Self where L: WireLogger {
+        self.wire_logger = Some(Arc::new(logger));
+        self
+    }
+
+    /// Sets the generator that assigns IDs to requests. The default counts up from 0.
+    pub fn id_generator<G>(mut self, generator: G) -> Self where G: IdGenerator {
+        self.id_generator = Box::new(generator);
+        self
+    }
+
+    ///
+    /// Sets the maximum number of requests that can wait for responses at the same time
+    ///
+    /// When the limit is reached, sending another request fails with
+    /// TransportError::TooManyPendingRequests. By default, there is no limit.
+    ///
+    pub fn max_pending_requests(mut self, max: usize) -> Self {
+        self.max_pending = Some(max);
+        self
+    }
+
+    /// Sets the policy used to retry idempotent requests
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Sets the method of the notification sent when a request is cancelled (see ClientEndpoint::set_cancel_method)
+    pub fn cancel_method(mut self, method: &str) -> Self {
+        self.cancel_method = Some(method.to_string());
+        self
+    }
+
+    /// Sets whether synchronous requests include their timeouts (see ClientEndpoint::set_send_timeouts)
+    pub fn send_timeouts(mut self, send_timeouts: bool) -> Self {
+        self.send_timeouts = send_timeouts;
+        self
+    }
+
+    /// Sets whether queued payloads are written together (see ClientEndpoint::set_coalesce_writes)
+    pub fn coalesce_writes(mut self, coalesce: bool) -> Self {
+        self.coalesce_writes = coalesce;
+        self
+    }
+
+    /// Sets the number of characters of an invalid payload that are logged (see ClientEndpoint::set_payload_log_limit)
+    pub fn payload_log_limit(mut self, limit: usize) -> Self {
+        self.payload_log_limit = limit;
+        self
+    }
+
+    /// Sets the method of the request that ClientEndpoint::ping sends (see ClientEndpoint::set_ping_method)
+    pub fn ping_method(mut self, method: &str) -> Self {
+        self.ping_method = method.to_string();
+        self
+    }
+
+    ///
+    /// Limits the rate that payloads are sent (see ClientEndpoint::with_rate_limit)
+    ///
+    /// Panics if rate is not positive or burst is 0.
+    ///
+    pub fn rate_limit(mut self, rate: f64, burst: u32) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(rate, burst));
+        self
+    }
+
+    ///
+    /// Sets the Spawn used to start the thread that writes payloads
+    ///
+    /// The default is ThreadSpawner. The transport may start its own threads, which are
+    /// configured separately.
+    ///
+    pub fn spawner<S>(mut self, spawner: S) -> Self where S: Spawn {
+        self.spawner = Box::new(spawner);
+        self
+    }
+
+    ///
+    /// Creates the endpoint
+    ///
+    /// Returns an error if the codec's payloads may contain the delimiter that the transport
+    /// writes after each payload, or if the writer thread could not be started.
+    ///
+    pub fn build(self) -> Result<ClientEndpoint, TransportError> {
+        let mut transport = self.transport;
+        if let Some(delimiter) = transport.delimiter() {
+            if self.codec.may_contain(delimiter) {
+                return Err(TransportError::IOError(io::Error::new(io::ErrorKind::InvalidInput,
+                    "Payloads encoded with this codec may contain the transport's delimiter")));
+            }
+        }
+        let handlers = Arc::new(PendingRequests::new());
+        let connected = Arc::new(AtomicBool::new(true));
+        let payload_log_limit = Arc::new(AtomicUsize::new(self.payload_log_limit));
+        let subscriptions = Arc::new(Mutex::new(HashMap::new()));
+        let payload_handler = StreamPayloadHandler::new(handlers.clone(), self.codec.clone(), connected.clone(),
+            payload_log_limit.clone(), subscriptions.clone(), self.notification_handler, self.invalid_payload_handler,
+            self.wire_logger.clone());
+
+        transport.set_payload_handler(payload_handler);
+
+        // Start a thread to write payloads
+        let (tx, rx) = channel();
+        let coalesce = Arc::new(AtomicBool::new(self.coalesce_writes));
+        let mut writer = StreamWriter::new(transport, rx, coalesce.clone(), connected.clone(), self.wire_logger);
+        let number = NEXT_ENDPOINT_NUMBER.fetch_add(1, Ordering::Relaxed);
+        let name = format!("ClientEndpoint {} writer", number);
+        self.spawner.spawn(name, Box::new(move || {
+            writer.run();
+        })).map_err(TransportError::IOError)?;
+
+        Ok(ClientEndpoint {
+            send_channel: Mutex::new(tx),
+            send_closed: AtomicBool::new(false),
+            close_lock: Mutex::new(()),
+            handlers,
+            id_generator: self.id_generator,
+            codec: self.codec,
+            retry_policy: Mutex::new(self.retry_policy),
+            cancel_method: Mutex::new(self.cancel_method),
+            send_timeouts: AtomicBool::new(self.send_timeouts),
+            coalesce_writes: coalesce,
+            connected,
+            payload_log_limit,
+            rate_limiter: self.rate_limiter.map(Mutex::new),
+            subscriptions,
+            default_timeout: self.default_timeout,
+            method_timeouts: self.method_timeouts,
+            max_pending: self.max_pending,
+            ping_method: Mutex::new(self.ping_method),
+        })
+    }
+}
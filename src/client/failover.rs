@@ -0,0 +1,163 @@
+//!
+//! Provides a client transport that fails over between several transports
+//!
+
+use transport::{ClientTransport, PayloadHandler, TransportError};
+use chrono::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The health of one transport, shared with the payload handler that forwards its payloads
+struct Health {
+    /// The time when the transport last failed, or None if it has not failed
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl Health {
+    fn new() -> Health {
+        Health {
+            failed_at: Mutex::new(None),
+        }
+    }
+
+    /// Records that the transport has failed
+    fn fail(&self) {
+        *self.failed_at.lock().expect("Health mutex poisoned") = Some(Instant::now());
+    }
+
+    /// Records that the transport has sent a payload successfully
+    fn succeed(&self) {
+        *self.failed_at.lock().expect("Health mutex poisoned") = None;
+    }
+
+    ///
+    /// Returns true if the transport should be used
+    ///
+    /// A transport that has failed is used again after retry_after has passed, if retry_after
+    /// is not None.
+    ///
+    fn healthy(&self, retry_after: Option<Duration>) -> bool {
+        match *self.failed_at.lock().expect("Health mutex poisoned") {
+            None => true,
+            Some(failed_at) => match retry_after.and_then(|retry_after| retry_after.to_std().ok()) {
+                Some(retry_after) => failed_at.elapsed() >= retry_after,
+                None => false,
+            },
+        }
+    }
+}
+
+///
+/// A client transport that sends payloads through one of several transports, and fails over to
+/// the next one when a transport fails
+///
+/// Payloads are sent through the current transport until sending fails with an end-of-file error
+/// or the transport reports that it has reached the end of its input. Then the next healthy
+/// transport becomes the current one. A send fails only if every healthy transport fails.
+///
+/// Payloads received from any of the transports are passed to the payload handler. An end of
+/// file is passed to the payload handler only when all of the transports have failed.
+///
+/// Requests that were sent through a transport that failed do not get responses. Use request
+/// timeouts (and, for idempotent requests, a retry policy) to recover from them.
+///
+pub struct FailoverClientTransport<T> where T: ClientTransport {
+    /// The transports, in the order that they are tried
+    transports: Vec<T>,
+    /// The health of each transport
+    health: Arc<Vec<Health>>,
+    /// The index of the current transport
+    current: usize,
+    /// The time after which a failed transport is tried again, or None to never try it again
+    retry_after: Option<Duration>,
+}
+
+impl<T> FailoverClientTransport<T> where T: ClientTransport {
+    ///
+    /// Creates a transport that uses the provided transports, starting with the first one
+    ///
+    /// Failed transports are not tried again.
+    ///
+    pub fn new(transports: Vec<T>) -> FailoverClientTransport<T> {
+        let health = transports.iter().map(|_| Health::new()).collect();
+        FailoverClientTransport {
+            transports,
+            health: Arc::new(health),
+            current: 0,
+            retry_after: None,
+        }
+    }
+
+    ///
+    /// Sets how long after a transport fails it is considered healthy again
+    ///
+    /// If this is None (the default), a transport that fails is never used again.
+    ///
+    pub fn set_retry_after(&mut self, retry_after: Option<Duration>) {
+        self.retry_after = retry_after;
+    }
+
+    /// Returns the index of the transport that payloads are currently sent through
+    pub fn current(&self) -> usize {
+        self.current
+    }
+}
+
+impl<T> ClientTransport for FailoverClientTransport<T> where T: ClientTransport {
+    fn set_payload_handler<H>(&mut self, handler: H) where H: PayloadHandler {
+        let handler: Arc<Mutex<Box<dyn PayloadHandler>>> = Arc::new(Mutex::new(Box::new(handler)));
+        for (index, transport) in self.transports.iter_mut().enumerate() {
+            let handler = handler.clone();
+            let health = self.health.clone();
+            transport.set_payload_handler(move |result: Result<Vec<u8>, TransportError>| {
+                if let Err(TransportError::EndOfFile) = result {
+                    health[index].fail();
+                    if health.iter().any(|health| health.healthy(None)) {
+                        return;
+                    }
+                }
+                let mut handler = handler.lock().expect("Payload handler mutex poisoned");
+                handler.payload_received(result);
+            });
+        }
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), TransportError> {
+        for offset in 0..self.transports.len() {
+            let index = (self.current + offset) % self.transports.len();
+            if !self.health[index].healthy(self.retry_after) {
+                continue;
+            }
+            match self.transports[index].send(payload) {
+                Ok(()) => {
+                    self.health[index].succeed();
+                    self.current = index;
+                    return Ok(());
+                },
+                Err(TransportError::EndOfFile) => {
+                    println!("FailoverClientTransport: Transport {} failed, trying the next one", index);
+                    self.health[index].fail();
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Err(TransportError::EndOfFile)
+    }
+
+    /// Closes the sending side of every transport, and returns the first error
+    fn close_send(&mut self) -> Result<(), TransportError> {
+        let mut result = Ok(());
+        for transport in &mut self.transports {
+            let transport_result = transport.close_send();
+            if result.is_ok() {
+                result = transport_result;
+            }
+        }
+        result
+    }
+
+    /// Returns the delimiter of the first transport that has one
+    fn delimiter(&self) -> Option<u8> {
+        self.transports.iter().find_map(ClientTransport::delimiter)
+    }
+}
@@ -0,0 +1,76 @@
+//!
+//! Provides a Future that resolves to the response to a request
+//!
+//! This module is available when the futures feature is enabled.
+//!
+
+use super::ResponseHandler;
+use transport::TransportError;
+use message::Response;
+use futures::channel::oneshot::{self, Receiver, Sender};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+///
+/// A future that resolves to the response to a request
+///
+/// Returned by ClientEndpoint::send_request_future.
+///
+/// If the request could not be sent, the future resolves to the send error. If the client stops
+/// waiting for the response (for example, because the request was cancelled or the endpoint was
+/// dropped), the future resolves to TransportError::EndOfFile.
+///
+pub struct ResponseFuture {
+    /// The error that occurred while sending the request, if any
+    send_error: Option<TransportError>,
+    /// The receiver that gets the response
+    receiver: Receiver<Response>,
+}
+
+impl ResponseFuture {
+    /// Creates a future and the response handler that completes it
+    pub(super) fn new() -> (ResponseFuture, FutureHandler) {
+        let (sender, receiver) = oneshot::channel();
+        let future = ResponseFuture {
+            send_error: None,
+            receiver,
+        };
+        (future, FutureHandler { sender: Some(sender) })
+    }
+
+    /// Makes this future resolve to an error that occurred while sending the request
+    pub(super) fn set_send_error(&mut self, error: TransportError) {
+        self.send_error = Some(error);
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Response, TransportError>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Some(error) = self.send_error.take() {
+            return Poll::Ready(Err(error));
+        }
+        match Pin::new(&mut self.receiver).poll(context) {
+            Poll::Ready(Ok(response)) => Poll::Ready(Ok(response)),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(TransportError::EndOfFile)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A response handler that completes a ResponseFuture
+pub struct FutureHandler {
+    /// The sender, or None if a response has already been sent
+    sender: Option<Sender<Response>>,
+}
+
+impl ResponseHandler for FutureHandler {
+    fn response_received(&mut self, response: Response) {
+        if let Some(sender) = self.sender.take() {
+            // If the future has been dropped, nobody is waiting for the response
+            let _ = sender.send(response);
+        }
+    }
+}
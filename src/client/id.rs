@@ -0,0 +1,55 @@
+//!
+//! Provides the generators that assign IDs to requests
+//!
+
+use super::RequestID;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+///
+/// Trait for things that choose the IDs of requests
+///
+/// A generator is called once for each request that a ClientEndpoint sends. It should not return
+/// the ID of a request that is still waiting for a response.
+///
+pub trait IdGenerator: 'static + Send + Sync {
+    /// Returns the ID for the next request
+    fn next_id(&self) -> RequestID;
+}
+
+/// IdGenerator implementation for closures
+impl<F> IdGenerator for F where F: Fn() -> RequestID, F: 'static + Send + Sync {
+    fn next_id(&self) -> RequestID {
+        self()
+    }
+}
+
+///
+/// An ID generator that counts up from a starting ID
+///
+/// This is the generator that ClientEndpoint uses by default, starting at 0.
+///
+#[derive(Debug, Default)]
+pub struct SequentialIds {
+    /// The next ID to return
+    next: AtomicU64,
+}
+
+impl SequentialIds {
+    /// Creates a generator that starts at 0
+    pub fn new() -> SequentialIds {
+        SequentialIds::starting_at(0)
+    }
+
+    /// Creates a generator that starts at the provided ID
+    pub fn starting_at(first: RequestID) -> SequentialIds {
+        SequentialIds {
+            next: AtomicU64::new(first),
+        }
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&self) -> RequestID {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,306 @@
+//!
+//! Provides a mock server for testing client code without a real server
+//!
+
+use transport::{ClientTransport, PayloadHandler, TransportError};
+use message::{Request, Response, Error, Value};
+use chrono::Duration;
+use serde_json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+///
+/// A canned response that a MockServer sends
+///
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+    /// A successful response with the provided result
+    Result(Value),
+    /// An error response
+    Error(Error),
+    /// A payload that is sent exactly as provided, with no ID added. This can be used to
+    /// simulate malformed responses.
+    Raw(String),
+    /// No response is sent
+    NoResponse,
+    ///
+    /// Sending the request fails with an end-of-file error, as if the connection had been lost
+    ///
+    /// This models a disconnection, not a temporary error. A ClientEndpoint stops writing after
+    /// it, so the request gets no response and later requests fail with
+    /// TransportError::EndOfFile. To test recovery, use the transport behind a
+    /// FailoverClientTransport or ReconnectingClientTransport.
+    ///
+    SendFailure,
+    /// The response is sent after the provided delay
+    Delayed(Duration, Box<MockResponse>),
+}
+
+/// The state of a mock server, shared with its transports
+struct MockState {
+    /// Responses to requests for each method
+    method_responses: HashMap<String, MockResponse>,
+    /// Responses to requests with specific IDs. These take precedence over method responses.
+    id_responses: Vec<(Value, MockResponse)>,
+    /// Requests and notifications that have been received
+    received: Vec<Request>,
+}
+
+impl MockState {
+    /// Finds the canned response for a request
+    fn response_for(&self, request: &Request) -> MockResponse {
+        if let Some(ref id) = request.id {
+            for (response_id, response) in &self.id_responses {
+                if response_id == id {
+                    return response.clone();
+                }
+            }
+        }
+        match self.method_responses.get(&request.method) {
+            Some(response) => response.clone(),
+            None => MockResponse::Error(Error::method_not_found()),
+        }
+    }
+}
+
+///
+/// A mock server that responds to requests with canned responses
+///
+/// A test programs the server with responses, creates a ClientEndpoint with a transport from
+/// the server, and then checks the requests that the server received.
+///
+/// Requests for methods that have no canned response get a method not found error.
+/// Notifications are recorded but never get responses.
+///
+/// A batch gets one array of responses, which is sent after the longest delay of the responses
+/// in it. Raw responses to requests in a batch are sent as separate payloads.
+///
+/// A MockServer can be cloned. All clones share the same responses and received requests.
+///
+#[derive(Clone)]
+pub struct MockServer {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockServer {
+    /// Creates a mock server with no canned responses
+    pub fn new() -> MockServer {
+        MockServer {
+            state: Arc::new(Mutex::new(MockState {
+                method_responses: HashMap::new(),
+                id_responses: Vec::new(),
+                received: Vec::new(),
+            })),
+        }
+    }
+
+    /// Sets the response to requests for a method
+    pub fn respond_to_method(&self, method: &str, response: MockResponse) {
+        self.lock().method_responses.insert(method.to_string(), response);
+    }
+
+    /// Sets the response to a request with a specific ID
+    pub fn respond_to_id(&self, id: Value, response: MockResponse) {
+        let mut state = self.lock();
+        state.id_responses.retain(|(response_id, _)| *response_id != id);
+        state.id_responses.push((id, response));
+    }
+
+    /// Returns the requests and notifications that have been received, in the order they were sent
+    pub fn received(&self) -> Vec<Request> {
+        self.lock().received.clone()
+    }
+
+    /// Creates a transport that sends requests to this server
+    pub fn transport(&self) -> MockTransport {
+        MockTransport {
+            server: self.clone(),
+            handler: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().expect("Mock server mutex poisoned")
+    }
+}
+
+impl Default for MockServer {
+    fn default() -> MockServer {
+        MockServer::new()
+    }
+}
+
+/// The payload handler of a mock transport, shared with the threads that deliver responses
+type SharedHandler = Arc<Mutex<Option<Box<dyn PayloadHandler>>>>;
+
+///
+/// A client transport that sends requests to a MockServer
+///
+/// Each response is delivered on a separate thread, so send does not block.
+///
+pub struct MockTransport {
+    /// The server that receives requests
+    server: MockServer,
+    /// The handler that receives responses
+    handler: SharedHandler,
+}
+
+impl ClientTransport for MockTransport {
+    fn set_payload_handler<H>(&mut self, handler: H) where H: PayloadHandler {
+        let mut shared = self.handler.lock().expect("Mock transport mutex poisoned");
+        *shared = Some(Box::new(handler));
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), TransportError> {
+        match serde_json::from_slice(payload)? {
+            Value::Array(elements) => self.send_batch(elements),
+            json => self.send_request(json),
+        }
+    }
+}
+
+impl MockTransport {
+    /// Records a request or notification and starts delivering its response
+    fn send_request(&mut self, json: Value) -> Result<(), TransportError> {
+        let request = match Request::from_json(json) {
+            Ok(request) => request,
+            Err(_) => return Err(TransportError::ParseError("Payload is not a request".to_string())),
+        };
+        let response = {
+            let mut state = self.server.lock();
+            state.received.push(request.clone());
+            state.response_for(&request)
+        };
+        if request.is_notification() {
+            return Ok(());
+        }
+        let id = request.id.unwrap_or(Value::Null);
+        match response {
+            MockResponse::SendFailure => Err(TransportError::EndOfFile),
+            response => self.spawn_responder(move |handler| deliver(handler, id, response)),
+        }
+    }
+
+    ///
+    /// Records the requests and notifications in a batch and starts delivering their responses
+    ///
+    /// An empty batch or an element that is not a request gets an invalid request error with a
+    /// null ID, as a server would send.
+    ///
+    fn send_batch(&mut self, elements: Vec<Value>) -> Result<(), TransportError> {
+        if elements.is_empty() {
+            let response = MockResponse::Error(Error::invalid_request());
+            return self.spawn_responder(move |handler| deliver(handler, Value::Null, response));
+        }
+        let mut responses = Vec::new();
+        {
+            let mut state = self.server.lock();
+            for element in elements {
+                match Request::from_json(element) {
+                    Ok(request) => {
+                        state.received.push(request.clone());
+                        if let Some(id) = request.id.clone() {
+                            responses.push((id, state.response_for(&request)));
+                        }
+                    },
+                    Err(_) => responses.push((Value::Null, MockResponse::Error(Error::invalid_request()))),
+                }
+            }
+        }
+        if responses.iter().any(|(_, response)| resolve(response.clone()).1.is_send_failure()) {
+            return Err(TransportError::EndOfFile);
+        }
+        if responses.is_empty() {
+            return Ok(());
+        }
+        self.spawn_responder(move |handler| deliver_batch(handler, responses))
+    }
+
+    /// Starts a thread that delivers responses to the payload handler
+    fn spawn_responder<F>(&self, deliver: F) -> Result<(), TransportError> where F: FnOnce(&SharedHandler), F: 'static + Send {
+        let handler = self.handler.clone();
+        thread::Builder::new().name("MockTransport responder".to_string()).spawn(move || {
+            deliver(&handler);
+        }).map_err(TransportError::IOError)?;
+        Ok(())
+    }
+}
+
+impl MockResponse {
+    /// Returns true if this is SendFailure
+    fn is_send_failure(&self) -> bool {
+        matches!(*self, MockResponse::SendFailure)
+    }
+}
+
+/// Removes the delays from a canned response, and returns the total delay and the response
+fn resolve(response: MockResponse) -> (StdDuration, MockResponse) {
+    match response {
+        MockResponse::Delayed(delay, response) => {
+            let (inner_delay, response) = resolve(*response);
+            (delay.to_std().unwrap_or_default() + inner_delay, response)
+        },
+        response => (StdDuration::from_secs(0), response),
+    }
+}
+
+/// Sends a canned response to a handler
+fn deliver(handler: &SharedHandler, id: Value, response: MockResponse) {
+    let (delay, response) = resolve(response);
+    thread::sleep(delay);
+    let payload = match response {
+        MockResponse::Result(result) => response_payload(id, Ok(result)),
+        MockResponse::Error(error) => response_payload(id, Err(error)),
+        MockResponse::Raw(payload) => payload,
+        MockResponse::NoResponse | MockResponse::SendFailure | MockResponse::Delayed(..) => return,
+    };
+    deliver_payload(handler, payload);
+}
+
+/// Sends the canned responses to the requests in a batch to a handler
+fn deliver_batch(handler: &SharedHandler, responses: Vec<(Value, MockResponse)>) {
+    let mut delay = StdDuration::from_secs(0);
+    let mut batch = Vec::new();
+    let mut raw = Vec::new();
+    for (id, response) in responses {
+        let (response_delay, response) = resolve(response);
+        delay = delay.max(response_delay);
+        let payload = match response {
+            MockResponse::Result(result) => Ok(result),
+            MockResponse::Error(error) => Err(error),
+            MockResponse::Raw(payload) => {
+                raw.push(payload);
+                continue;
+            },
+            MockResponse::NoResponse | MockResponse::SendFailure | MockResponse::Delayed(..) => continue,
+        };
+        let mut response = Response::new(payload);
+        response.set_id(id);
+        batch.push(response.to_json());
+    }
+    thread::sleep(delay);
+    for payload in raw {
+        deliver_payload(handler, payload);
+    }
+    if !batch.is_empty() {
+        deliver_payload(handler, serde_json::to_string(&Value::Array(batch)).expect("Failed to encode response"));
+    }
+}
+
+/// Passes a payload to a handler
+fn deliver_payload(handler: &SharedHandler, payload: String) {
+    let mut handler = handler.lock().expect("Mock transport mutex poisoned");
+    match *handler {
+        Some(ref mut handler) => handler.payload_received(Ok(payload.into_bytes())),
+        None => println!("MockTransport: No payload handler set, dropping response"),
+    }
+}
+
+/// Encodes a response with the provided ID
+fn response_payload(id: Value, payload: Result<Value, Error>) -> String {
+    let mut response = Response::new(payload);
+    response.set_id(id);
+    serde_json::to_string(&response.to_json()).expect("Failed to encode response")
+}
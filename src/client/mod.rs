@@ -2,14 +2,35 @@
 //! Provdes a client endpoint
 //!
 pub mod stream;
-use std::collections::HashMap;
-use transport::{ClientTransport, PayloadHandler};
+pub mod retry;
+pub mod mock;
+pub mod failover;
+pub mod rate_limit;
+pub mod id;
+pub mod builder;
+pub mod reconnect;
+#[cfg(feature = "futures")]
+pub mod future;
+use std::collections::{BTreeMap, HashMap};
+use transport::{ClientTransport, PayloadHandler, WireLogger, Direction};
 use transport::TransportError;
 use message::*;
+use codec::Codec;
+use self::retry::RetryPolicy;
+use self::rate_limit::RateLimiter;
+use self::id::IdGenerator;
+use self::builder::ClientEndpointBuilder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json;
 use chrono::{Duration, Local};
-use std::sync::{Arc, Mutex};
+use std::error;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration as StdDuration;
 use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
 
 ///
@@ -18,6 +39,16 @@ use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
 pub trait ResponseHandler: 'static + Send {
     /// Called with a response from the server
     fn response_received(&mut self, response: Response);
+
+    ///
+    /// Called with a response from the server and the method of the request that it answers
+    ///
+    /// The default implementation ignores the method and calls response_received.
+    ///
+    fn method_response_received(&mut self, method: &str, response: Response) {
+        let _ = method;
+        self.response_received(response)
+    }
 }
 
 /// ResponseHandler implementation for closures
@@ -27,19 +58,297 @@ impl<F> ResponseHandler for F where F: Fn(Response), F: 'static + Send {
     }
 }
 
+///
+/// Trait for things that can receive notifications from the server
+///
+pub trait NotificationHandler: 'static + Send + Sync {
+    /// Called with a notification from the server
+    fn notification_received(&mut self, notification: Request);
+}
+
+/// NotificationHandler implementation for closures
+impl<F> NotificationHandler for F where F: Fn(Request), F: 'static + Send + Sync {
+    fn notification_received(&mut self, notification: Request) {
+        self(notification)
+    }
+}
+
+///
+/// The reason that a payload from the server, or a message in it, could not be handled
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayloadError {
+    /// The payload could not be decoded. Contains a description of the problem from the codec.
+    InvalidJson(String),
+    /// The payload, or an element of a batch, is not an object
+    NotAnObject,
+    /// A notification from the server is not a valid request object
+    InvalidNotification,
+    /// A response does not have an ID
+    MissingId,
+    /// A response has both a result and an error
+    ResultAndError,
+    /// A response has neither a result nor an error
+    NoResultOrError,
+    /// The error in a response is not a valid error object
+    InvalidError,
+    /// A response has an ID that does not belong to any pending request. Contains the ID.
+    UnknownId(Value),
+    /// An error response has a null ID and more than one request is pending, so the request that
+    /// it answers cannot be determined
+    AmbiguousNullId,
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PayloadError::InvalidJson(ref reason) => write!(f, "Invalid JSON: {}", reason),
+            PayloadError::NotAnObject => write!(f, "Message is not an object"),
+            PayloadError::InvalidNotification => write!(f, "Invalid notification"),
+            PayloadError::MissingId => write!(f, "Response has no ID"),
+            PayloadError::ResultAndError => write!(f, "Response has both a result and an error"),
+            PayloadError::NoResultOrError => write!(f, "Response has neither a result nor an error"),
+            PayloadError::InvalidError => write!(f, "Response has an invalid error object"),
+            PayloadError::UnknownId(ref id) => write!(f, "No pending request with ID {}", id),
+            PayloadError::AmbiguousNullId => write!(f, "Error response with null ID matches more than one pending request"),
+        }
+    }
+}
+
+///
+/// Trait for things that are notified when a payload from the server cannot be handled
+///
+pub trait InvalidPayloadHandler: 'static + Send + Sync {
+    ///
+    /// Called with a payload and the reason that it, or a message in it, could not be handled
+    ///
+    /// For a batch, this is called once for each message that could not be handled.
+    ///
+    fn invalid_payload_received(&mut self, payload: &[u8], error: PayloadError);
+}
+
+/// InvalidPayloadHandler implementation for closures
+impl<F> InvalidPayloadHandler for F where F: Fn(&[u8], PayloadError), F: 'static + Send + Sync {
+    fn invalid_payload_received(&mut self, payload: &[u8], error: PayloadError) {
+        self(payload, error)
+    }
+}
+
+///
+/// An error that can occur when calling a method
+///
+#[derive(Debug)]
+pub enum CallError {
+    /// The request could not be sent, or no response was received
+    Transport(TransportError),
+    /// The server responded with an error
+    Rpc(Error),
+    /// The result in the response could not be converted into the requested type
+    Deserialize(serde_json::Error),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallError::Transport(ref e) => write!(f, "Transport error: {:?}", e),
+            CallError::Rpc(ref e) => write!(f, "Server returned an error: {:?}", e),
+            CallError::Deserialize(ref e) => write!(f, "Failed to deserialize result: {}", e),
+        }
+    }
+}
+
+impl error::Error for CallError {
+    fn description(&self) -> &str {
+        match *self {
+            CallError::Transport(_) => "Transport error",
+            CallError::Rpc(_) => "Server returned an error",
+            CallError::Deserialize(_) => "Failed to deserialize result",
+        }
+    }
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            CallError::Deserialize(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransportError> for CallError {
+    fn from(e: TransportError) -> Self {
+        CallError::Transport(e)
+    }
+}
+
+impl From<Error> for CallError {
+    fn from(e: Error) -> Self {
+        CallError::Rpc(e)
+    }
+}
+
+impl From<serde_json::Error> for CallError {
+    fn from(e: serde_json::Error) -> Self {
+        CallError::Deserialize(e)
+    }
+}
+
 /// The type used to identify requests
-type RequestID = u64;
+pub type RequestID = u64;
+
+/// A request that has been sent and is waiting for a response
+struct PendingRequest {
+    /// The method of the request
+    method: String,
+    /// The handler to call when the response is received
+    handler: Box<dyn ResponseHandler>,
+    /// The span that tracks this request
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+}
+
+///
+/// The ID of a pending request, used as a key in the pending request map
+///
+/// IDs that the endpoint assigns are numbers. IDs chosen by the application with
+/// ClientEndpoint::send_request_with_id may also be strings or numbers that are not
+/// non-negative integers.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PendingId {
+    /// A non-negative integer ID
+    Number(RequestID),
+    /// Any other ID, encoded as JSON
+    Other(String),
+}
+
+impl PendingId {
+    /// Converts the ID of a request or response into a key
+    fn from_json(id: &Value) -> PendingId {
+        match request_id_from_json(id) {
+            Some(id) => PendingId::Number(id),
+            None => PendingId::Other(id.to_string()),
+        }
+    }
+
+    /// Converts this key back into an ID
+    fn to_json(&self) -> Value {
+        match *self {
+            PendingId::Number(id) => Value::from(id),
+            PendingId::Other(ref json) => serde_json::from_str(json).unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// A mapping from request IDs to pending requests
+type PendingMap = HashMap<PendingId, PendingRequest>;
+
+/// The pending requests, shared between an endpoint and its payload handler
+struct PendingRequests {
+    /// The requests by ID
+    map: Mutex<PendingMap>,
+    /// Notified when the map is unlocked while it is empty
+    idle: Condvar,
+}
+
+impl PendingRequests {
+    fn new() -> PendingRequests {
+        PendingRequests {
+            map: Mutex::new(HashMap::new()),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// Locks the map
+    fn lock(&self) -> PendingGuard<'_> {
+        PendingGuard {
+            map: self.map.lock().expect("Handler mutex poisoned"),
+            idle: &self.idle,
+        }
+    }
+}
+
+/// Access to the locked pending requests, which notifies waiters when it is dropped if no requests are pending
+struct PendingGuard<'a> {
+    map: MutexGuard<'a, PendingMap>,
+    idle: &'a Condvar,
+}
+
+impl<'a> Deref for PendingGuard<'a> {
+    type Target = PendingMap;
+    fn deref(&self) -> &PendingMap {
+        &self.map
+    }
+}
+
+impl<'a> DerefMut for PendingGuard<'a> {
+    fn deref_mut(&mut self) -> &mut PendingMap {
+        &mut self.map
+    }
+}
+
+impl<'a> Drop for PendingGuard<'a> {
+    fn drop(&mut self) {
+        if self.map.is_empty() {
+            self.idle.notify_all();
+        }
+    }
+}
+
+/// A mapping from subscription IDs, encoded as JSON, to the channels that receive their notifications
+type SubscriptionMap = HashMap<String, Sender<Value>>;
+
+/// The default method of the request that ClientEndpoint::ping sends
+const DEFAULT_PING_METHOD: &str = "rpc.ping";
+
+/// The default maximum number of characters of an invalid payload to log
+const DEFAULT_PAYLOAD_LOG_LIMIT: usize = 200;
+
+/// Counter used to give each endpoint's writer thread a unique name
+static NEXT_ENDPOINT_NUMBER: AtomicUsize = AtomicUsize::new(0);
 
 ///
 /// A client endpoint, which can be used to send requests
 ///
+/// A ClientEndpoint can be shared between threads (for example, in an Arc) and used to send
+/// requests concurrently.
+///
 pub struct ClientEndpoint {
     /// Channel used to send payloads to the transport thread
-    send_channel: Sender<String>,
-    /// A mapping from request IDs to response handlers
-    handlers: Arc<Mutex<HashMap<RequestID, Box<ResponseHandler>>>>,
-    /// The next ID to assign to a request
-    next_id: RequestID,
+    send_channel: Mutex<Sender<WriterMessage>>,
+    /// True after close_send has been called, unless closing failed
+    send_closed: AtomicBool,
+    /// Held while close_send is running
+    close_lock: Mutex<()>,
+    /// A mapping from request IDs to pending requests and their response handlers
+    handlers: Arc<PendingRequests>,
+    /// The generator that assigns IDs to requests
+    id_generator: Box<dyn IdGenerator>,
+    /// The codec used to encode requests
+    codec: Arc<dyn Codec>,
+    /// The policy used to retry idempotent requests
+    retry_policy: Mutex<RetryPolicy>,
+    /// The method of the notification sent to the server when a request is cancelled, or None
+    /// to not notify the server
+    cancel_method: Mutex<Option<String>>,
+    /// If true, synchronous requests include their timeouts
+    send_timeouts: AtomicBool,
+    /// If true, the writer thread sends all queued payloads together
+    coalesce_writes: Arc<AtomicBool>,
+    /// False if the transport has reported that the connection has closed
+    connected: Arc<AtomicBool>,
+    /// The maximum number of characters of an invalid payload to log
+    payload_log_limit: Arc<AtomicUsize>,
+    /// The limit on the rate of sending payloads, or None for no limit
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    /// The subscriptions that receive notifications from the server
+    subscriptions: Arc<Mutex<SubscriptionMap>>,
+    /// The timeout used by methods that do not take a timeout
+    default_timeout: Duration,
+    /// Timeouts that replace the default timeout for some methods
+    method_timeouts: HashMap<String, Duration>,
+    /// The maximum number of pending requests, or None for no limit
+    max_pending: Option<usize>,
+    /// The method of the request that ping sends
+    ping_method: Mutex<String>,
 }
 
 impl ClientEndpoint {
@@ -48,53 +357,453 @@ impl ClientEndpoint {
     ///
     /// transport: The transport layer to use
     ///
-    pub fn new<T>(transport: T) -> ClientEndpoint where T: ClientTransport {
-        let mut transport = transport;
-
-        let handlers = Arc::new(Mutex::new(HashMap::new()));
-        let payload_handler = StreamPayloadHandler::new(handlers.clone());
+    /// Returns an error if the writer thread could not be started.
+    ///
+    pub fn new<T>(transport: T) -> Result<ClientEndpoint, TransportError> where T: ClientTransport {
+        ClientEndpoint::builder(transport).build()
+    }
 
-        transport.set_payload_handler(payload_handler);
+    ///
+    /// Returns a builder that can configure a ClientEndpoint that uses the provided transport
+    ///
+    pub fn builder<T>(transport: T) -> ClientEndpointBuilder<T> where T: ClientTransport {
+        ClientEndpointBuilder::new(transport)
+    }
 
-        // Start a thread to write payloads
-        let (tx, rx) = channel();
-        let mut writer = StreamWriter::new(transport, rx);
-        thread::Builder::new().name("ClientEndpoint writer".to_string()).spawn(move || {
-            writer.run();
-        }).unwrap();
+    ///
+    /// Creates a new ClientEndpoint that limits the rate that it sends payloads
+    ///
+    /// On average, at most rate payloads (requests, notifications, or batches) are sent per
+    /// second, with bursts of up to burst payloads. When the limit is reached, the methods that
+    /// send payloads block until they are allowed to send. This can prevent a server that limits
+    /// the rate of requests from rejecting them.
+    ///
+    /// Panics if rate is not positive or burst is 0.
+    ///
+    pub fn with_rate_limit<T>(transport: T, rate: f64, burst: u32) -> Result<ClientEndpoint, TransportError> where T: ClientTransport {
+        ClientEndpoint::builder(transport).rate_limit(rate, burst).build()
+    }
 
-        ClientEndpoint {
-            send_channel: tx,
-            handlers: handlers,
-            next_id: 0,
-        }
+    ///
+    /// Creates a new ClientEndpoint that encodes and decodes messages with the provided codec
+    ///
+    /// Returns an error if the codec's payloads may contain the transport's delimiter, as with
+    /// PrettyJSONCodec and the default newline delimiter.
+    ///
+    pub fn with_codec<T, C>(transport: T, codec: C) -> Result<ClientEndpoint, TransportError> where T: ClientTransport, C: Codec {
+        ClientEndpoint::builder(transport).codec(codec).build()
     }
 
     ///
     /// Sends a request
     ///
-    /// If the request could not be sent, returns an error.
+    /// If the request could not be sent, returns an error. Otherwise, returns the ID assigned
+    /// to the request, which can be used to cancel it.
     ///
     /// The provided response handler will be called if a response is received.
     ///
-    pub fn send_request<R>(&mut self, request: Request, response_handler: R) -> Result<(), TransportError> where R: ResponseHandler {
+    /// Returns TransportError::DuplicateId if the ID generator returns the ID of a request that
+    /// is still waiting for a response.
+    ///
+    pub fn send_request<R>(&self, request: Request, response_handler: R) -> Result<RequestID, TransportError> where R: ResponseHandler {
         // Get the ID to assign
-        let id = self.next_id;
-        self.next_id += 1;
+        let id = self.id_generator.next_id();
+        let mut request = request;
+        request.set_id(Value::from(id));
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::info_span!("jsonrpc_request", method = %request.method, id = id,
+            outcome = ::tracing::field::Empty);
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+        {
+            // Register the handler before sending so that no response can arrive without a handler
+            let mut handlers = self.handlers.lock();
+            self.check_pending_limit(&handlers, 1)?;
+            if handlers.contains_key(&PendingId::Number(id)) {
+                return Err(TransportError::DuplicateId);
+            }
+            handlers.insert(PendingId::Number(id), PendingRequest {
+                method: request.method.clone(),
+                handler: Box::new(response_handler),
+                #[cfg(feature = "tracing")]
+                span: span.clone(),
+            });
+        }
+        if let Err(e) = self.send(request) {
+            let mut handlers = self.handlers.lock();
+            handlers.remove(&PendingId::Number(id));
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    ///
+    /// Sends a request with an ID chosen by the caller
+    ///
+    /// The request is sent with the provided ID instead of one from the ID generator, and the
+    /// handler is called when the response with that ID is received. The ID must be a string or
+    /// a number. Because the endpoint assigns numeric IDs to other requests, callers that mix
+    /// these requests with ordinary requests should use string IDs or numbers from a separate
+    /// range.
+    ///
+    /// Returns TransportError::EncodeError if the ID is not a string or a number, or
+    /// TransportError::DuplicateId if it is the same as the ID of a pending request.
+    ///
+    pub fn send_request_with_id<R>(&self, request: Request, id: Value, response_handler: R) -> Result<(), TransportError> where R: ResponseHandler {
+        if !(id.is_string() || id.is_number()) {
+            return Err(TransportError::EncodeError("Request ID must be a string or a number".to_string()));
+        }
+        let key = PendingId::from_json(&id);
         let mut request = request;
-        request.set_id(Value::U64(id));
-        try!(self.send(request));
-        // Store the handler if the request was sent
-        let mut handlers = self.handlers.lock().ok().expect("Handler mutex poisoned");
-        assert!(!handlers.contains_key(&id));
-        handlers.insert(id, Box::new(response_handler));
+        request.set_id(id);
+        {
+            // Register the handler before sending so that no response can arrive without a handler
+            let mut handlers = self.handlers.lock();
+            if handlers.contains_key(&key) {
+                return Err(TransportError::DuplicateId);
+            }
+            self.check_pending_limit(&handlers, 1)?;
+            handlers.insert(key.clone(), PendingRequest {
+                method: request.method.clone(),
+                handler: Box::new(response_handler),
+                #[cfg(feature = "tracing")]
+                span: ::tracing::info_span!("jsonrpc_request", method = %request.method, id = %key.to_json(),
+                    outcome = ::tracing::field::Empty),
+            });
+        }
+        if let Err(e) = self.send(request) {
+            let mut handlers = self.handlers.lock();
+            handlers.remove(&key);
+            return Err(e);
+        }
         Ok(())
     }
 
+    ///
+    /// Sends several requests in one batch
+    ///
+    /// The requests are sent as one payload containing an array. Each handler is called when the
+    /// response to its request is received. The server may send the responses in one array or
+    /// separately, in any order.
+    ///
+    /// Returns the IDs assigned to the requests, in the same order as the requests. An empty
+    /// batch is not sent. Returns TransportError::DuplicateId, and sends nothing, if any ID is the
+    /// same as the ID of a pending request or of another request in the batch.
+    ///
+    pub fn send_batch(&self, requests: Vec<(Request, Box<dyn ResponseHandler>)>) -> Result<Vec<RequestID>, TransportError> {
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::with_capacity(requests.len());
+        let mut batch = Vec::with_capacity(requests.len());
+        {
+            // Register the handlers before sending so that no response can arrive without a handler
+            let mut handlers = self.handlers.lock();
+            self.check_pending_limit(&handlers, requests.len())?;
+            for (mut request, handler) in requests {
+                let id = self.id_generator.next_id();
+                if handlers.contains_key(&PendingId::Number(id)) {
+                    for &id in &ids {
+                        handlers.remove(&PendingId::Number(id));
+                    }
+                    return Err(TransportError::DuplicateId);
+                }
+                request.set_id(Value::from(id));
+                handlers.insert(PendingId::Number(id), PendingRequest {
+                    method: request.method.clone(),
+                    handler,
+                    #[cfg(feature = "tracing")]
+                    span: ::tracing::info_span!("jsonrpc_request", method = %request.method, id = id,
+                        outcome = ::tracing::field::Empty),
+                });
+                batch.push(request.to_json());
+                ids.push(id);
+            }
+        }
+        if let Err(e) = self.send_json(&Value::Array(batch)) {
+            let mut handlers = self.handlers.lock();
+            for &id in &ids {
+                handlers.remove(&PendingId::Number(id));
+            }
+            return Err(e);
+        }
+        Ok(ids)
+    }
+
+    ///
+    /// Sends several requests in one batch and waits for all their responses
+    ///
+    /// The responses are in the order that they were received, which may be different from the
+    /// order of the requests. IDs are assigned in the order of the requests, and a response can be
+    /// looked up by its ID with BatchResponse::get.
+    ///
+    /// Returns TransportError::TimedOut if any response has not been received when the timeout
+    /// expires.
+    ///
+    pub fn send_batch_sync(&self, requests: Vec<Request>, timeout: &Duration) -> Result<BatchResponse, TransportError> {
+        let count = requests.len();
+        let end = Local::now() + *timeout;
+        let (tx, rx) = channel();
+        let requests = requests.into_iter().map(|request| {
+            let tx = tx.clone();
+            let handler: Box<dyn ResponseHandler> = Box::new(move |response: Response| {
+                if tx.send(response).is_err() {
+                    println!("ClientEndpoint::send_batch_sync: Client thread has hung up");
+                }
+            });
+            (request, handler)
+        }).collect();
+        self.send_batch(requests)?;
+        let mut responses = Vec::with_capacity(count);
+        while responses.len() < count {
+            let remaining = (end - Local::now()).to_std().map_err(|_| TransportError::TimedOut)?;
+            responses.push(rx.recv_timeout(remaining).map_err(|_| TransportError::TimedOut)?);
+        }
+        Ok(BatchResponse::new(responses))
+    }
+
+    ///
+    /// Sends several requests separately and waits for all their responses
+    ///
+    /// Unlike send_batch_sync, each request is sent in its own payload, so this can be used with
+    /// servers that do not support batches. The results are in the same order as the requests.
+    /// A request that could not be sent has the error from sending it, and a request that has
+    /// not received a response when the timeout expires has TransportError::TimedOut.
+    ///
+    pub fn call_all(&self, requests: Vec<Request>, timeout: &Duration) -> Vec<Result<Response, TransportError>> {
+        let end = Local::now() + *timeout;
+        let (tx, rx) = channel();
+        let mut results: Vec<Option<Result<Response, TransportError>>> = Vec::with_capacity(requests.len());
+        let mut remaining = 0;
+        for (index, mut request) in requests.into_iter().enumerate() {
+            if self.send_timeouts.load(Ordering::Relaxed) {
+                request.set_timeout(timeout);
+            }
+            let tx = tx.clone();
+            let sent = self.send_request(request, move |response: Response| {
+                if tx.send((index, response)).is_err() {
+                    println!("ClientEndpoint::call_all: Client thread has hung up");
+                }
+            });
+            match sent {
+                Ok(_) => {
+                    results.push(None);
+                    remaining += 1;
+                },
+                Err(e) => results.push(Some(Err(e))),
+            }
+        }
+        while remaining > 0 {
+            let wait = match (end - Local::now()).to_std() {
+                Ok(wait) => wait,
+                Err(_) => break,
+            };
+            match rx.recv_timeout(wait) {
+                Ok((index, response)) => {
+                    results[index] = Some(Ok(response));
+                    remaining -= 1;
+                },
+                Err(_) => break,
+            }
+        }
+        results.into_iter().map(|result| result.unwrap_or(Err(TransportError::TimedOut))).collect()
+    }
+
+    ///
+    /// Sends a request that has already been encoded
+    ///
+    /// The payload is sent exactly as provided. It must contain a request with an ID that is a
+    /// non-negative integer, and the handler is called when the response with that ID is
+    /// received. The ID must not be the same as the ID of any pending request. The IDs that
+    /// this endpoint assigns to other requests count up from 0, so callers that mix raw and
+    /// ordinary requests should choose raw IDs from a separate range.
+    ///
+    /// Returns TransportError::EncodeError if the payload cannot be decoded or has no suitable ID,
+    /// or TransportError::DuplicateId if it has the same ID as a pending request.
+    ///
+    pub fn send_raw<R>(&self, payload: &[u8], response_handler: R) -> Result<RequestID, TransportError> where R: ResponseHandler {
+        let decoded = self.codec.decode(payload)
+            .map_err(|e| TransportError::EncodeError(format!("Payload could not be decoded: {:?}", e)))?;
+        let (id, method) = match decoded {
+            Value::Object(ref map) => {
+                let id = map.get("id").and_then(request_id_from_json);
+                let method = map.get("method").and_then(|method| method.as_str()).unwrap_or("").to_string();
+                (id, method)
+            },
+            _ => (None, String::new()),
+        };
+        let id = id.ok_or_else(|| TransportError::EncodeError("Payload has no suitable ID".to_string()))?;
+        {
+            let mut handlers = self.handlers.lock();
+            if handlers.contains_key(&PendingId::Number(id)) {
+                return Err(TransportError::DuplicateId);
+            }
+            self.check_pending_limit(&handlers, 1)?;
+            handlers.insert(PendingId::Number(id), PendingRequest {
+                #[cfg(feature = "tracing")]
+                span: ::tracing::info_span!("jsonrpc_request", method = %method, id = id,
+                    outcome = ::tracing::field::Empty),
+                method,
+                handler: Box::new(response_handler),
+            });
+        }
+        if let Err(e) = self.send_payload(payload.to_vec()) {
+            let mut handlers = self.handlers.lock();
+            handlers.remove(&PendingId::Number(id));
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    ///
+    /// Sets the method of the notification that is sent to the server when a request is cancelled
+    ///
+    /// The notification has one named parameter, id, which is the ID of the cancelled request.
+    /// For example, the Language Server Protocol uses the method "$/cancelRequest". If the method
+    /// is None (the default), the server is not notified.
+    ///
+    pub fn set_cancel_method(&self, method: Option<&str>) {
+        *self.cancel_method.lock().expect("Cancel method mutex poisoned") = method.map(|method| method.to_string());
+    }
+
+    ///
+    /// Sets the method of the request that ping sends. The default is "rpc.ping".
+    ///
+    pub fn set_ping_method(&self, method: &str) {
+        *self.ping_method.lock().expect("Ping method mutex poisoned") = method.to_string();
+    }
+
+    ///
+    /// Checks that the server is responding
+    ///
+    /// This sends a request with no parameters using the ping method, and returns Ok if any
+    /// response is received within the timeout. An error response, such as method not found from
+    /// a server that does not implement the method, still shows that the server is responding.
+    ///
+    pub fn ping(&self, timeout: &Duration) -> Result<(), TransportError> {
+        let method = self.ping_method.lock().expect("Ping method mutex poisoned").clone();
+        self.send_request_sync(Request::new(&method, None), timeout).map(|_| ())
+    }
+
+    ///
+    /// Cancels a request that has been sent
+    ///
+    /// The request's response handler is called with a request cancelled error, and any response
+    /// that the server sends later is ignored. If a cancel method has been set, a cancellation
+    /// notification is sent to the server.
+    ///
+    /// Returns true if the request was pending, or false if it has already received a response or
+    /// been cancelled. Returns an error if the cancellation notification could not be sent.
+    ///
+    pub fn cancel(&self, id: RequestID) -> Result<bool, TransportError> {
+        let pending = {
+            let mut handlers = self.handlers.lock();
+            handlers.remove(&PendingId::Number(id))
+        };
+        let mut pending = match pending {
+            Some(pending) => pending,
+            None => return Ok(false),
+        };
+        {
+            #[cfg(feature = "tracing")]
+            let _enter = {
+                pending.span.record("outcome", "cancelled");
+                pending.span.enter()
+            };
+            let mut response = Response::err(Error::request_cancelled());
+            response.set_id(Value::from(id));
+            pending.handler.method_response_received(&pending.method, response);
+        }
+        let cancel_method = self.cancel_method.lock().expect("Cancel method mutex poisoned").clone();
+        if let Some(method) = cancel_method {
+            let mut params = BTreeMap::new();
+            params.insert("id".to_string(), Value::from(id));
+            self.send_notification(Notification::new(&method, Some(Params::Named(params))))?;
+        }
+        Ok(true)
+    }
+
+    ///
+    /// Replaces the response handler of a pending request
+    ///
+    /// The new handler is called when the response is received, and the old handler is dropped
+    /// without being called. This can be used to hand off the completion of a request to a
+    /// different part of an application.
+    ///
+    /// Returns true if the request was pending, or false if it has already received a response or
+    /// been cancelled. In that case, the new handler is dropped.
+    ///
+    pub fn reassign_handler<R>(&self, id: RequestID, response_handler: R) -> bool where R: ResponseHandler {
+        let mut handlers = self.handlers.lock();
+        match handlers.get_mut(&PendingId::Number(id)) {
+            Some(pending) => {
+                pending.handler = Box::new(response_handler);
+                true
+            },
+            None => false,
+        }
+    }
+
+    ///
+    /// Sets whether queued payloads are written together
+    ///
+    /// Payloads are written to the transport on a separate thread. If this is enabled, that
+    /// thread sends all the payloads that are waiting to be written with one call to
+    /// ClientTransport::send_all, which lets a stream transport flush its output once instead of
+    /// once per payload. This reduces the number of writes when many requests are sent at once.
+    /// The default is disabled.
+    ///
+    pub fn set_coalesce_writes(&self, coalesce: bool) {
+        self.coalesce_writes.store(coalesce, Ordering::Relaxed);
+    }
+
+    ///
+    /// Sets the maximum number of characters of a payload that is logged when a payload from the
+    /// server cannot be parsed
+    ///
+    /// Longer payloads are truncated. The default is 200. Setting this to 0 hides the contents of
+    /// payloads from the log.
+    ///
+    pub fn set_payload_log_limit(&self, limit: usize) {
+        self.payload_log_limit.store(limit, Ordering::Relaxed);
+    }
+
+    ///
+    /// Sets whether synchronous requests tell the server how long the client will wait
+    ///
+    /// If this is enabled, send_request_sync and call add the timeout to each request's named
+    /// parameters (see Request::set_timeout). Requests with positional parameters or no
+    /// parameters are sent unchanged. The default is disabled.
+    ///
+    pub fn set_send_timeouts(&self, send_timeouts: bool) {
+        self.send_timeouts.store(send_timeouts, Ordering::Relaxed);
+    }
+
+    ///
+    /// Sends a request and returns a future that resolves to the response
+    ///
+    /// Unlike send_request_sync, this does not block the calling thread. The future has no
+    /// timeout; to stop waiting, drop it.
+    ///
+    /// This method is available when the futures feature is enabled.
+    ///
+    #[cfg(feature = "futures")]
+    pub fn send_request_future(&self, request: Request) -> self::future::ResponseFuture {
+        let (mut future, handler) = self::future::ResponseFuture::new();
+        if let Err(e) = self.send_request(request, handler) {
+            future.set_send_error(e);
+        }
+        future
+    }
+
     ///
     /// Sends a request synchronously and returns the result
     ///
-    pub fn send_request_sync(&mut self, request: Request, timeout: &Duration) -> Result<Response, TransportError> {
+    pub fn send_request_sync(&self, request: Request, timeout: &Duration) -> Result<Response, TransportError> {
+        let mut request = request;
+        if self.send_timeouts.load(Ordering::Relaxed) {
+            request.set_timeout(timeout);
+        }
         let end = Local::now() + *timeout;
         let (tx, rx): (Sender<Response>, Receiver<Response>) = channel();
         let callback = move |response: Response| {
@@ -103,7 +812,7 @@ impl ClientEndpoint {
                 Err(_) => println!("ClientEndpoint::send_request_sync: Client thread has hung up"),
             };
         };
-        try!(self.send_request(request, callback));
+        self.send_request(request, callback)?;
         loop {
             match rx.try_recv() {
                 Ok(response) => return Ok(response),
@@ -116,124 +825,667 @@ impl ClientEndpoint {
         }
     }
 
+    ///
+    /// Sets the policy used to retry idempotent requests
+    ///
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry_policy.lock().expect("Retry policy mutex poisoned") = policy;
+    }
+
+    ///
+    /// Sends an idempotent request synchronously and returns the result, retrying if it fails
+    ///
+    /// If sending fails or no response is received within the timeout, the request is sent
+    /// again with a new ID according to the retry policy. Only use this for requests that can
+    /// safely be processed more than once.
+    ///
+    pub fn send_idempotent_request_sync(&self, request: Request, timeout: &Duration) -> Result<Response, TransportError> {
+        // Later changes to the policy do not affect a request that has already been sent
+        let policy = self.retry_policy.lock().expect("Retry policy mutex poisoned").clone();
+        let mut attempt = 1;
+        loop {
+            match self.send_request_sync(request.clone(), timeout) {
+                Err(ref e) if attempt < policy.max_attempts && RetryPolicy::is_retriable(e) => {
+                    let backoff = policy.backoff(attempt);
+                    thread::sleep(backoff.to_std().unwrap_or(StdDuration::from_millis(0)));
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    ///
+    /// Calls a method and waits for its result for the default timeout
+    ///
+    /// This is the same as call, with the timeout returned by timeout_for.
+    ///
+    pub fn call_with_default_timeout<P, R>(&self, method: &str, params: &P) -> Result<R, CallError>
+        where P: Serialize, R: DeserializeOwned {
+        self.call(method, params, &self.timeout_for(method))
+    }
+
+    ///
+    /// Returns the timeout for requests for a method
+    ///
+    /// This is the timeout set for the method by ClientEndpointBuilder::method_timeout, or the
+    /// timeout set by ClientEndpointBuilder::default_timeout if the method does not have one.
+    ///
+    pub fn timeout_for(&self, method: &str) -> Duration {
+        self.method_timeouts.get(method).cloned().unwrap_or(self.default_timeout)
+    }
+
+    ///
+    /// Calls a method and waits for its result
+    ///
+    /// The parameters are sent as named parameters if they serialize to a map, or as positional
+    /// parameters if they serialize to an array. Parameters that serialize to null are omitted.
+    /// The result is deserialized into the requested type.
+    ///
+    pub fn call<P, R>(&self, method: &str, params: &P, timeout: &Duration) -> Result<R, CallError>
+        where P: Serialize, R: DeserializeOwned {
+        let (_, result) = self.call_with_id(method, params, timeout)?;
+        result
+    }
+
+    ///
+    /// Calls a method and waits for its result, and also returns the ID of the response
+    ///
+    /// This is the same as call, except that the ID is available for correlation or logging
+    /// whether the server returned a result or an error. The outer error is returned if the
+    /// request could not be sent or no response was received. The inner result contains the
+    /// deserialized result, or the error that the server returned, or the error that occurred
+    /// while deserializing the result. The ID is null if the response did not have one.
+    ///
+    pub fn call_with_id<P, R>(&self, method: &str, params: &P, timeout: &Duration) -> Result<(Value, Result<R, CallError>), TransportError>
+        where P: Serialize, R: DeserializeOwned {
+        let params = match serde_json::to_value(params).map_err(TransportError::from_encode_error)? {
+            Value::Null => None,
+            json => Some(Params::from_json(json).map_err(|_| TransportError::EncodeError(
+                "Parameters must serialize to an object, an array, or null".to_string()))?),
+        };
+        let response = self.send_request_sync(Request::new(method, params), timeout)?;
+        let id = response.id.unwrap_or(Value::Null);
+        let result = response.payload.map_err(CallError::Rpc)
+            .and_then(|result| serde_json::from_value(result).map_err(CallError::Deserialize));
+        Ok((id, result))
+    }
+
+    ///
+    /// Subscribes to notifications from the server
+    ///
+    /// Sends a request with the provided method and parameters and waits for its result, which is
+    /// the subscription ID. After that, each notification from the server with named parameters
+    /// that include a subscription member equal to the subscription ID is routed to the returned
+    /// receiver, which gets the result member of the parameters. This is the format that
+    /// Ethereum's eth_subscribe uses:
+    ///
+    /// {"jsonrpc":"2.0","method":"eth_subscription","params":{"subscription":"0x1","result":{}}}
+    ///
+    /// Notifications are routed as soon as the response is received, so a transport that delivers
+    /// payloads in order does not lose notifications that the server sends right after the
+    /// response. If the receiver is dropped, later notifications for the subscription are
+    /// discarded.
+    ///
+    pub fn subscribe(&self, method: &str, params: Option<Params>, timeout: &Duration) -> Result<(Value, Receiver<Value>), CallError> {
+        let (notification_tx, notification_rx) = channel();
+        let (response_tx, response_rx) = channel();
+        let subscriptions = self.subscriptions.clone();
+        let handler = move |response: Response| {
+            if let Ok(ref id) = response.payload {
+                let mut subscriptions = subscriptions.lock().expect("Subscription mutex poisoned");
+                subscriptions.insert(id.to_string(), notification_tx.clone());
+            }
+            if response_tx.send(response).is_err() {
+                println!("ClientEndpoint::subscribe: Client thread has hung up");
+            }
+        };
+        self.send_request(Request::new(method, params), handler)?;
+        let wait = timeout.to_std().unwrap_or(StdDuration::from_millis(0));
+        let response = response_rx.recv_timeout(wait).map_err(|_| TransportError::TimedOut)?;
+        let id = response.payload?;
+        Ok((id, notification_rx))
+    }
+
+    ///
+    /// Ends a subscription
+    ///
+    /// Notifications for the subscription are no longer routed, even if the request fails. Then a
+    /// request is sent with the provided method and the subscription ID as its only positional
+    /// parameter, as Ethereum's eth_unsubscribe expects, and its result is returned.
+    ///
+    pub fn unsubscribe(&self, method: &str, id: &Value, timeout: &Duration) -> Result<Value, CallError> {
+        {
+            let mut subscriptions = self.subscriptions.lock().expect("Subscription mutex poisoned");
+            subscriptions.remove(&id.to_string());
+        }
+        let request = Request::new(method, Some(Params::Positional(vec![id.clone()])));
+        let response = self.send_request_sync(request, timeout)?;
+        Ok(response.payload?)
+    }
+
     ///
     /// Sends a notification
     ///
     /// If the notification could not be sent, returns an error.
     ///
-    pub fn send_notification(&mut self, notification: Request) -> Result<(), TransportError> {
-        self.send(notification)
+    pub fn send_notification(&self, notification: Notification) -> Result<(), TransportError> {
+        self.send(Request::from(notification))
+    }
+
+    ///
+    /// Sends a notification with named parameters serialized from the provided value
+    ///
+    /// Returns TransportError::EncodeError if the parameters do not serialize to a map.
+    ///
+    pub fn notify<P>(&self, method: &str, params: &P) -> Result<(), TransportError> where P: Serialize {
+        match serde_json::to_value(params).map_err(TransportError::from_encode_error)? {
+            Value::Object(map) => self.send_notification(Notification::new(method, Some(Params::Named(map.into_iter().collect())))),
+            _ => Err(TransportError::EncodeError("Parameters must serialize to an object".to_string())),
+        }
+    }
+
+    ///
+    /// Sends a notification with positional parameters serialized from the provided value
+    ///
+    /// Returns TransportError::EncodeError if the parameters do not serialize to an array.
+    ///
+    pub fn notify_positional<P>(&self, method: &str, params: &P) -> Result<(), TransportError> where P: Serialize {
+        match serde_json::to_value(params).map_err(TransportError::from_encode_error)? {
+            Value::Array(values) => self.send_notification(Notification::new(method, Some(Params::Positional(values)))),
+            _ => Err(TransportError::EncodeError("Parameters must serialize to an array".to_string())),
+        }
+    }
+
+    ///
+    /// Returns true if the connection to the server is still open, as far as the client knows
+    ///
+    /// The connection is considered closed after the transport reports an end of file while
+    /// reading or writing. With a transport that connects for each request (like the HTTP
+    /// transport), this reflects the most recent request, and becomes true again when a response
+    /// is received.
+    ///
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    ///
+    /// Closes the sending side of the transport while continuing to receive responses
+    ///
+    /// Payloads that have already been queued are sent first. The server then sees an end of
+    /// file, and can still respond to the requests that it has received. Pending requests keep
+    /// waiting for their responses. After this is called, sending requests and notifications
+    /// fails with TransportError::EndOfFile.
+    ///
+    /// Returns an error if the transport could not close its sending side (see
+    /// ClientTransport::close_send). Calling this again after it has succeeded does nothing.
+    ///
+    pub fn close_send(&self) -> Result<(), TransportError> {
+        // Another call waits for this one to finish, and then does nothing if it succeeded
+        let _closing = self.close_lock.lock().expect("Close mutex poisoned");
+        let (tx, rx) = channel();
+        {
+            let send_channel = self.send_channel.lock().expect("Send channel mutex poisoned");
+            if self.send_closed.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            // Set while the channel is locked so that no payload is queued after CloseSend
+            self.send_closed.store(true, Ordering::SeqCst);
+            if send_channel.send(WriterMessage::CloseSend(tx)).is_err() {
+                self.send_closed.store(false, Ordering::SeqCst);
+                return Err(TransportError::EndOfFile);
+            }
+        }
+        let result = rx.recv().unwrap_or(Err(TransportError::EndOfFile));
+        if result.is_err() {
+            self.send_closed.store(false, Ordering::SeqCst);
+        }
+        result
+    }
+
+    ///
+    /// Returns the number of requests that have been sent but have not yet received responses
+    ///
+    pub fn pending_requests(&self) -> usize {
+        let handlers = self.handlers.lock();
+        handlers.len()
+    }
+
+    ///
+    /// Blocks until every pending request has received a response
+    ///
+    /// Returns TransportError::TimedOut if requests are still pending when the timeout expires.
+    ///
+    pub fn wait_idle(&self, timeout: &Duration) -> Result<(), TransportError> {
+        let end = Local::now() + *timeout;
+        let mut handlers = self.handlers.map.lock().expect("Handler mutex poisoned");
+        while !handlers.is_empty() {
+            let remaining = match (end - Local::now()).to_std() {
+                Ok(remaining) => remaining,
+                Err(_) => return Err(TransportError::TimedOut),
+            };
+            handlers = self.handlers.idle.wait_timeout(handlers, remaining).expect("Handler mutex poisoned").0;
+        }
+        Ok(())
+    }
+
+    /// Returns an error if adding count pending requests would exceed the limit
+    fn check_pending_limit(&self, handlers: &PendingMap, count: usize) -> Result<(), TransportError> {
+        match self.max_pending {
+            Some(max) if handlers.len() + count > max => Err(TransportError::TooManyPendingRequests),
+            _ => Ok(()),
+        }
     }
 
     /// Sends a request
-    fn send(&mut self, request: Request) -> Result<(), TransportError> {
-        // Convert to JSON
-        let json_text = match serde_json::to_string(&request.to_json()) {
-            Ok(text) => text,
-            Err(_) => return Err(TransportError::EncodeError),
-        };
-        // Send
-        match self.send_channel.send(json_text) {
+    fn send(&self, request: Request) -> Result<(), TransportError> {
+        self.send_json(&request.to_json())
+    }
+
+    /// Encodes and sends a message
+    fn send_json(&self, json: &Value) -> Result<(), TransportError> {
+        let payload = self.codec.encode(json)?;
+        self.send_payload(payload)
+    }
+
+    ///
+    /// Sends an encoded payload
+    ///
+    /// The payload is moved through the channel to the writer thread, so it is not copied after
+    /// it is encoded.
+    ///
+    fn send_payload(&self, payload: Vec<u8>) -> Result<(), TransportError> {
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            let wait = rate_limiter.lock().expect("Rate limiter mutex poisoned").reserve();
+            thread::sleep(wait);
+        }
+        let send_channel = self.send_channel.lock().expect("Send channel mutex poisoned");
+        if self.send_closed.load(Ordering::SeqCst) {
+            return Err(TransportError::EndOfFile);
+        }
+        match send_channel.send(WriterMessage::Payload(payload)) {
             Ok(()) => Ok(()),
-            Err(_) => Err(TransportError::EndOfFile),
+            Err(_) => {
+                // The writer thread has stopped
+                self.connected.store(false, Ordering::Relaxed);
+                Err(TransportError::EndOfFile)
+            },
         }
     }
 
 }
 
+///
+/// Converts the ID of a response into the ID of the request that it corresponds to
+///
+/// Requests are sent with unsigned integer IDs, but a server may send them back as signed
+/// integers or as floating-point numbers with integral values.
+///
+fn request_id_from_json(id: &Value) -> Option<RequestID> {
+    if let Some(id) = id.as_u64() {
+        Some(id)
+    } else if let Some(id) = id.as_i64() {
+        if id >= 0 { Some(id as RequestID) } else { None }
+    } else if let Some(id) = id.as_f64() {
+        if id >= 0.0 && id.fract() == 0.0 && id < RequestID::MAX as f64 {
+            Some(id as RequestID)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+///
+/// Returns the first limit characters of a payload, followed by an ellipsis if the payload
+/// is longer
+///
+fn truncate(payload: &str, limit: usize) -> String {
+    match payload.char_indices().nth(limit) {
+        Some((end, _)) => format!("{}...", &payload[..end]),
+        None => payload.to_string(),
+    }
+}
+
+/// Returns the reason that a response object is not valid
+fn invalid_response_reason(map: &serde_json::Map<String, Value>) -> PayloadError {
+    if !map.contains_key("id") {
+        return PayloadError::MissingId;
+    }
+    match (map.contains_key("result"), map.contains_key("error")) {
+        (true, true) => PayloadError::ResultAndError,
+        (false, false) => PayloadError::NoResultOrError,
+        _ => PayloadError::InvalidError,
+    }
+}
+
 /// Handles payloads received from the transport layer
 struct StreamPayloadHandler {
-    /// Maps from response IDs to handlers
-    response_handlers: Arc<Mutex<HashMap<RequestID, Box<ResponseHandler>>>>,
+    /// Maps from response IDs to pending requests
+    response_handlers: Arc<PendingRequests>,
+    /// The codec used to decode responses
+    codec: Arc<dyn Codec>,
+    /// Set to false when the transport reports an end of file
+    connected: Arc<AtomicBool>,
+    /// The maximum number of characters of an invalid payload to log
+    payload_log_limit: Arc<AtomicUsize>,
+    /// The subscriptions that receive notifications
+    subscriptions: Arc<Mutex<SubscriptionMap>>,
+    /// The handler for notifications that do not belong to a subscription
+    notification_handler: Option<Box<dyn NotificationHandler>>,
+    /// The handler for payloads that cannot be handled, or None to log them
+    invalid_payload_handler: Option<Box<dyn InvalidPayloadHandler>>,
+    /// The logger that receives every payload, if any
+    wire_logger: Option<Arc<dyn WireLogger>>,
 }
 
 impl StreamPayloadHandler {
-    pub fn new(handlers: Arc<Mutex<HashMap<RequestID, Box<ResponseHandler>>>>) -> StreamPayloadHandler {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(handlers: Arc<PendingRequests>, codec: Arc<dyn Codec>, connected: Arc<AtomicBool>,
+        payload_log_limit: Arc<AtomicUsize>, subscriptions: Arc<Mutex<SubscriptionMap>>,
+        notification_handler: Option<Box<dyn NotificationHandler>>,
+        invalid_payload_handler: Option<Box<dyn InvalidPayloadHandler>>,
+        wire_logger: Option<Arc<dyn WireLogger>>) -> StreamPayloadHandler {
         StreamPayloadHandler {
             response_handlers: handlers,
+            codec,
+            connected,
+            payload_log_limit,
+            subscriptions,
+            notification_handler,
+            invalid_payload_handler,
+            wire_logger,
         }
     }
 
-    fn handle_payload(&mut self, payload: &str) {
-        match serde_json::from_str(payload) {
-            Ok(json) => self.handle_json(json),
-            Err(_) => println!("StreamPayloadHandler: Could not parse response"),
+    /// Handles a payload and returns the reasons that any messages in it could not be handled
+    fn handle_payload(&mut self, payload: &[u8]) -> Vec<PayloadError> {
+        let mut errors = Vec::new();
+        // A peer may write several messages in one payload, so handle each one
+        for message in self.codec.decode_all(payload) {
+            match message {
+                Ok(json) => self.handle_json(json, &mut errors),
+                Err(TransportError::ParseError(reason)) => errors.push(PayloadError::InvalidJson(reason)),
+                Err(e) => errors.push(PayloadError::InvalidJson(format!("{:?}", e))),
+            }
         }
+        errors
     }
 
-    fn handle_json(&mut self, json: Value) {
+    ///
+    /// Handles a response, a notification, or a batch of them
+    ///
+    /// The reason that each response or notification could not be handled is added to errors.
+    ///
+    fn handle_json(&mut self, json: Value, errors: &mut Vec<PayloadError>) {
         match json {
-            Value::Object(map) => match Response::from_json(map) {
-                Ok(response) => self.handle_response(response),
-                Err(_) => println!("StreamPayloadHandler: Response invalid"),
+            // A batch of responses
+            Value::Array(elements) => {
+                for element in elements {
+                    self.handle_json(element, errors);
+                }
             },
-            _ => println!("StreamPayloadHandler: Response invalid"),
+            // A notification from the server
+            Value::Object(ref map) if map.contains_key("method") => match Request::from_json(json) {
+                Ok(notification) => self.handle_notification(notification),
+                Err(_) => errors.push(PayloadError::InvalidNotification),
+            },
+            Value::Object(map) => {
+                let reason = invalid_response_reason(&map);
+                match Response::from_json(map.into_iter().collect()) {
+                    Ok(response) => if let Err(e) = self.handle_response(response) {
+                        errors.push(e);
+                    },
+                    Err(_) => errors.push(reason),
+                }
+            },
+            _ => errors.push(PayloadError::NotAnObject),
         }
     }
 
-    fn handle_response(&mut self, response: Response) {
-        match response.id.clone() {
-            Some(value) => match value.as_u64() {
-                Some(id) => self.handle_response_with_id(response, id),
-                None => println!("StreamPayloadHandler: Response has an ID that is not an integer"),
+    /// Passes a payload that could not be handled to the invalid payload handler, or logs it
+    fn report_invalid_payload(&mut self, payload: &[u8], error: PayloadError) {
+        match self.invalid_payload_handler {
+            Some(ref mut handler) => handler.invalid_payload_received(payload, error),
+            None => {
+                let limit = self.payload_log_limit.load(Ordering::Relaxed);
+                println!("StreamPayloadHandler: Could not handle payload: {}: {}", error, truncate(&String::from_utf8_lossy(payload), limit));
             },
-            None => println!("StreamPayloadHandler: Response has no ID"),
         }
     }
 
-    fn handle_response_with_id(&mut self, response: Response, id: u64) {
-        let mut handlers = self.response_handlers.lock().unwrap();
-        match handlers.remove(&id) {
-            Some(mut handler) => handler.response_received(response),
-            None => println!("StreamPayloadHandler: No handler for response"),
+    ///
+    /// Passes the result of a notification to the subscription that it belongs to, or passes
+    /// the notification to the notification handler if it does not belong to a subscription
+    ///
+    fn handle_notification(&mut self, notification: Request) {
+        let subscription = notification.named_params()
+            .and_then(|params| params.get("subscription").map(|id| (id.to_string(), params.get("result"))));
+        if let Some((id, result)) = subscription {
+            let mut subscriptions = self.subscriptions.lock().expect("Subscription mutex poisoned");
+            if let Some(sender) = subscriptions.get(&id) {
+                if sender.send(result.cloned().unwrap_or(Value::Null)).is_err() {
+                    // The subscriber has stopped listening
+                    subscriptions.remove(&id);
+                }
+                return;
+            }
+        }
+        match self.notification_handler {
+            Some(ref mut handler) => handler.notification_received(notification),
+            None => println!("StreamPayloadHandler: No handler for notification {}", notification.method),
+        }
+    }
+
+    ///
+    /// Calls the handlers of all pending requests with an error and ends all subscriptions
+    ///
+    /// This is used when the transport has replaced its connection, so the server will not
+    /// respond to requests or send notifications for subscriptions that were made earlier.
+    ///
+    fn fail_pending(&mut self) {
+        let pending: Vec<(PendingId, PendingRequest)> = {
+            let mut handlers = self.response_handlers.lock();
+            handlers.drain().collect()
+        };
+        for (id, mut pending) in pending {
+            #[cfg(feature = "tracing")]
+            let _enter = {
+                pending.span.record("outcome", "connection lost");
+                pending.span.enter()
+            };
+            let mut error = Error::internal_error();
+            error.set_data(Value::from("Connection lost"));
+            let mut response = Response::err(error);
+            response.set_id(id.to_json());
+            pending.handler.method_response_received(&pending.method, response);
+        }
+        // Dropping the senders disconnects the subscription receivers
+        self.subscriptions.lock().expect("Subscription mutex poisoned").clear();
+    }
+
+    fn handle_response(&mut self, response: Response) -> Result<(), PayloadError> {
+        match response.id.clone() {
+            Some(value) => self.handle_response_with_id(response, value),
+            None => Err(PayloadError::MissingId),
+        }
+    }
+
+    ///
+    /// Passes a response to the handler of the request with the provided ID
+    ///
+    /// A server responds with an error and a null ID when it cannot read the ID of a request,
+    /// for example because the request could not be parsed. Requests never have null IDs, so
+    /// such a response is passed to the pending request if there is exactly one. If more than
+    /// one request is pending, the response is reported as ambiguous and the requests are left
+    /// to time out.
+    ///
+    fn handle_response_with_id(&mut self, response: Response, id: Value) -> Result<(), PayloadError> {
+        let mut handlers = self.response_handlers.lock();
+        let key = if id.is_null() && response.payload.is_err() {
+            match handlers.len() {
+                0 => return Err(PayloadError::UnknownId(id)),
+                1 => handlers.keys().next().cloned().expect("No pending request"),
+                _ => return Err(PayloadError::AmbiguousNullId),
+            }
+        } else {
+            PendingId::from_json(&id)
+        };
+        match handlers.remove(&key) {
+            Some(mut pending) => {
+                // Record the outcome and handle the response in the request's span
+                #[cfg(feature = "tracing")]
+                let _enter = {
+                    let outcome = if response.payload.is_ok() { "result" } else { "error" };
+                    pending.span.record("outcome", outcome);
+                    pending.span.enter()
+                };
+                pending.handler.method_response_received(&pending.method, response);
+                Ok(())
+            },
+            None => Err(PayloadError::UnknownId(id)),
         }
     }
 }
 
 impl PayloadHandler for StreamPayloadHandler {
-    fn payload_received(&mut self, result: Result<String, TransportError>) {
+    fn payload_received(&mut self, result: Result<Vec<u8>, TransportError>) {
         match result {
-            Ok(payload) => self.handle_payload(&payload),
+            Ok(payload) => {
+                self.connected.store(true, Ordering::Relaxed);
+                if let Some(ref logger) = self.wire_logger {
+                    logger.log_payload(Direction::Received, &payload);
+                }
+                for error in self.handle_payload(&payload) {
+                    self.report_invalid_payload(&payload, error);
+                }
+            },
+            Err(TransportError::EndOfFile) => {
+                println!("Client transport reached end of file");
+                self.connected.store(false, Ordering::Relaxed);
+            },
+            Err(TransportError::Reconnected) => {
+                println!("Client transport reconnected");
+                self.connected.store(true, Ordering::Relaxed);
+                self.fail_pending();
+            },
             Err(e) => println!("Client transport receive error: {:?}", e),
         }
     }
 }
 
+/// A message to the writer thread
+enum WriterMessage {
+    /// A payload to send
+    Payload(Vec<u8>),
+    /// A request to close the sending side of the transport, with a channel for the result
+    CloseSend(Sender<Result<(), TransportError>>),
+}
+
 ///
 /// Writes messages to a transport mechanism
 ///
-/// For every string that is received over the channel, the received string is sent to the
-/// transport mechanism.
+/// Every payload that is received over the channel is sent to the transport mechanism.
 ///
 struct StreamWriter<T> where T: ClientTransport {
     transport: T,
-    channel: Receiver<String>,
+    channel: Receiver<WriterMessage>,
+    /// If true, all the payloads waiting in the channel are sent together
+    coalesce: Arc<AtomicBool>,
+    /// Set to false when writing fails because the connection has closed
+    connected: Arc<AtomicBool>,
+    /// The logger that receives every payload, if any
+    wire_logger: Option<Arc<dyn WireLogger>>,
 }
 
 impl<T> StreamWriter<T> where T: ClientTransport {
-    pub fn new(transport: T, channel: Receiver<String>) -> StreamWriter<T> {
+    pub fn new(transport: T, channel: Receiver<WriterMessage>, coalesce: Arc<AtomicBool>, connected: Arc<AtomicBool>,
+        wire_logger: Option<Arc<dyn WireLogger>>) -> StreamWriter<T> {
         StreamWriter {
-            transport: transport,
-            channel: channel,
+            transport,
+            channel,
+            coalesce,
+            connected,
+            wire_logger,
         }
     }
 
-    fn send_payload(&mut self, payload: &str) {
-        match self.transport.send(payload) {
-            Ok(()) => {},
+    ///
+    /// Sends payloads to the transport
+    ///
+    /// Returns false if the connection has closed and the writer should stop.
+    ///
+    fn send_payloads(&mut self, payloads: &[Vec<u8>]) -> bool {
+        if let Some(ref logger) = self.wire_logger {
+            for payload in payloads {
+                logger.log_payload(Direction::Sent, payload);
+            }
+        }
+        let result = if payloads.len() == 1 {
+            self.transport.send(&payloads[0])
+        } else {
+            self.transport.send_all(payloads)
+        };
+        match result {
+            Ok(()) => true,
+            Err(TransportError::EndOfFile) => {
+                println!("StreamWriter: Connection closed; stopping");
+                self.connected.store(false, Ordering::Relaxed);
+                false
+            },
             Err(e) => match e {
                 TransportError::TimedOut
                 | TransportError::Interrupted
-                | TransportError::EncodeError => println!("StreamWriter: Failed to write: {:?}", e),
+                | TransportError::EncodeError(_)
+                | TransportError::ParseError(_)
+                | TransportError::Reconnected => {
+                    println!("StreamWriter: Failed to write: {:?}", e);
+                    true
+                },
                 _ => panic!("StreamWriter: Failed to write: {:?}", e),
             }
         }
     }
 
+    ///
+    /// Closes the sending side of the transport and reports the result
+    ///
+    /// The transport is kept so that it can continue to receive payloads until the client hangs up.
+    ///
+    fn close_send(&mut self, result_channel: Sender<Result<(), TransportError>>) {
+        let result = self.transport.close_send();
+        if let Err(ref e) = result {
+            println!("StreamWriter: Failed to close the sending side: {:?}", e);
+        }
+        let _ = result_channel.send(result);
+    }
+
     pub fn run(&mut self) {
         loop {
             match self.channel.recv() {
-                Ok(payload) => self.send_payload(&payload),
+                Ok(WriterMessage::Payload(payload)) => {
+                    let mut payloads = vec![payload];
+                    let mut close = None;
+                    if self.coalesce.load(Ordering::Relaxed) {
+                        while let Ok(message) = self.channel.try_recv() {
+                            match message {
+                                WriterMessage::Payload(payload) => payloads.push(payload),
+                                WriterMessage::CloseSend(result_channel) => {
+                                    close = Some(result_channel);
+                                    break;
+                                },
+                            }
+                        }
+                    }
+                    if !self.send_payloads(&payloads) {
+                        return;
+                    }
+                    if let Some(result_channel) = close {
+                        self.close_send(result_channel);
+                    }
+                },
+                Ok(WriterMessage::CloseSend(result_channel)) => self.close_send(result_channel),
                 Err(_) => {
                     println!("StreamWriter: Client has hung up; stopping");
                     return;
@@ -242,3 +1494,16 @@ impl<T> StreamWriter<T> where T: ClientTransport {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::truncate;
+
+    #[test]
+    fn truncate_payload() {
+        assert_eq!(truncate("{\"id\":1}", 20), "{\"id\":1}");
+        assert_eq!(truncate("{\"id\":1}", 4), "{\"id...");
+        assert_eq!(truncate("\u{e9}\u{e9}\u{e9}", 2), "\u{e9}\u{e9}...");
+        assert_eq!(truncate("abc", 0), "...");
+    }
+}
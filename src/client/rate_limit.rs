@@ -0,0 +1,89 @@
+//!
+//! Provides a limit on the rate that a client sends payloads
+//!
+
+use std::time::{Duration, Instant};
+
+///
+/// A token bucket that limits how often payloads are sent
+///
+/// The bucket holds up to burst tokens and gains rate tokens per second. Sending a payload
+/// uses one token. When the bucket is empty, the sender waits until a token is available.
+/// Tokens are reserved in the order that senders ask for them, so waiting senders are served in
+/// order.
+///
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// The number of tokens added per second
+    rate: f64,
+    /// The maximum number of tokens
+    burst: f64,
+    /// The number of tokens available. This is negative when senders are waiting.
+    tokens: f64,
+    /// The time when tokens was last updated
+    updated: Instant,
+}
+
+impl RateLimiter {
+    ///
+    /// Creates a rate limiter that allows rate payloads per second on average, and up to burst
+    /// payloads at once
+    ///
+    /// The bucket starts full. Panics if rate is not positive or burst is 0.
+    ///
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        assert!(rate > 0.0, "Rate limit must be positive");
+        assert!(burst > 0, "Rate limit burst size must be positive");
+        RateLimiter {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    ///
+    /// Takes a token and returns how long the caller must wait before sending
+    ///
+    pub fn reserve(&mut self) -> Duration {
+        self.reserve_at(Instant::now())
+    }
+
+    /// Takes a token at the provided time and returns how long the caller must wait before sending
+    fn reserve_at(&mut self, now: Instant) -> Duration {
+        if now > self.updated {
+            let elapsed = now.duration_since(self.updated);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.updated = now;
+        }
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            let wait = -self.tokens / self.rate;
+            Duration::new(wait.trunc() as u64, (wait.fract() * 1e9) as u32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn burst_then_rate() {
+        let mut limiter = RateLimiter::new(10.0, 2);
+        let start = limiter.updated;
+        assert_eq!(limiter.reserve_at(start), Duration::from_secs(0));
+        assert_eq!(limiter.reserve_at(start), Duration::from_secs(0));
+        // The bucket is empty, so each sender waits for one more token
+        assert_eq!(limiter.reserve_at(start), Duration::from_millis(100));
+        assert_eq!(limiter.reserve_at(start), Duration::from_millis(200));
+        // After 300 milliseconds, the waiting senders have used two of the three new tokens
+        let later = start + Duration::from_millis(300);
+        assert_eq!(limiter.reserve_at(later), Duration::from_secs(0));
+        assert_eq!(limiter.reserve_at(later), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,164 @@
+//!
+//! Provides a client transport that replaces its inner transport when the connection is lost
+//!
+
+use transport::{ClientTransport, PayloadHandler, TransportError};
+use std::sync::{Arc, Mutex, Weak};
+
+/// A function that creates a new inner transport
+type Factory<T> = Box<dyn FnMut() -> Result<T, TransportError> + Send>;
+/// A payload handler shared between the inner transports
+type SharedHandler = Arc<Mutex<Box<dyn PayloadHandler>>>;
+
+/// The state shared between the transport and the payload handlers of its inner transports
+struct Inner<T> {
+    /// The current inner transport, or None if the last attempt to create one failed
+    transport: Option<T>,
+    /// Creates inner transports
+    factory: Factory<T>,
+    /// The payload handler
+    handler: Option<SharedHandler>,
+    /// Incremented each time the inner transport is replaced, so that an end of file from a
+    /// transport that has already been replaced can be ignored
+    generation: u64,
+}
+
+///
+/// A client transport that creates a new inner transport when the current one reaches an end of
+/// file, either while sending or while reading
+///
+/// The inner transports are created by a factory function, which is called once when this
+/// transport is created and again for each reconnection. The payload handler is registered with
+/// each new inner transport.
+///
+/// Requests that were sent before a reconnection will not get responses. When the inner
+/// transport is replaced, the payload handler receives TransportError::Reconnected, which makes a
+/// ClientEndpoint call the handlers of all its pending requests with an error. A send that fails
+/// and causes a reconnection returns TransportError::Reconnected without sending the payload
+/// again.
+///
+/// If the factory fails, sending fails with the factory's error. The next send tries to create
+/// a transport again.
+///
+pub struct ReconnectingClientTransport<T> where T: ClientTransport {
+    /// The state, shared with the payload handlers of the inner transports
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> ReconnectingClientTransport<T> where T: ClientTransport {
+    ///
+    /// Creates a transport that uses the provided function to create inner transports
+    ///
+    /// Returns an error if the first inner transport could not be created.
+    ///
+    pub fn new<F>(factory: F) -> Result<ReconnectingClientTransport<T>, TransportError>
+        where F: FnMut() -> Result<T, TransportError>, F: 'static + Send {
+        let mut factory: Factory<T> = Box::new(factory);
+        let transport = factory()?;
+        Ok(ReconnectingClientTransport {
+            inner: Arc::new(Mutex::new(Inner {
+                transport: Some(transport),
+                factory,
+                handler: None,
+                generation: 0,
+            })),
+        })
+    }
+
+    /// Returns the number of times that the inner transport has been replaced
+    pub fn reconnections(&self) -> u64 {
+        self.inner.lock().expect("Reconnecting transport mutex poisoned").generation
+    }
+}
+
+/// Registers a payload handler with an inner transport that forwards to the shared handler
+fn register<T>(transport: &mut T, handler: SharedHandler, inner: Weak<Mutex<Inner<T>>>, generation: u64)
+    where T: ClientTransport {
+    transport.set_payload_handler(move |result: Result<Vec<u8>, TransportError>| {
+        if let Err(TransportError::EndOfFile) = result {
+            // Reconnect unless the transport that reached the end of file has already been replaced
+            if let Some(inner) = inner.upgrade() {
+                let mut inner_state = inner.lock().expect("Reconnecting transport mutex poisoned");
+                if inner_state.generation == generation {
+                    println!("ReconnectingClientTransport: Connection closed; reconnecting");
+                    let _ = reconnect(&mut inner_state, &inner);
+                }
+            }
+            return;
+        }
+        let mut handler = handler.lock().expect("Payload handler mutex poisoned");
+        handler.payload_received(result);
+    });
+}
+
+///
+/// Replaces the inner transport and notifies the payload handler
+///
+/// The handler receives TransportError::Reconnected if a new transport was created, or
+/// TransportError::EndOfFile if the factory failed.
+///
+fn reconnect<T>(state: &mut Inner<T>, inner: &Arc<Mutex<Inner<T>>>) -> Result<(), TransportError> where T: ClientTransport {
+    state.generation += 1;
+    state.transport = None;
+    let result = match (state.factory)() {
+        Ok(mut transport) => {
+            if let Some(ref handler) = state.handler {
+                register(&mut transport, handler.clone(), Arc::downgrade(inner), state.generation);
+            }
+            state.transport = Some(transport);
+            Ok(())
+        },
+        Err(e) => {
+            println!("ReconnectingClientTransport: Failed to reconnect: {:?}", e);
+            Err(e)
+        },
+    };
+    if let Some(ref handler) = state.handler {
+        let error = if result.is_ok() { TransportError::Reconnected } else { TransportError::EndOfFile };
+        handler.lock().expect("Payload handler mutex poisoned").payload_received(Err(error));
+    }
+    result
+}
+
+impl<T> ClientTransport for ReconnectingClientTransport<T> where T: ClientTransport {
+    fn set_payload_handler<H>(&mut self, handler: H) where H: PayloadHandler {
+        let handler: SharedHandler = Arc::new(Mutex::new(Box::new(handler)));
+        let mut state = self.inner.lock().expect("Reconnecting transport mutex poisoned");
+        state.handler = Some(handler.clone());
+        let generation = state.generation;
+        if let Some(ref mut transport) = state.transport {
+            register(transport, handler, Arc::downgrade(&self.inner), generation);
+        }
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), TransportError> {
+        let mut state = self.inner.lock().expect("Reconnecting transport mutex poisoned");
+        let result = match state.transport {
+            Some(ref mut transport) => transport.send(payload),
+            None => Err(TransportError::EndOfFile),
+        };
+        match result {
+            Err(TransportError::EndOfFile) => {
+                println!("ReconnectingClientTransport: Failed to send; reconnecting");
+                reconnect(&mut state, &self.inner)?;
+                Err(TransportError::Reconnected)
+            },
+            result => result,
+        }
+    }
+
+    /// Closes the sending side of the current inner transport
+    fn close_send(&mut self) -> Result<(), TransportError> {
+        let mut state = self.inner.lock().expect("Reconnecting transport mutex poisoned");
+        match state.transport {
+            Some(ref mut transport) => transport.close_send(),
+            None => Err(TransportError::EndOfFile),
+        }
+    }
+
+    /// Returns the delimiter of the current inner transport
+    fn delimiter(&self) -> Option<u8> {
+        let state = self.inner.lock().expect("Reconnecting transport mutex poisoned");
+        state.transport.as_ref().and_then(ClientTransport::delimiter)
+    }
+}
@@ -0,0 +1,151 @@
+//!
+//! Provides a policy for retrying requests
+//!
+
+use chrono::Duration;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use transport::TransportError;
+
+/// The state of the generator that chooses jitter, or 0 if it has not been seeded yet
+static JITTER_STATE: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Controls how idempotent requests are retried after transient failures
+///
+/// The delay before each retry doubles, starting at initial_backoff and limited to max_backoff.
+/// A random amount of up to half of each delay is subtracted so that clients that failed at
+/// the same time do not all retry at the same time.
+///
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of times to send a request, including the first attempt
+    pub max_attempts: u32,
+    /// The delay before the first retry
+    pub initial_backoff: Duration,
+    /// The maximum delay before a retry
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::milliseconds(100),
+            max_backoff: Duration::seconds(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    ///
+    /// Returns true if a request that failed with the provided error should be retried
+    ///
+    /// Timeouts, interruptions, and connection failures are retriable. Transports report
+    /// connection resets as EndOfFile.
+    ///
+    pub fn is_retriable(error: &TransportError) -> bool {
+        matches!(*error, TransportError::TimedOut | TransportError::Interrupted | TransportError::EndOfFile)
+    }
+
+    ///
+    /// Returns the delay before a retry
+    ///
+    /// retry is 1 for the first retry, 2 for the second, and so on.
+    ///
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = cmp::min(retry.saturating_sub(1), 30);
+        let initial = self.initial_backoff.num_milliseconds();
+        let delay = cmp::min(initial.saturating_mul(1 << exponent), self.max_backoff.num_milliseconds());
+        let delay = cmp::max(delay, 0);
+        let jitter = (next_random() % (delay as u64 / 2 + 1)) as i64;
+        Duration::milliseconds(delay - jitter)
+    }
+}
+
+///
+/// Returns a pseudo-random number
+///
+/// The numbers come from an xorshift generator that is seeded once per process, so clients in
+/// different processes choose different delays.
+///
+fn next_random() -> u64 {
+    let mut state = JITTER_STATE.load(Ordering::Relaxed);
+    loop {
+        let mut next = if state == 0 { seed() } else { state };
+        next ^= next << 13;
+        next ^= next >> 7;
+        next ^= next << 17;
+        match JITTER_STATE.compare_exchange_weak(state, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(current) => state = current,
+        }
+    }
+}
+
+/// Returns a nonzero seed for the jitter generator
+fn seed() -> u64 {
+    // RandomState is seeded from the operating system's random number source
+    let seed = RandomState::new().build_hasher().finish();
+    if seed == 0 { 1 } else { seed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::milliseconds(100),
+            max_backoff: Duration::milliseconds(1000),
+        }
+    }
+
+    /// Asserts that a backoff is between half of the delay and the delay
+    fn assert_jittered(backoff: Duration, delay: i64) {
+        let backoff = backoff.num_milliseconds();
+        assert!(backoff >= delay - delay / 2 && backoff <= delay, "Backoff {} for delay {}", backoff, delay);
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = policy();
+        for _ in 0..100 {
+            assert_jittered(policy.backoff(1), 100);
+            assert_jittered(policy.backoff(2), 200);
+            assert_jittered(policy.backoff(3), 400);
+            assert_jittered(policy.backoff(4), 800);
+        }
+    }
+
+    #[test]
+    fn backoff_limited() {
+        let policy = policy();
+        for retry in 5..40 {
+            assert_jittered(policy.backoff(retry), 1000);
+        }
+        assert_jittered(policy.backoff(u32::MAX), 1000);
+    }
+
+    #[test]
+    fn backoff_jitter_varies() {
+        let policy = policy();
+        let backoffs: Vec<i64> = (0..100).map(|_| policy.backoff(1).num_milliseconds()).collect();
+        assert!(backoffs.iter().any(|backoff| *backoff != backoffs[0]));
+        // Over many retries, both ends of the range are reached
+        let backoffs: Vec<i64> = (0..1000).map(|_| policy.backoff(1).num_milliseconds()).collect();
+        assert!(backoffs.iter().any(|backoff| *backoff < 60));
+        assert!(backoffs.iter().any(|backoff| *backoff > 90));
+    }
+
+    #[test]
+    fn retriable_errors() {
+        assert!(RetryPolicy::is_retriable(&TransportError::TimedOut));
+        assert!(RetryPolicy::is_retriable(&TransportError::EndOfFile));
+        assert!(!RetryPolicy::is_retriable(&TransportError::EncodeError("invalid".to_string())));
+        assert!(!RetryPolicy::is_retriable(&TransportError::ParseError("invalid".to_string())));
+    }
+}
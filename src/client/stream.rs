@@ -1,37 +1,99 @@
 //!
 //! Provides a stream-based client transport layer
 //!
+//! Each payload is followed by a delimiter, which is a newline (\n) by default. A payload must
+//! not contain the delimiter, so messages are encoded as compact JSON, which escapes line breaks
+//! inside strings. With the default delimiter, lines that are read may end with either \n or
+//! \r\n. Other delimiters can be used with ClientStreamTransport::with_delimiter.
+//!
 
 use transport::{ClientTransport, PayloadHandler, TransportError};
-use std::io::{Read, Write, BufRead, BufReader, Lines, BufWriter};
+use transport::delimited::{Payloads, DEFAULT_DELIMITER};
+use std::io;
+use std::io::{Read, Write, BufWriter};
+use std::net::{Shutdown, TcpStream};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use spawn::{Spawn, ThreadSpawner};
 
 
-/// The character that separates payloads in a stream
-const SEPARATOR: u8 = '\n' as u8;
+/// Counter used to give each transport's reader thread a unique name
+static NEXT_TRANSPORT_NUMBER: AtomicUsize = AtomicUsize::new(0);
 
 ///
 /// A client transport that supports byte streams through Read and Write objects
 ///
 pub struct ClientStreamTransport<W> where W: Write + Send {
-    /// Output writer
-    output: BufWriter<W>,
+    /// Output writer, or None if the sending side has been closed
+    output: Option<BufWriter<W>>,
+    /// The byte written after each payload
+    delimiter: u8,
     /// Channel used to send new callbacks to the reader
-    callback_tx: Sender<Box<PayloadHandler>>,
+    callback_tx: Sender<Box<dyn PayloadHandler>>,
 }
 
 impl<W> ClientStreamTransport<W> where W: Write + Send {
-    pub fn new<R>(input: R, output: W) -> ClientStreamTransport<W> where R: 'static + Read + Send {
+    ///
+    /// Creates a transport that reads payloads from input and writes payloads to output
+    ///
+    /// Returns an error if the reader thread could not be started.
+    ///
+    pub fn new<R>(input: R, output: W) -> Result<ClientStreamTransport<W>, TransportError> where R: 'static + Read + Send {
+        ClientStreamTransport::with_delimiter(input, output, DEFAULT_DELIMITER)
+    }
+
+    ///
+    /// Creates a transport that reads payloads from input and writes payloads to output, with
+    /// payloads separated by the provided delimiter instead of a newline
+    ///
+    /// JSON payloads may contain any printable byte, so with the JSON codecs the delimiter must be
+    /// a control character like NUL (0). ClientEndpointBuilder::build returns an error if the
+    /// codec's payloads may contain the delimiter.
+    ///
+    pub fn with_delimiter<R>(input: R, output: W, delimiter: u8) -> Result<ClientStreamTransport<W>, TransportError> where R: 'static + Read + Send {
+        ClientStreamTransport::with_spawner(input, output, delimiter, &ThreadSpawner)
+    }
+
+    ///
+    /// Creates a transport that reads payloads from input and writes payloads to output, with
+    /// payloads separated by the provided delimiter, and uses the provided Spawn to start the
+    /// reader
+    ///
+    pub fn with_spawner<R>(input: R, output: W, delimiter: u8, spawner: &dyn Spawn) -> Result<ClientStreamTransport<W>, TransportError>
+        where R: 'static + Read + Send {
         let (tx, rx) = channel();
-        let mut reader = LineReader::new(input, rx);
-        thread::Builder::new().name("ClientStreamTransport reader".to_string()).spawn(move || {
+        let mut reader = LineReader::new(input, rx, delimiter);
+        let number = NEXT_TRANSPORT_NUMBER.fetch_add(1, Ordering::Relaxed);
+        let name = format!("ClientStreamTransport {} reader", number);
+        spawner.spawn(name, Box::new(move || {
             reader.run();
-        }).unwrap();
-        ClientStreamTransport {
-            output: BufWriter::new(output),
+        })).map_err(TransportError::IOError)?;
+        Ok(ClientStreamTransport {
+            output: Some(BufWriter::new(output)),
+            delimiter,
             callback_tx: tx,
-        }
+        })
+    }
+
+    ///
+    /// Writes a payload without flushing the output
+    ///
+    /// The payload may stay in the output buffer until flush is called or the buffer fills. This
+    /// can be used to write several payloads and flush once. ClientTransport::send, which
+    /// ClientEndpoint uses, always flushes. To send queued payloads together from a
+    /// ClientEndpoint, see ClientEndpoint::set_coalesce_writes.
+    ///
+    pub fn send_unflushed(&mut self, payload: &[u8]) -> Result<(), TransportError> {
+        let output = self.output.as_mut().ok_or(TransportError::EndOfFile)?;
+        output.write_all(payload)?;
+        output.write_all(&[self.delimiter])?;
+        Ok(())
+    }
+
+    /// Flushes the output, writing any payloads that have been buffered
+    pub fn flush(&mut self) -> Result<(), TransportError> {
+        self.output.as_mut().ok_or(TransportError::EndOfFile)?.flush()?;
+        Ok(())
     }
 }
 
@@ -43,61 +105,208 @@ impl<W> ClientTransport for ClientStreamTransport<W> where W: 'static + Write +
         };
     }
 
-    fn send(&mut self, payload: &str) -> Result<(), TransportError>{
-        try!(self.output.write_all(payload.as_bytes()));
-        try!(self.output.write_all(&[SEPARATOR]));
-        try!(self.output.flush());
+    fn send(&mut self, payload: &[u8]) -> Result<(), TransportError>{
+        self.send_unflushed(payload)?;
+        self.flush()
+    }
+
+    /// Writes all the payloads and then flushes the output once
+    fn send_all(&mut self, payloads: &[Vec<u8>]) -> Result<(), TransportError> {
+        for payload in payloads {
+            self.send_unflushed(payload)?;
+        }
+        self.flush()
+    }
+
+    ///
+    /// Flushes and drops the output while the reader thread continues
+    ///
+    /// Dropping the output closes it if it is the only handle to a pipe or file. To close the
+    /// sending side of a TCP connection, use a TcpWriteHalf as the output.
+    ///
+    fn close_send(&mut self) -> Result<(), TransportError> {
+        if let Some(mut output) = self.output.take() {
+            output.flush()?;
+        }
         Ok(())
     }
+
+    fn delimiter(&self) -> Option<u8> {
+        Some(self.delimiter)
+    }
+}
+
+///
+/// A writer that shuts down the sending side of a TCP connection when it is dropped
+///
+/// This can be used as the output of a ClientStreamTransport, with a clone of the stream as the
+/// input, so that ClientTransport::close_send sends a TCP half-close to the server.
+///
+pub struct TcpWriteHalf(pub TcpStream);
+
+impl Write for TcpWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for TcpWriteHalf {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown(Shutdown::Write) {
+            println!("TcpWriteHalf: Failed to shut down: {:?}", e);
+        }
+    }
+}
+
+///
+/// Flushes the output and tells the reader thread to stop
+///
+/// The output is closed when it is dropped. A reader thread that is blocked waiting for input
+/// cannot be interrupted, so it stops when it next reads a payload or reaches the end of its input.
+///
+impl<W> Drop for ClientStreamTransport<W> where W: Write + Send {
+    fn drop(&mut self) {
+        if let Some(ref mut output) = self.output {
+            if let Err(e) = output.flush() {
+                println!("ClientStreamTransport: Failed to flush output: {:?}", e);
+            }
+        }
+        // Dropping callback_tx after this disconnects the reader's channel, which tells it to stop
+    }
 }
 
 /// Reads lines from a Read object and provides them to a callback
 struct LineReader<R> where R: Read {
-    /// Line iterator used for reading
-    lines: Lines<BufReader<R>>,
+    /// Payload iterator used for reading
+    lines: Payloads<R>,
     /// Handler that handles lines that have been read, or None if no handler
     /// has been provided
-    handler: Option<Box<PayloadHandler>>,
+    handler: Option<Box<dyn PayloadHandler>>,
     /// Channel used to receive new handlers
-    handler_rx: Receiver<Box<PayloadHandler>>
+    handler_rx: Receiver<Box<dyn PayloadHandler>>
 }
 
 impl<R> LineReader<R> where R: Read {
-    pub fn new(input: R, handler_channel: Receiver<Box<PayloadHandler>>) -> LineReader<R> {
+    pub fn new(input: R, handler_channel: Receiver<Box<dyn PayloadHandler>>, delimiter: u8) -> LineReader<R> {
         LineReader {
-            lines: BufReader::new(input).lines(),
+            lines: Payloads::new(input, delimiter),
             handler: None,
             handler_rx: handler_channel,
         }
     }
 
     /// Updates the handler
-    fn update_payload_handler(&mut self) {
+    ///
+    /// Returns false if the transport has been dropped and the reader should stop
+    fn update_payload_handler(&mut self) -> bool {
         match self.handler_rx.try_recv() {
-            Ok(handler) => self.handler = Some(handler),
-            Err(TryRecvError::Empty) => {},
-            Err(TryRecvError::Disconnected) => panic!("LineReader: Client has hung up, exiting"),
-        };
+            Ok(handler) => {
+                self.handler = Some(handler);
+                true
+            },
+            Err(TryRecvError::Empty) => true,
+            Err(TryRecvError::Disconnected) => false,
+        }
     }
     /// Handles a line that has been read from the input
-    fn handle_line(&mut self, line: String) {
-        self.update_payload_handler();
+    ///
+    /// Returns false if the reader should stop
+    fn handle_line(&mut self, line: Vec<u8>) -> bool {
+        if !self.update_payload_handler() {
+            println!("LineReader: Transport has been dropped, exiting");
+            return false;
+        }
         match self.handler {
             Some(ref mut handler) => handler.payload_received(Ok(line)),
             None => println!("LineReader: Read a payload, but no handler is available"),
         };
+        true
+    }
+
+    ///
+    /// Reports an error that stopped reading to the handler
+    ///
+    /// If no handler has been provided yet, this waits for one so that the error is not lost.
+    ///
+    fn report_error(&mut self, error: TransportError) {
+        if self.handler.is_none() {
+            self.handler = self.handler_rx.recv().ok();
+        } else if !self.update_payload_handler() {
+            return;
+        }
+        if let Some(ref mut handler) = self.handler {
+            handler.payload_received(Err(error));
+        }
     }
 
     pub fn run(&mut self) {
         loop {
             match self.lines.next() {
-                Some(Ok(line)) => self.handle_line(line),
-                Some(Err(_))
-                | None => {
+                Some(Ok(line)) => if !self.handle_line(line) {
+                    return;
+                },
+                Some(Err(e)) => {
                     println!("LineReader: Failed to read line, exiting");
+                    self.report_error(TransportError::from(e));
                     return;
-                }
+                },
+                None => {
+                    self.report_error(TransportError::EndOfFile);
+                    return;
+                },
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::ClientTransport;
+    use std::io;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer that records the data written and the number of times it has been flushed
+    #[derive(Clone)]
+    struct CountingWriter {
+        state: Arc<Mutex<(Vec<u8>, usize)>>,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.state.lock().unwrap().0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.state.lock().unwrap().1 += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_all_flushes_once() {
+        let writer = CountingWriter { state: Arc::new(Mutex::new((Vec::new(), 0))) };
+        let mut transport = ClientStreamTransport::new(io::empty(), writer.clone()).unwrap();
+        let payloads = vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()];
+        transport.send_all(&payloads).unwrap();
+        let state = writer.state.lock().unwrap();
+        assert_eq!(state.0, b"1\n2\n3\n");
+        assert_eq!(state.1, 1);
+    }
+
+    #[test]
+    fn send_unflushed() {
+        let writer = CountingWriter { state: Arc::new(Mutex::new((Vec::new(), 0))) };
+        let mut transport = ClientStreamTransport::new(io::empty(), writer.clone()).unwrap();
+        transport.send_unflushed(b"1").unwrap();
+        transport.send_unflushed(b"2").unwrap();
+        assert_eq!(*writer.state.lock().unwrap(), (Vec::new(), 0));
+        transport.flush().unwrap();
+        assert_eq!(*writer.state.lock().unwrap(), (b"1\n2\n".to_vec(), 1));
+    }
+}
@@ -0,0 +1,266 @@
+//!
+//! Provides abstractions for the format that messages are encoded in
+//!
+
+use serde_json;
+use message::Value;
+use transport::TransportError;
+
+///
+/// Trait for a format that messages can be encoded in
+///
+/// Messages are represented as JSON values. A codec converts them to and from the payloads that
+/// transports carry. Payloads are bytes, so a codec can use a binary format like MessagePack or
+/// CBOR. An encoded payload must not contain anything that the transport uses as a separator (for
+/// the stream transports, a newline by default).
+///
+pub trait Codec: 'static + Send + Sync {
+    /// Encodes a message into a payload
+    fn encode(&self, message: &Value) -> Result<Vec<u8>, TransportError>;
+    /// Decodes a payload into a message
+    fn decode(&self, payload: &[u8]) -> Result<Value, TransportError>;
+
+    ///
+    /// Decodes a payload that may contain several messages
+    ///
+    /// Some peers write more than one message in a payload without separating them. Codecs that
+    /// can find the boundaries between messages can override this to decode each one. If part of
+    /// the payload cannot be decoded, the result for that part is an error, and the messages
+    /// before it are still returned. The default implementation decodes the payload as one
+    /// message.
+    ///
+    fn decode_all(&self, payload: &[u8]) -> Vec<Result<Value, TransportError>> {
+        vec![self.decode(payload)]
+    }
+
+    ///
+    /// Returns true if encoded payloads may contain the provided delimiter byte
+    ///
+    /// Endpoints use this to reject a codec that cannot be used with the delimiter of their
+    /// transport. The default implementation returns true for every byte, so a codec can only be
+    /// used with a delimited transport if it overrides this.
+    ///
+    fn may_contain(&self, _delimiter: u8) -> bool {
+        true
+    }
+}
+
+///
+/// The default codec, which encodes messages as compact JSON
+///
+#[derive(Debug, Clone, Default)]
+pub struct JSONCodec;
+
+impl Codec for JSONCodec {
+    fn encode(&self, message: &Value) -> Result<Vec<u8>, TransportError> {
+        serde_json::to_vec(message).map_err(TransportError::from_encode_error)
+    }
+    fn decode(&self, payload: &[u8]) -> Result<Value, TransportError> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+    /// Decodes each JSON value in the payload, stopping at the first part that is not valid JSON
+    fn decode_all(&self, payload: &[u8]) -> Vec<Result<Value, TransportError>> {
+        let mut messages = Vec::new();
+        for message in serde_json::Deserializer::from_slice(payload).into_iter::<Value>() {
+            match message {
+                Ok(message) => messages.push(Ok(message)),
+                Err(e) => {
+                    // The end of the invalid part cannot be found, so the rest of the payload is skipped
+                    messages.push(Err(TransportError::from(e)));
+                    break;
+                },
+            }
+        }
+        if messages.is_empty() {
+            // The payload is empty or contains only whitespace
+            messages.push(self.decode(payload));
+        }
+        messages
+    }
+    /// Compact JSON has no whitespace between values, and control characters in strings are escaped
+    fn may_contain(&self, delimiter: u8) -> bool {
+        delimiter >= 0x20
+    }
+}
+
+///
+/// A codec that encodes messages as indented JSON, which is easier for people to read
+///
+/// This is intended for debugging and for peers that expect indented output. The encoded
+/// payloads contain newlines, so they cannot be sent with the default newline delimiter, and
+/// endpoints reject this codec when their transport uses it. Use a transport that does not
+/// separate payloads with newlines, like a stream transport created with with_delimiter and a
+/// delimiter such as NUL (0). Payloads are decoded in the same way as with JSONCodec.
+///
+#[derive(Debug, Clone, Default)]
+pub struct PrettyJSONCodec;
+
+impl Codec for PrettyJSONCodec {
+    fn encode(&self, message: &Value) -> Result<Vec<u8>, TransportError> {
+        serde_json::to_vec_pretty(message).map_err(TransportError::from_encode_error)
+    }
+    fn decode(&self, payload: &[u8]) -> Result<Value, TransportError> {
+        JSONCodec.decode(payload)
+    }
+    fn decode_all(&self, payload: &[u8]) -> Vec<Result<Value, TransportError>> {
+        JSONCodec.decode_all(payload)
+    }
+    /// Indented JSON also contains newlines between values
+    fn may_contain(&self, delimiter: u8) -> bool {
+        delimiter == b'\n' || JSONCodec.may_contain(delimiter)
+    }
+}
+
+///
+/// A codec for peers that use JSON-RPC 1.0
+///
+/// Messages are encoded as compact JSON, like JSONCodec, and converted between the 1.0 format and
+/// the 2.0 format that the rest of this library uses:
+///
+/// * Encoded requests and responses have no jsonrpc member
+/// * A notification is encoded as a request with a null ID, and a request with a null ID is
+///   decoded as a notification
+/// * Encoded requests without parameters get an empty parameter array
+/// * Encoded responses have both result and error members, one of which is null. When a response
+///   is decoded, the null member is removed.
+///
+/// JSON-RPC 1.0 only supports positional parameters. Requests with named parameters are sent
+/// unchanged, which some 1.0 peers accept.
+///
+/// Use this codec with ClientEndpointBuilder::codec or ServerConfig::codec.
+///
+#[derive(Debug, Clone, Default)]
+pub struct JSONRPC1Codec;
+
+impl JSONRPC1Codec {
+    /// Converts a 2.0 message into a 1.0 message
+    fn to_version_1(message: &mut Value) {
+        match *message {
+            Value::Array(ref mut elements) => elements.iter_mut().for_each(JSONRPC1Codec::to_version_1),
+            Value::Object(ref mut map) => {
+                map.remove("jsonrpc");
+                if map.contains_key("method") {
+                    map.entry("id").or_insert(Value::Null);
+                    map.entry("params").or_insert_with(|| Value::Array(Vec::new()));
+                } else {
+                    map.entry("result").or_insert(Value::Null);
+                    map.entry("error").or_insert(Value::Null);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Converts a 1.0 message into a 2.0 message
+    fn to_version_2(message: &mut Value) {
+        match *message {
+            Value::Array(ref mut elements) => elements.iter_mut().for_each(JSONRPC1Codec::to_version_2),
+            Value::Object(ref mut map) => {
+                if map.contains_key("method") {
+                    if map.get("id") == Some(&Value::Null) {
+                        map.remove("id");
+                    }
+                } else if map.get("error") == Some(&Value::Null) {
+                    map.remove("error");
+                } else if map.get("result") == Some(&Value::Null) {
+                    map.remove("result");
+                }
+                map.insert("jsonrpc".to_string(), Value::from("2.0"));
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Codec for JSONRPC1Codec {
+    fn encode(&self, message: &Value) -> Result<Vec<u8>, TransportError> {
+        let mut message = message.clone();
+        JSONRPC1Codec::to_version_1(&mut message);
+        JSONCodec.encode(&message)
+    }
+    fn decode(&self, payload: &[u8]) -> Result<Value, TransportError> {
+        let mut message = JSONCodec.decode(payload)?;
+        JSONRPC1Codec::to_version_2(&mut message);
+        Ok(message)
+    }
+    fn decode_all(&self, payload: &[u8]) -> Vec<Result<Value, TransportError>> {
+        JSONCodec.decode_all(payload).into_iter()
+            .map(|message| message.map(|mut message| {
+                JSONRPC1Codec::to_version_2(&mut message);
+                message
+            }))
+            .collect()
+    }
+    fn may_contain(&self, delimiter: u8) -> bool {
+        JSONCodec.may_contain(delimiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::{Request, Response, Error};
+
+    #[test]
+    fn jsonrpc1() {
+        let codec = JSONRPC1Codec;
+        let notification = Request::new("notify", None);
+        assert_eq!(codec.encode(&notification.to_json()).unwrap(), b"{\"id\":null,\"method\":\"notify\",\"params\":[]}");
+        let request = Request::from_json(codec.decode(b"{\"id\":null,\"method\":\"notify\",\"params\":[]}").unwrap()).unwrap();
+        assert!(request.is_notification());
+
+        let mut response = Response::err(Error::method_not_found());
+        response.set_id(Value::from(1));
+        assert_eq!(codec.encode(&response.to_json()).unwrap(),
+            &b"{\"error\":{\"code\":-32601,\"message\":\"Method not found\"},\"id\":1,\"result\":null}"[..]);
+        let json = codec.decode(b"{\"result\":3,\"error\":null,\"id\":1}").unwrap();
+        let response = Response::from_json(json.as_object().unwrap().clone().into_iter().collect()).unwrap();
+        assert_eq!(response.payload, Ok(Value::from(3)));
+    }
+
+    #[test]
+    fn pretty() {
+        let message = json_object("id", Value::from(1));
+        let payload = PrettyJSONCodec.encode(&message).unwrap();
+        assert_eq!(payload, b"{\n  \"id\": 1\n}");
+        assert_eq!(PrettyJSONCodec.decode(&payload).unwrap(), message);
+    }
+
+    #[test]
+    fn decode_all() {
+        let messages = JSONCodec.decode_all(b"{\"id\":1} [2]{\"id\":3} {\"id");
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].as_ref().unwrap(), &json_object("id", Value::from(1)));
+        assert_eq!(messages[1].as_ref().unwrap(), &Value::Array(vec![Value::from(2)]));
+        assert_eq!(messages[2].as_ref().unwrap(), &json_object("id", Value::from(3)));
+        assert!(messages[3].is_err());
+
+        let messages = JSONCodec.decode_all(b"");
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].is_err());
+    }
+
+    #[test]
+    fn may_contain() {
+        // A string with every ASCII character and some that are not ASCII
+        let text: String = (0u8..0x80).map(char::from).chain("\u{e9}\u{2028}\u{1f600}".chars()).collect();
+        let payload = JSONCodec.encode(&json_object(&text, Value::String(text.clone()))).unwrap();
+        for byte in 0..=255u8 {
+            if payload.contains(&byte) {
+                assert!(JSONCodec.may_contain(byte), "Payload contains {:#x}", byte);
+            }
+        }
+        assert!(!JSONCodec.may_contain(b'\n'));
+        assert!(!JSONCodec.may_contain(0));
+        assert!(JSONCodec.may_contain(b'}'));
+        assert!(PrettyJSONCodec.may_contain(b'\n'));
+        assert!(!PrettyJSONCodec.may_contain(0));
+    }
+
+    /// Returns an object with one member
+    fn json_object(key: &str, value: Value) -> Value {
+        let mut map = serde_json::Map::new();
+        map.insert(key.to_string(), value);
+        Value::Object(map)
+    }
+}
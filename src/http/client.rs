@@ -3,36 +3,244 @@
 //!
 
 use transport::{ClientTransport, TransportError, PayloadHandler};
+use super::proxy::{Proxy, ProxyConnector, NoSsl};
 use hyper;
-use hyper::client::Client;
-use hyper::client::IntoUrl;
+use hyper::client::{Client, IntoUrl, ProxyConfig};
+use hyper::net::{HttpConnector, HttpsConnector, SslClient};
 use hyper::Url;
 use hyper::status::StatusCode;
-use hyper::header::ContentType;
+use hyper::header::{ContentType, Headers};
 use hyper::mime::{Mime, TopLevel, SubLevel};
-use std::thread;
-use std::sync::{Arc, Mutex};
+use spawn::{Spawn, ThreadSpawner};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::io;
 use std::io::Read;
+use chrono::{Duration, Local};
+
+/// Counter used to give each requestor thread a unique name
+static NEXT_REQUEST_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates clients for an HTTPS server, connecting through a proxy if one is provided
+type HttpsClients = Arc<dyn Fn(Option<&Proxy>) -> Client + Send + Sync>;
+
+///
+/// The status and headers of an HTTP response
+///
+#[derive(Debug, Clone)]
+pub struct ResponseMetadata {
+    /// The status code
+    pub status: StatusCode,
+    /// The headers
+    pub headers: Headers,
+}
+
+///
+/// Trait for something to be notified of the status and headers of each HTTP response
+///
+pub trait MetadataHandler: 'static + Send + Sync {
+    ///
+    /// Called when an HTTP response is received, before its body is passed to the payload handler
+    ///
+    fn metadata_received(&mut self, metadata: ResponseMetadata);
+}
+/// MetadataHandler implementation for closures
+impl<F> MetadataHandler for F where F: Fn(ResponseMetadata), F: 'static + Send + Sync {
+    fn metadata_received(&mut self, metadata: ResponseMetadata) {
+        self(metadata)
+    }
+}
 
 ///
 /// An HTTP-based client transport implementation
+///
+/// Each payload is sent in its own POST request, and the body of the HTTP response is passed to
+/// the payload handler unchanged. A batch payload sent with ClientEndpoint::send_batch therefore
+/// takes one HTTP round trip, and the array of responses in the body is dispatched by the client.
+///
+/// Requests to HTTPS servers need a TLS implementation, which is provided with set_ssl_client.
+///
 pub struct HTTPClientTransport {
     /// The URL of the server endpoint
     url: Url,
     /// The payload handler
-    payload_handler: Option<Arc<Mutex<Box<PayloadHandler>>>>,
+    payload_handler: Option<Arc<Mutex<Box<dyn PayloadHandler>>>>,
+    /// The content type to send with requests
+    content_type: Mime,
+    /// The handler that is notified of response metadata, if any
+    metadata_handler: Option<Arc<Mutex<Box<dyn MetadataHandler>>>>,
+    /// Tracks the requests that are in progress
+    in_flight: Arc<InFlight>,
+    /// Starts the threads that send requests
+    spawner: Box<dyn Spawn>,
+    /// The proxy that requests are sent through, if any
+    proxy: Option<Proxy>,
+    /// Creates clients for HTTPS servers, or None if no TLS implementation has been provided
+    https_clients: Option<HttpsClients>,
 }
 
 impl HTTPClientTransport {
+    ///
+    /// Creates a transport that sends requests to a URL
+    ///
+    /// Returns an error if the URL is not valid. Use parse for an error that describes the
+    /// problem.
+    ///
+    #[allow(clippy::result_unit_err)]
     pub fn new<U>(url: U) -> Result<HTTPClientTransport, ()> where U: IntoUrl {
         match url.into_url() {
-            Ok(url) => Ok(HTTPClientTransport {
-                url: url,
-                payload_handler: None,
-            }),
+            Ok(url) => Ok(HTTPClientTransport::with_url(url)),
             Err(_) => Err(()),
         }
     }
+
+    ///
+    /// Creates a transport that sends requests to a URL parsed from a string
+    ///
+    /// Returns TransportError::IOError with the kind InvalidInput if the URL is not valid.
+    ///
+    pub fn parse(url: &str) -> Result<HTTPClientTransport, TransportError> {
+        match Url::parse(url) {
+            Ok(url) => Ok(HTTPClientTransport::with_url(url)),
+            Err(e) => Err(TransportError::IOError(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Invalid URL {}: {}", url, e)))),
+        }
+    }
+
+    ///
+    /// Creates a transport that sends requests to a URL from the url crate
+    ///
+    /// Any version of the url crate can be used, because its Url type can be viewed as a string.
+    /// The URL is parsed again, so this returns an error in the same cases as parse.
+    ///
+    pub fn from_url<U>(url: &U) -> Result<HTTPClientTransport, TransportError> where U: AsRef<str> + ?Sized {
+        HTTPClientTransport::parse(url.as_ref())
+    }
+
+    fn with_url(url: Url) -> HTTPClientTransport {
+        HTTPClientTransport {
+            url,
+            payload_handler: None,
+            content_type: Mime(TopLevel::Application, SubLevel::Json, vec![]),
+            metadata_handler: None,
+            in_flight: Arc::new(InFlight {
+                count: Mutex::new(0),
+                finished: Condvar::new(),
+                closed: AtomicBool::new(false),
+            }),
+            spawner: Box::new(ThreadSpawner),
+            proxy: None,
+            https_clients: None,
+        }
+    }
+
+    ///
+    /// Sets the content type to send with requests
+    ///
+    /// The default content type is application/json. Some servers require a different type,
+    /// such as application/json-rpc, or a charset parameter.
+    ///
+    pub fn set_content_type(&mut self, content_type: Mime) {
+        self.content_type = content_type;
+    }
+
+    ///
+    /// Blocks until all requests that have been sent have finished
+    ///
+    /// Returns TransportError::TimedOut if requests are still in progress when the timeout expires.
+    ///
+    pub fn wait_for_requests(&self, timeout: &Duration) -> Result<(), TransportError> {
+        self.in_flight_handle().wait(timeout)
+    }
+
+    ///
+    /// Returns a handle that tracks the requests that this transport starts
+    ///
+    /// ClientEndpoint takes ownership of its transport, so take a handle before creating the
+    /// endpoint to wait for requests or shut down later.
+    ///
+    pub fn in_flight_handle(&self) -> InFlightHandle {
+        InFlightHandle(self.in_flight.clone())
+    }
+
+    ///
+    /// Sets the proxy that requests are sent through, or None to connect directly
+    ///
+    /// The default is to connect directly. Requests to HTTP servers are sent to the proxy, and
+    /// requests to HTTPS servers are sent through a tunnel that the proxy opens.
+    ///
+    pub fn set_proxy(&mut self, proxy: Option<Proxy>) {
+        self.proxy = proxy;
+    }
+
+    ///
+    /// Sets the proxy to the one that the environment variables select for the server URL
+    ///
+    /// This uses HTTP_PROXY or HTTPS_PROXY, depending on the scheme of the URL, and does not
+    /// use a proxy if the server's host is listed in NO_PROXY (see Proxy::from_env).
+    ///
+    pub fn set_proxy_from_env(&mut self) {
+        self.proxy = Proxy::from_env(self.url.as_str());
+    }
+
+    ///
+    /// Sets the TLS implementation used for requests to HTTPS servers
+    ///
+    /// hyper does not include a TLS implementation. Any implementation of its SslClient trait
+    /// can be used. Without one, each payload sent to an HTTPS server produces an IOError with
+    /// the kind InvalidInput, which is passed to the payload handler.
+    ///
+    pub fn set_ssl_client<S>(&mut self, ssl: S) where S: SslClient + Clone + Send + Sync + 'static {
+        self.https_clients = Some(Arc::new(move |proxy: Option<&Proxy>| match proxy {
+            Some(proxy) => Client::with_connector(ProxyConnector::new(proxy.clone(), ssl.clone())),
+            None => Client::with_connector(HttpsConnector::new(ssl.clone())),
+        }));
+    }
+
+    /// Creates a client that sends requests to the server through the proxy, if any
+    fn client(&self) -> Result<Client, TransportError> {
+        if self.url.scheme() == "https" {
+            return match self.https_clients {
+                Some(ref clients) => Ok(clients(self.proxy.as_ref())),
+                None => Err(TransportError::IOError(io::Error::new(io::ErrorKind::InvalidInput,
+                    "Sending requests to an HTTPS server requires an SslClient (see set_ssl_client)"))),
+            };
+        }
+        match self.proxy {
+            // The proxy config makes hyper send the full URL in the request line
+            Some(ref proxy) => Ok(Client::with_proxy_config(ProxyConfig::new("http", proxy.host.clone(), proxy.port,
+                HttpConnector, NoSsl))),
+            None => Ok(Client::new()),
+        }
+    }
+
+    /// Returns the headers to send with each request
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(ContentType(self.content_type.clone()));
+        // Credentials for a tunnel are sent in the CONNECT request instead
+        if self.url.scheme() == "http" {
+            if let Some(authorization) = self.proxy.as_ref().and_then(Proxy::authorization) {
+                headers.set_raw("Proxy-Authorization", vec![authorization.into_bytes()]);
+            }
+        }
+        headers
+    }
+
+    /// Sets the Spawn used to start a thread for each request. The default is ThreadSpawner.
+    pub fn set_spawner<S>(&mut self, spawner: S) where S: Spawn {
+        self.spawner = Box::new(spawner);
+    }
+
+    ///
+    /// Sets a handler to be notified of the status code and headers of each response
+    ///
+    /// This can be used to react to information that a server sends in headers, such as rate
+    /// limits. Response bodies are still passed to the payload handler.
+    ///
+    pub fn set_metadata_handler<H>(&mut self, handler: H) where H: MetadataHandler {
+        self.metadata_handler = Some(Arc::new(Mutex::new(Box::new(handler))));
+    }
 }
 
 impl ClientTransport for HTTPClientTransport {
@@ -40,13 +248,25 @@ impl ClientTransport for HTTPClientTransport {
         self.payload_handler = Some(Arc::new(Mutex::new(Box::new(handler))));
     }
 
-    fn send(&mut self, payload: &str) -> Result<(), TransportError> {
+    fn send(&mut self, payload: &[u8]) -> Result<(), TransportError> {
         match self.payload_handler {
             Some(ref handler) => {
-                let requestor = Requestor::new(self.url.clone(), String::from(payload), handler.clone());
-                let _ = thread::spawn(move || {
+                let client = match self.client() {
+                    Ok(client) => client,
+                    Err(e) => {
+                        // Like a failure to connect, this is reported to the payload handler
+                        handler.lock().expect("Payload handler mutex poisoned").payload_received(Err(e));
+                        return Ok(());
+                    },
+                };
+                let requestor = Requestor::new(self.url.clone(), self.headers(), payload.to_vec(),
+                    handler.clone(), self.metadata_handler.clone(), InFlightGuard::new(self.in_flight.clone()),
+                    client);
+                let number = NEXT_REQUEST_NUMBER.fetch_add(1, Ordering::Relaxed);
+                let name = format!("HTTPClientTransport request {}", number);
+                self.spawner.spawn(name, Box::new(move || {
                     requestor.run();
-                });
+                })).map_err(TransportError::IOError)?;
 
                 Ok(())
             },
@@ -55,49 +275,157 @@ impl ClientTransport for HTTPClientTransport {
     }
 }
 
+///
+/// Stops requests that are in progress from calling the handlers
+///
+/// The threads that send requests cannot be interrupted. They keep running until their requests
+/// finish, but they do not call the payload handler or metadata handler after the transport
+/// has been dropped. Use wait_for_requests or InFlightHandle::wait before dropping the transport
+/// to let requests finish.
+///
+impl Drop for HTTPClientTransport {
+    fn drop(&mut self) {
+        self.in_flight.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+///
+/// A handle to the requests that an HTTPClientTransport has started
+///
+/// Handles can be cloned, and remain usable after the transport has been moved into a
+/// ClientEndpoint or dropped.
+///
+#[derive(Clone)]
+pub struct InFlightHandle(Arc<InFlight>);
+
+impl InFlightHandle {
+    /// Returns the number of requests that are in progress
+    pub fn count(&self) -> usize {
+        *self.0.count.lock().expect("In-flight mutex poisoned")
+    }
+
+    ///
+    /// Blocks until all requests that have been started have finished
+    ///
+    /// Returns TransportError::TimedOut if requests are still in progress when the timeout expires.
+    ///
+    pub fn wait(&self, timeout: &Duration) -> Result<(), TransportError> {
+        let end = Local::now() + *timeout;
+        let mut count = self.0.count.lock().expect("In-flight mutex poisoned");
+        while *count != 0 {
+            let remaining = match (end - Local::now()).to_std() {
+                Ok(remaining) => remaining,
+                Err(_) => return Err(TransportError::TimedOut),
+            };
+            count = self.0.finished.wait_timeout(count, remaining).expect("In-flight mutex poisoned").0;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Stops requests that are in progress from calling the payload handler and metadata handler
+    ///
+    /// This has the same effect as dropping the transport. Requests that are sent later are
+    /// still sent, but their responses are also ignored.
+    ///
+    pub fn close(&self) {
+        self.0.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The state of the requests that a transport has started
+struct InFlight {
+    /// The number of requests in progress
+    count: Mutex<usize>,
+    /// Notified when a request finishes
+    finished: Condvar,
+    /// True if the transport has been dropped
+    closed: AtomicBool,
+}
+
+/// Counts a request as in progress until it is dropped
+struct InFlightGuard(Arc<InFlight>);
+
+impl InFlightGuard {
+    fn new(in_flight: Arc<InFlight>) -> InFlightGuard {
+        *in_flight.count.lock().expect("In-flight mutex poisoned") += 1;
+        InFlightGuard(in_flight)
+    }
+
+    /// Returns true if the transport has been dropped
+    fn closed(&self) -> bool {
+        self.0.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut count) = self.0.count.lock() {
+            *count -= 1;
+        }
+        self.0.finished.notify_all();
+    }
+}
+
 /// Sends an HTTP request and processes the response
 struct Requestor {
     /// The URL of the endpoint
     url: Url,
+    /// The headers to send
+    headers: Headers,
     /// The payload to send
-    payload: String,
-    /// The handler to
-    handler: Arc<Mutex<Box<PayloadHandler>>>,
+    payload: Vec<u8>,
+    /// The handler to call with the response body
+    handler: Arc<Mutex<Box<dyn PayloadHandler>>>,
+    /// The handler to call with the response status and headers
+    metadata_handler: Option<Arc<Mutex<Box<dyn MetadataHandler>>>>,
+    /// Counts this request as in progress
+    in_flight: InFlightGuard,
+    /// The client that sends the request
+    client: Client,
 }
 
 impl Requestor {
     /// Creates a new Requestor
-    pub fn new(url: Url, payload: String, handler: Arc<Mutex<Box<PayloadHandler>>>) -> Requestor {
+    pub fn new(url: Url, headers: Headers, payload: Vec<u8>, handler: Arc<Mutex<Box<dyn PayloadHandler>>>,
+        metadata_handler: Option<Arc<Mutex<Box<dyn MetadataHandler>>>>, in_flight: InFlightGuard,
+        client: Client) -> Requestor {
         Requestor {
-            url: url,
-            payload: payload,
-            handler: handler,
+            url,
+            headers,
+            payload,
+            handler,
+            metadata_handler,
+            in_flight,
+            client,
         }
     }
 
     /// Sends the request and processes the response
     pub fn run(self) {
-        let client = Client::new();
-        let result = client.post(self.url)
-            .header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
-            .body(&self.payload)
+        let result = self.client.post(self.url)
+            .headers(self.headers)
+            .body(&self.payload[..])
             .send();
 
         match result {
             Ok(mut response) => {
-                match response.status {
-                    StatusCode::Ok => {
-
-                    },
-                    _ => {
-
-                    },
-                };
+                if let Some(metadata_handler) = self.metadata_handler {
+                    if self.in_flight.closed() {
+                        return;
+                    }
+                    let metadata = ResponseMetadata {
+                        status: response.status,
+                        headers: response.headers.clone(),
+                    };
+                    let mut metadata_handler = metadata_handler.lock().expect("Metadata handler mutex poisoned");
+                    metadata_handler.metadata_received(metadata);
+                }
 
-                let mut response_string = String::new();
-                match response.read_to_string(&mut response_string) {
-                    Ok(_) => Self::call_handler(self.handler, Ok(response_string)),
-                    Err(e) => Self::call_handler(self.handler, Err(TransportError::from(e))),
+                let mut response_body = Vec::new();
+                match response.read_to_end(&mut response_body) {
+                    Ok(_) => Self::call_handler(&self.in_flight, self.handler, Ok(response_body)),
+                    Err(e) => Self::call_handler(&self.in_flight, self.handler, Err(TransportError::from(e))),
                 }
             },
             Err(http_err) => {
@@ -106,12 +434,16 @@ impl Requestor {
                     hyper::error::Error::Io(io_err) => TransportError::from(io_err),
                     _ => TransportError::Other,
                 };
-                Self::call_handler(self.handler, Err(err));
+                Self::call_handler(&self.in_flight, self.handler, Err(err));
             }
         };
     }
 
-    fn call_handler(handler: Arc<Mutex<Box<PayloadHandler>>>, result: Result<String, TransportError>) {
+    /// Calls the payload handler, unless the transport has been dropped
+    fn call_handler(in_flight: &InFlightGuard, handler: Arc<Mutex<Box<dyn PayloadHandler>>>, result: Result<Vec<u8>, TransportError>) {
+        if in_flight.closed() {
+            return;
+        }
         let mut handler = handler.lock().expect("Payload handler mutex poisoned");
         handler.payload_received(result);
     }
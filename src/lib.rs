@@ -6,25 +6,35 @@ pub mod client;
 pub mod server;
 pub mod transport;
 pub mod message;
+pub mod codec;
+pub mod spawn;
 
 #[cfg(feature = "http")]
 extern crate hyper;
 #[cfg(feature = "http")]
-extern crate url;
-#[cfg(feature = "http")]
 pub mod http;
 
 use message::*;
 
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "futures")]
+extern crate futures;
+#[cfg(feature = "jsonschema")]
+extern crate jsonschema;
+
+extern crate serde;
 extern crate serde_json;
 extern crate chrono;
+extern crate net2;
 
 use std::sync::mpsc::Sender;
 
 ///
 /// Trait for a function object that can respond to a request and provide a response.
 ///
-/// Implementations are provided for tuples of two closures.
+/// Implementations are provided for tuples of two closures, and for single closures that handle
+/// requests and ignore notifications.
 ///
 pub trait RequestHandler : 'static + Send + Sync {
     /// Handles a JSON RPC request. Returns the result or an error.
@@ -45,6 +55,14 @@ impl<F, G> RequestHandler for (F, G) where F: Fn(Request) -> Result<Value, Error
     }
 }
 
+/// Implementation of RequestHandler for a closure that handles requests. Notifications are ignored.
+impl<F> RequestHandler for F where F: Fn(Request) -> Result<Value, Error>, F: 'static + Send + Sync {
+    fn handle_request(&mut self, request: Request) -> Result<Value, Error> {
+        self(request)
+    }
+    fn handle_notification(&mut self, _notification: Request) {}
+}
+
 ///
 /// Trait for a function object that can receive a response
 ///
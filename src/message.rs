@@ -1,12 +1,63 @@
-use serde_json::builder::ObjectBuilder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use serde_json::Map;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::io::Read;
+use std::slice;
+use std::str::FromStr;
+use std::vec;
+use chrono::Duration;
 
 pub use serde_json::Value;
 
+///
+/// The name of the named parameter that holds the number of milliseconds that the client will
+/// wait for a response
+///
+pub const TIMEOUT_PARAM: &str = "timeout_ms";
+
+///
+/// The name of the named parameter that Params::single accepts as the only parameter of a
+/// method that takes one parameter
+///
+pub const SINGLE_PARAM: &str = "value";
+
+///
+/// The error codes defined by the JSON RPC specification
+///
+/// Codes from -32768 to -32000 are reserved. Codes from -32099 to -32000 are available
+/// for implementation-defined server errors.
+///
+pub mod error_codes {
+    /// Invalid JSON was received
+    pub const PARSE_ERROR: i64 = -32700;
+    /// The JSON sent is not a valid request object
+    pub const INVALID_REQUEST: i64 = -32600;
+    /// The method does not exist or is not available
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    /// Invalid method parameters
+    pub const INVALID_PARAMS: i64 = -32602;
+    /// Internal JSON RPC error
+    pub const INTERNAL_ERROR: i64 = -32603;
+    ///
+    /// The client cancelled the request
+    ///
+    /// This is not defined by the JSON RPC specification. It is the code that the Language
+    /// Server Protocol uses.
+    ///
+    pub const REQUEST_CANCELLED: i64 = -32800;
+}
+
 ///
 /// The parameters of a request
 ///
-#[derive(Debug,PartialEq)]
+/// Parameters are either named or positional, never both. Named parameters always have string
+/// keys, because JSON object keys are strings. An empty array or object is valid and is
+/// different from having no parameters.
+///
+#[derive(Debug,Clone,PartialEq)]
 pub enum Params {
     /// Named parameters. Parameters are stored in a map.
     Named(BTreeMap<String, Value>),
@@ -17,18 +68,62 @@ pub enum Params {
 impl Params {
     pub fn to_json(&self) -> Value {
         match *self {
-            Params::Named(ref map) => Value::Object(map.clone()),
+            Params::Named(ref map) => Value::Object(map.clone().into_iter().collect()),
             Params::Positional(ref vec) => Value::Array(vec.clone()),
         }
     }
 
+    ///
+    /// Creates parameters from JSON
+    ///
+    /// Returns an invalid request error if the JSON is not an array or an object. Checking
+    /// whether the parameters are valid for a method is up to the method.
+    ///
     pub fn from_json(json: Value) -> Result<Params, Error> {
         match json {
-            Value::Object(map) => Ok(Params::Named(map)),
+            Value::Object(map) => Ok(Params::Named(map.into_iter().collect())),
             Value::Array(vec) => Ok(Params::Positional(vec)),
             _ => Err(Error::invalid_request()),
         }
     }
+
+    ///
+    /// Extracts a positional parameter and deserializes it into the requested type
+    ///
+    /// A parameter with an index past the end of the parameters is treated as null, so it can be
+    /// extracted into an Option to implement optional trailing parameters.
+    ///
+    /// Returns an invalid params error if these parameters are named or if the parameter
+    /// cannot be deserialized into the requested type.
+    ///
+    pub fn get_positional<T>(&self, index: usize) -> Result<T, Error> where T: DeserializeOwned {
+        match *self {
+            Params::Positional(ref values) => {
+                let value = values.get(index).cloned().unwrap_or(Value::Null);
+                serde_json::from_value(value).map_err(|_| Error::invalid_params())
+            },
+            Params::Named(_) => Err(Error::invalid_params()),
+        }
+    }
+
+    ///
+    /// Extracts the only parameter of a method that takes one parameter and deserializes it into
+    /// the requested type
+    ///
+    /// The parameter can be the only element of positional parameters, or the only named
+    /// parameter if its name is SINGLE_PARAM.
+    ///
+    /// Returns an invalid params error if there is not exactly one parameter in one of those forms
+    /// or if the parameter cannot be deserialized into the requested type.
+    ///
+    pub fn single<T>(&self) -> Result<T, Error> where T: DeserializeOwned {
+        let value = match *self {
+            Params::Positional(ref values) if values.len() == 1 => &values[0],
+            Params::Named(ref map) if map.len() == 1 => map.get(SINGLE_PARAM).ok_or_else(Error::invalid_params)?,
+            _ => return Err(Error::invalid_params()),
+        };
+        serde_json::from_value(value.clone()).map_err(|_| Error::invalid_params())
+    }
 }
 
 ///
@@ -36,11 +131,18 @@ impl Params {
 ///
 /// A request has an ID, a notification does not.
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Request {
     pub method: String,
     pub params: Option<Params>,
     pub id: Option<Value>,
+    ///
+    /// Members of the request object other than jsonrpc, method, params, and id
+    ///
+    /// These are kept when a request is parsed and included when it is converted to JSON, so
+    /// extensions that add members to requests pass through a proxy unchanged.
+    ///
+    pub extra: BTreeMap<String, Value>,
 }
 
 impl Request {
@@ -48,8 +150,9 @@ impl Request {
     pub fn new(method: &str, params: Option<Params>) -> Request {
         Request {
             method: method.to_string(),
-            params: params,
+            params,
             id: None,
+            extra: BTreeMap::new(),
         }
     }
 
@@ -58,39 +161,188 @@ impl Request {
         self.id = Some(id);
     }
 
+    ///
+    /// Returns true if this is a notification
+    ///
+    /// A request is a notification if and only if it has no id member. A request with an id
+    /// of null is not a notification and requires a response.
+    ///
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+
+    /// Returns the named parameters of this request, or None if it has no named parameters
+    pub fn named_params(&self) -> Option<&BTreeMap<String, Value>> {
+        match self.params {
+            Some(Params::Named(ref map)) => Some(map),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Returns the parameters of this request, for a method that requires parameters
+    ///
+    /// Returns an invalid params error if this request has no parameters. An empty array or
+    /// object counts as parameters.
+    ///
+    pub fn require_params(&self) -> Result<&Params, Error> {
+        self.params.as_ref().ok_or_else(Error::invalid_params)
+    }
+
+    /// Returns the positional parameters of this request, or None if it has no positional parameters
+    pub fn positional_params(&self) -> Option<&[Value]> {
+        match self.params {
+            Some(Params::Positional(ref values)) => Some(values),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Returns how long the client will wait for a response to this request, if the client
+    /// provided a timeout
+    ///
+    /// The timeout is read from the named parameter TIMEOUT_PARAM. A handler can use it to give up
+    /// on work that will finish after the client has stopped waiting.
+    ///
+    pub fn timeout(&self) -> Option<Duration> {
+        self.named_params()
+            .and_then(|params| params.get(TIMEOUT_PARAM))
+            .and_then(|value| value.as_u64())
+            .map(|milliseconds| Duration::milliseconds(milliseconds as i64))
+    }
+
+    ///
+    /// Adds a timeout to this request in the named parameter TIMEOUT_PARAM
+    ///
+    /// Returns false and does nothing if this request has positional parameters or no parameters.
+    /// Adding named parameters to a request without parameters could make a method that does not
+    /// take any parameters reject it.
+    ///
+    pub fn set_timeout(&mut self, timeout: &Duration) -> bool {
+        match self.params {
+            Some(Params::Named(ref mut map)) => {
+                let milliseconds = cmp::max(timeout.num_milliseconds(), 0) as u64;
+                map.insert(TIMEOUT_PARAM.to_string(), Value::from(milliseconds));
+                true
+            },
+            Some(Params::Positional(_)) | None => false,
+        }
+    }
+
     pub fn to_json(&self) -> Value {
-        let mut builder = ObjectBuilder::new()
-            .insert("jsonrpc", "2.0")
-            .insert("method", self.method.clone());
+        let mut map = Map::new();
+        for (key, value) in &self.extra {
+            map.insert(key.clone(), value.clone());
+        }
+        map.insert("jsonrpc".to_string(), Value::from("2.0"));
+        map.insert("method".to_string(), Value::String(self.method.clone()));
         if let Some(ref params_value) = self.params {
-            builder = builder.insert("params", params_value.to_json());
+            map.insert("params".to_string(), params_value.to_json());
         }
         if let Some(ref id_value) = self.id {
-            builder = builder.insert("id", id_value.clone());
+            map.insert("id".to_string(), id_value.clone());
         }
-        builder.unwrap()
+        Value::Object(map)
     }
 
+    ///
+    /// Creates a request from JSON
+    ///
+    /// The parameters and ID are moved out of the JSON value, not copied, so parsing a request
+    /// with large parameters does not use extra memory.
+    ///
+    /// Returns an invalid request error if the JSON is not an object with a string method and
+    /// optional array or object parameters.
+    ///
     pub fn from_json(json: Value) -> Result<Request, Error> {
         let err = Error::invalid_request();
         match json {
-            Value::Object(map) => {
-                let method = try!(try!(map.get("method").ok_or(err.clone())).as_string().ok_or(err.clone()));
-                let id = match map.get("id") {
-                    None => None,
-                    Some(id) => Some(id.clone()),
+            Value::Object(mut map) => {
+                let method = match map.remove("method") {
+                    Some(Value::String(method)) => method,
+                    _ => return Err(err),
                 };
-                let params = match map.get("params") {
-                    Some(params_json) => Some(try!(Params::from_json(params_json.clone()))),
+                let id = map.remove("id");
+                let params = match map.remove("params") {
+                    Some(params_json) => Some(Params::from_json(params_json)?),
                     None => None,
                 };
+                map.remove("jsonrpc");
                 Ok(Request {
-                    method: method.to_string(),
-                    params: params,
-                    id: id,
+                    method,
+                    params,
+                    id,
+                    extra: map.into_iter().collect(),
                 })
             },
-            _ => Err(err.clone()),
+            _ => Err(err),
+        }
+    }
+}
+
+///
+/// Parses a request from JSON text
+///
+/// Returns a parse error if the text is not valid JSON, or an invalid request error if it is not
+/// a request object.
+///
+impl FromStr for Request {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Request, Error> {
+        let json: Value = serde_json::from_str(s).map_err(|_| Error::parse_error())?;
+        Request::from_json(json)
+    }
+}
+
+impl Request {
+    ///
+    /// Parses a request from JSON read from a reader
+    ///
+    /// The JSON is parsed as it is read, without first collecting it into a string, so a large
+    /// request only needs memory for the parsed value. The reader should be buffered, and must
+    /// end after the request, for example by limiting it to one frame with Read::take.
+    ///
+    /// Returns a parse error if the reader does not contain valid JSON or cannot be read, or an
+    /// invalid request error if the JSON is not a request object.
+    ///
+    pub fn from_reader<R>(reader: R) -> Result<Request, Error> where R: Read {
+        let json: Value = serde_json::from_reader(reader).map_err(|_| Error::parse_error())?;
+        Request::from_json(json)
+    }
+}
+
+///
+/// A JSON RPC notification, which is a request that gets no response
+///
+/// A notification never has an ID. It is converted into a Request without an ID when it is sent.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub method: String,
+    pub params: Option<Params>,
+}
+
+impl Notification {
+    /// Creates a new notification
+    pub fn new(method: &str, params: Option<Params>) -> Notification {
+        Notification {
+            method: method.to_string(),
+            params,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Request::from(self.clone()).to_json()
+    }
+}
+
+impl From<Notification> for Request {
+    fn from(notification: Notification) -> Request {
+        Request {
+            method: notification.method,
+            params: notification.params,
+            id: None,
+            extra: BTreeMap::new(),
         }
     }
 }
@@ -98,7 +350,7 @@ impl Request {
 ///
 /// A JSON RPC response
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Response {
     /// The payload (result or error) of this response
     pub payload: Result<Value, Error>,
@@ -109,54 +361,231 @@ pub struct Response {
 impl Response {
     pub fn new(payload: Result<Value, Error>) -> Response {
         Response {
-            payload: payload,
+            payload,
             id: None,
         }
     }
 
+    ///
+    /// Creates a successful response with a result serialized from the provided value
+    ///
+    /// Returns an internal error if the value cannot be serialized.
+    ///
+    pub fn ok<T>(value: &T) -> Result<Response, Error> where T: Serialize {
+        let value = serde_json::to_value(value).map_err(|_| Error::internal_error())?;
+        Ok(Response::new(Ok(value)))
+    }
+
+    /// Creates an error response
+    pub fn err(error: Error) -> Response {
+        Response::new(Err(error))
+    }
+
     /// Sets the ID of this response
     pub fn set_id(&mut self, id: Value) {
         self.id = Some(id);
     }
 
+    /// Returns true if this response has an error instead of a result
+    pub fn is_error(&self) -> bool {
+        self.payload.is_err()
+    }
+
+    /// Returns the error of this response, or None if it has a result
+    pub fn error(&self) -> Option<&Error> {
+        self.payload.as_ref().err()
+    }
+
+    /// Returns the result of this response, or None if it has an error
+    pub fn ok_value(&self) -> Option<&Value> {
+        self.payload.as_ref().ok()
+    }
+
     pub fn to_json(&self) -> Value {
-        let mut builder = ObjectBuilder::new()
-            .insert("jsonrpc", "2.0");
+        let mut map = Map::new();
+        map.insert("jsonrpc".to_string(), Value::from("2.0"));
         match self.id {
-            Some(ref id) => builder = builder.insert("id", id.clone()),
-            None => builder = builder.insert("id", Value::Null),
-        }
+            Some(ref id) => map.insert("id".to_string(), id.clone()),
+            None => map.insert("id".to_string(), Value::Null),
+        };
         match self.payload {
-            Ok(ref result) => {
-                builder = builder.insert("result", result.clone());
-            },
-            Err(ref error) => {
-                builder = builder.insert("error", error.to_json());
-            }
-        }
-        builder.unwrap()
+            Ok(ref result) => map.insert("result".to_string(), result.clone()),
+            Err(ref error) => map.insert("error".to_string(), error.to_json()),
+        };
+        Value::Object(map)
     }
-    pub fn from_json(map: BTreeMap<String, Value>) -> Result<Response, Error> {
+    ///
+    /// Creates a response from a JSON object
+    ///
+    /// The result or error is moved out of the map, not copied, so parsing a response with
+    /// a large result does not use extra memory.
+    ///
+    /// The ID may be null, which a server uses when it cannot read the ID of a request. Returns
+    /// an invalid request error if the object does not have an ID and exactly one of a result and
+    /// a valid error.
+    ///
+    pub fn from_json(mut map: BTreeMap<String, Value>) -> Result<Response, Error> {
         let err = Error::invalid_request();
-        let id = try!(map.get("id").ok_or(err.clone())).clone();
-        let has_result = map.contains_key("result");
-        let has_error = map.contains_key("error");
-        let payload: Result<Value, Error> = try!(match (has_result, has_error) {
-            (true, false) => Ok(Ok(map.get("result").unwrap().clone())),
-            (false, true) => Ok(Err(try!(Error::from_json(map.get("error").unwrap().clone())))),
-            _ => Err(err.clone()),
-        });
+        let id = map.remove("id").ok_or(err.clone())?;
+        let payload: Result<Value, Error> = match (map.remove("result"), map.remove("error")) {
+            (Some(result), None) => Ok(result),
+            (None, Some(error)) => Err(Error::from_json(error)?),
+            _ => return Err(err),
+        };
         Ok(Response {
-            payload: payload,
+            payload,
             id: Some(id),
         })
     }
 }
 
+///
+/// The responses to a batch of requests
+///
+/// The responses are kept in the order that they were received, which may be different from the
+/// order of the requests. Responses can be looked up by the IDs of the requests that they answer.
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BatchResponse {
+    /// The responses
+    responses: Vec<Response>,
+}
+
+impl BatchResponse {
+    /// Creates a batch from responses
+    pub fn new(responses: Vec<Response>) -> BatchResponse {
+        BatchResponse { responses }
+    }
+
+    ///
+    /// Creates a batch from a JSON array of response objects
+    ///
+    /// Returns an invalid request error if the value is not an array or any element is not a
+    /// valid response.
+    ///
+    pub fn from_json(json: Value) -> Result<BatchResponse, Error> {
+        match json {
+            Value::Array(elements) => {
+                let responses = elements.into_iter().map(|element| match element {
+                    Value::Object(map) => Response::from_json(map.into_iter().collect()),
+                    _ => Err(Error::invalid_request()),
+                }).collect::<Result<Vec<Response>, Error>>()?;
+                Ok(BatchResponse::new(responses))
+            },
+            _ => Err(Error::invalid_request()),
+        }
+    }
+
+    /// Returns the response with the provided ID, if there is one
+    pub fn get(&self, id: &Value) -> Option<&Response> {
+        self.responses.iter().find(|response| response.id.as_ref() == Some(id))
+    }
+
+    /// Returns an iterator over the responses
+    pub fn iter(&self) -> slice::Iter<'_, Response> {
+        self.responses.iter()
+    }
+
+    /// Returns an iterator over the responses that contain errors
+    pub fn errors(&self) -> impl Iterator<Item = &Response> {
+        self.responses.iter().filter(|response| response.payload.is_err())
+    }
+
+    /// Returns true if any response contains an error
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// Returns the number of responses
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    /// Returns true if there are no responses
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    /// Returns the responses
+    pub fn into_responses(self) -> Vec<Response> {
+        self.responses
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.responses.iter().map(Response::to_json).collect())
+    }
+}
+
+impl<'a> IntoIterator for &'a BatchResponse {
+    type Item = &'a Response;
+    type IntoIter = slice::Iter<'a, Response>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.responses.iter()
+    }
+}
+
+impl IntoIterator for BatchResponse {
+    type Item = Response;
+    type IntoIter = vec::IntoIter<Response>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.responses.into_iter()
+    }
+}
+
+///
+/// Parses a response from JSON text
+///
+/// Returns a parse error if the text is not valid JSON, or an invalid request error if it is not
+/// a response object.
+///
+impl FromStr for Response {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Response, Error> {
+        match serde_json::from_str(s).map_err(|_| Error::parse_error())? {
+            Value::Object(map) => Response::from_json(map.into_iter().collect()),
+            _ => Err(Error::invalid_request()),
+        }
+    }
+}
+
+impl Response {
+    ///
+    /// Parses a response from JSON read from a reader
+    ///
+    /// Like Request::from_reader, this parses the JSON as it is read, which avoids keeping both
+    /// the text and the parsed value of a large result in memory. The reader should be buffered
+    /// and must end after the response.
+    ///
+    /// Returns a parse error if the reader does not contain valid JSON or cannot be read, or an
+    /// invalid request error if the JSON is not a response object.
+    ///
+    pub fn from_reader<R>(reader: R) -> Result<Response, Error> where R: Read {
+        match serde_json::from_reader(reader).map_err(|_| Error::parse_error())? {
+            Value::Object(map) => Response::from_json(map.into_iter().collect()),
+            _ => Err(Error::invalid_request()),
+        }
+    }
+}
+
+///
+/// Parses a sequence of JSON values from a reader, one at a time
+///
+/// Each value is parsed as it is read and returned before the next one is read, so a stream of
+/// messages can be processed without reading it into a string. The values may be separated by
+/// whitespace or not separated at all. Use Request::from_json or Response::from_json to convert
+/// each value into a message.
+///
+/// After a parse error, the position in the stream is unknown, so the iterator ends. The reader
+/// should be buffered.
+///
+pub fn read_values<R>(reader: R) -> impl Iterator<Item = Result<Value, Error>> where R: Read {
+    serde_json::Deserializer::from_reader(reader).into_iter::<Value>().map(|value| value.map_err(|_| Error::parse_error()))
+}
+
 ///
 /// A JSON RPC error
 ///
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Error {
     /// Error code
     code: i64,
@@ -169,42 +598,100 @@ pub struct Error {
 impl Error {
     pub fn new(code: i64, message: &str, data: Option<Value>) -> Error {
         Error {
-            code: code,
+            code,
             message: message.to_string(),
-            data: data,
+            data,
+        }
+    }
+
+    ///
+    /// Creates an error with the provided code and the standard message for that code
+    ///
+    /// Codes in the range reserved for implementation-defined server errors (-32099 to -32000)
+    /// get the message "Server error". Other unknown codes get the message "Unknown error".
+    ///
+    pub fn from_code(code: i64) -> Error {
+        match code {
+            error_codes::PARSE_ERROR => Error::parse_error(),
+            error_codes::INVALID_REQUEST => Error::invalid_request(),
+            error_codes::METHOD_NOT_FOUND => Error::method_not_found(),
+            error_codes::INVALID_PARAMS => Error::invalid_params(),
+            error_codes::INTERNAL_ERROR => Error::internal_error(),
+            error_codes::REQUEST_CANCELLED => Error::request_cancelled(),
+            -32099 ..= -32000 => Error::new(code, "Server error", None),
+            _ => Error::new(code, "Unknown error", None),
         }
     }
 
+    /// Returns the code of this error
+    pub fn code(&self) -> i64 {
+        self.code
+    }
+
+    /// Returns the message of this error
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the additional data of this error, if it has any
+    pub fn data(&self) -> Option<&Value> {
+        self.data.as_ref()
+    }
+
+    ///
+    /// Deserializes the additional data of this error into the requested type
+    ///
+    /// Returns None if this error has no data, or an error if the data cannot be deserialized
+    /// into the requested type.
+    ///
+    pub fn data_as<T>(&self) -> Option<Result<T, serde_json::Error>> where T: DeserializeOwned {
+        self.data.as_ref().map(|data| T::deserialize(data))
+    }
+
+    /// Sets the additional data of this error
+    pub fn set_data(&mut self, data: Value) {
+        self.data = Some(data);
+    }
+
     pub fn to_json(&self) -> Value {
-        let mut builder = ObjectBuilder::new()
-            .insert("code", self.code)
-            .insert("message", self.message.clone());
+        let mut map = Map::new();
+        map.insert("code".to_string(), Value::from(self.code));
+        map.insert("message".to_string(), Value::String(self.message.clone()));
         if let Some(ref data_value) = self.data {
-            builder = builder.insert("data", data_value.clone());
+            map.insert("data".to_string(), data_value.clone());
         }
-        builder.unwrap()
+        Value::Object(map)
     }
 
+    ///
+    /// Creates an error from a JSON object
+    ///
+    /// Returns an invalid request error if the JSON is not an object with an integer code and
+    /// a string message.
+    ///
     pub fn from_json(json: Value) -> Result<Error, Error> {
         let err = Error::invalid_request();
-        let map = try!(json.as_object().ok_or(err.clone()));
-        let code = try!(try!(map.get("id").ok_or(err.clone())).as_i64().ok_or(err.clone()));
-        let message = try!(try!(map.get("message").ok_or(err.clone())).as_string().ok_or(err.clone()));
-        let data = match map.get("data") {
-            Some(data) => Some(data.clone()),
-            None => None,
+        let mut map = match json {
+            Value::Object(map) => map,
+            _ => return Err(err),
+        };
+        let code = map.get("code").ok_or(err.clone())?.as_i64().ok_or(err.clone())?;
+        let message = match map.remove("message") {
+            Some(Value::String(message)) => message,
+            _ => return Err(err),
         };
+        let data = map.remove("data");
         Ok(Error {
-            code: code,
-            message: message.to_string(),
-            data: data,
+            code,
+            message,
+            data,
         })
     }
 
     /// Returns a standard error that indicates that the requested method was not found
     pub fn method_not_found() -> Error {
         Error {
-            code: -32601,
+            code: error_codes::METHOD_NOT_FOUND,
             message: "Method not found".to_string(),
             data: None,
         }
@@ -212,7 +699,7 @@ impl Error {
     /// Return a standard error that indicates a parsing failure
     pub fn parse_error() -> Error {
         Error {
-            code: -32700,
+            code: error_codes::PARSE_ERROR,
             message: "Parse error".to_string(),
             data: None,
         }
@@ -220,7 +707,7 @@ impl Error {
     /// Return a standard error that indicates an invalid request was sent
     pub fn invalid_request() -> Error {
         Error {
-            code: -32600,
+            code: error_codes::INVALID_REQUEST,
             message: "Invalid request".to_string(),
             data: None,
         }
@@ -228,7 +715,7 @@ impl Error {
     /// Return a standard error that indicates invalid parameters
     pub fn invalid_params() -> Error {
         Error {
-            code: -32602,
+            code: error_codes::INVALID_PARAMS,
             message: "Invalid params".to_string(),
             data: None,
         }
@@ -236,11 +723,19 @@ impl Error {
     /// Return a standard error that indicates an internal error
     pub fn internal_error() -> Error {
         Error {
-            code: -32603,
+            code: error_codes::INTERNAL_ERROR,
             message: "Internal error".to_string(),
             data: None,
         }
     }
+    /// Return an error that indicates that the client cancelled a request
+    pub fn request_cancelled() -> Error {
+        Error {
+            code: error_codes::REQUEST_CANCELLED,
+            message: "Request cancelled".to_string(),
+            data: None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -249,6 +744,7 @@ mod tests {
     use serde_json;
     use serde_json::Value;
     use std::collections::BTreeMap;
+    use chrono::Duration;
 
     #[test]
     fn params_named() {
@@ -258,24 +754,193 @@ mod tests {
 
         let mut expected_map: BTreeMap<String, Value> = BTreeMap::new();
         expected_map.insert("key_1".to_string(), Value::String("value_1".to_string()));
-        expected_map.insert("key_2".to_string(), Value::U64(39));
+        expected_map.insert("key_2".to_string(), Value::from(39));
         let expected_params = Params::Named(expected_map);
 
         assert_eq!(params, expected_params);
     }
     #[test]
+    #[allow(clippy::approx_constant)]
     fn params_positional() {
         let json_text = "[1, 2, 3, \"Pie\", -3.14]";
         let json = serde_json::from_str(json_text).unwrap();
         let params = Params::from_json(json).unwrap();
 
-        let expected_vec: Vec<Value> = vec![Value::U64(1), Value::U64(2), Value::U64(3),
-            Value::String("Pie".to_string()), Value::F64(-3.14)];
+        let expected_vec: Vec<Value> = vec![Value::from(1), Value::from(2), Value::from(3),
+            Value::String("Pie".to_string()), Value::from(-3.14)];
         let expected_params = Params::Positional(expected_vec);
 
         assert_eq!(params, expected_params);
     }
     #[test]
+    fn params_get_positional() {
+        let params = Params::Positional(vec![Value::from(1), Value::String("two".to_string())]);
+        assert_eq!(params.get_positional::<u64>(0).unwrap(), 1);
+        assert_eq!(params.get_positional::<Option<String>>(1).unwrap(), Some("two".to_string()));
+        // Out of range
+        assert_eq!(params.get_positional::<Option<u64>>(2).unwrap(), None);
+        assert!(params.get_positional::<u64>(2).is_err());
+        // Wrong type
+        assert!(params.get_positional::<String>(0).is_err());
+        assert!(params.get_positional::<Option<u64>>(1).is_err());
+        // Named
+        let params = Params::Named(BTreeMap::new());
+        assert!(params.get_positional::<Option<u64>>(0).is_err());
+    }
+    #[test]
+    fn request_params_accessors() {
+        let request = Request::new("m", Some(Params::Positional(vec![Value::from(1)])));
+        assert_eq!(request.positional_params(), Some(&[Value::from(1)][..]));
+        assert!(request.named_params().is_none());
+        let request = Request::new("m", Some(Params::Named(BTreeMap::new())));
+        assert!(request.positional_params().is_none());
+        assert_eq!(request.named_params(), Some(&BTreeMap::new()));
+        assert_eq!(request.require_params(), Ok(&Params::Named(BTreeMap::new())));
+        let request = Request::new("m", None);
+        assert!(request.positional_params().is_none());
+        assert!(request.named_params().is_none());
+        assert_eq!(request.require_params(), Err(Error::invalid_params()));
+    }
+    #[test]
+    fn request_timeout() {
+        let mut request = Request::new("m", Some(Params::Named(BTreeMap::new())));
+        assert!(request.timeout().is_none());
+        assert!(request.set_timeout(&Duration::milliseconds(1500)));
+        assert_eq!(request.timeout(), Some(Duration::milliseconds(1500)));
+        let mut request = Request::new("m", Some(Params::Positional(vec![])));
+        assert!(!request.set_timeout(&Duration::seconds(1)));
+        assert!(request.timeout().is_none());
+        // A request without parameters is not given any
+        let mut request = Request::new("m", None);
+        assert!(!request.set_timeout(&Duration::seconds(1)));
+        assert!(request.params.is_none());
+    }
+    #[test]
+    fn request_extra_members() {
+        let json_text = "{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1,\"trace\":\"abc\"}";
+        let json: Value = serde_json::from_str(json_text).unwrap();
+        let request = Request::from_json(json.clone()).unwrap();
+        assert_eq!(request.extra.get("trace"), Some(&Value::String("abc".to_string())));
+        assert!(!request.extra.contains_key("jsonrpc"));
+        assert_eq!(request.to_json(), json);
+    }
+    #[test]
+    fn from_reader() {
+        let text = "{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"params\":[1],\"id\":1}";
+        assert_eq!(Request::from_reader(text.as_bytes()), text.parse::<Request>());
+        let text = "{\"jsonrpc\":\"2.0\",\"result\":[1,2],\"id\":1}";
+        assert_eq!(Response::from_reader(text.as_bytes()).unwrap().ok_value(), Some(&Value::from(vec![1, 2])));
+        assert_eq!(Request::from_reader("{\"method\":".as_bytes()), Err(Error::parse_error()));
+        assert_eq!(Response::from_reader("[]".as_bytes()), Err(Error::invalid_request()));
+        // A frame with a known length can be read from a longer stream
+        let stream = "{\"method\":\"m\"}{\"method\":\"n\"}";
+        assert_eq!(Request::from_reader(stream.as_bytes().take(14)).unwrap().method, "m");
+    }
+    #[test]
+    fn read_values_stream() {
+        let stream = "{\"method\":\"m\"}\n{\"method\":\"n\"}[1]{\"method\":";
+        let values: Vec<Result<Value, Error>> = read_values(stream.as_bytes()).collect();
+        assert_eq!(values.len(), 4);
+        assert_eq!(Request::from_json(values[0].clone().unwrap()).unwrap().method, "m");
+        assert_eq!(Request::from_json(values[1].clone().unwrap()).unwrap().method, "n");
+        assert_eq!(values[2], Ok(Value::from(vec![1])));
+        assert_eq!(values[3], Err(Error::parse_error()));
+    }
+    #[test]
+    fn notification_to_request() {
+        let notification = Notification::new("m", Some(Params::Positional(vec![Value::from(1)])));
+        let request = Request::from(notification.clone());
+        assert!(request.is_notification());
+        assert_eq!(request, Request::new("m", Some(Params::Positional(vec![Value::from(1)]))));
+        let json: Value = serde_json::from_str("{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"params\":[1]}").unwrap();
+        assert_eq!(notification.to_json(), json);
+    }
+    #[test]
+    fn batch_response() {
+        let json: Value = serde_json::from_str("[{\"jsonrpc\":\"2.0\",\"result\":1,\"id\":2},\
+            {\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32601,\"message\":\"Method not found\"},\"id\":1}]").unwrap();
+        let batch = BatchResponse::from_json(json.clone()).unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.get(&Value::from(2)).unwrap().payload, Ok(Value::from(1)));
+        assert_eq!(batch.get(&Value::from(1)).unwrap().payload, Err(Error::method_not_found()));
+        assert!(batch.get(&Value::from(3)).is_none());
+        assert!(batch.has_errors());
+        let errors: Vec<&Response> = batch.errors().collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].id, Some(Value::from(1)));
+        assert_eq!(batch.to_json(), json);
+        assert!(BatchResponse::from_json(Value::from(1)).is_err());
+    }
+
+    #[test]
+    fn response_from_str() {
+        let response: Response = "{\"jsonrpc\":\"2.0\",\"id\":3,\"result\":true}".parse().unwrap();
+        assert_eq!(response.id, Some(Value::from(3)));
+        assert_eq!(response.payload, Ok(Value::Bool(true)));
+        assert_eq!(Response::from_str("[1]"), Err(Error::invalid_request()));
+        assert_eq!(Response::from_str("{"), Err(Error::parse_error()));
+    }
+    #[test]
+    fn response_accessors() {
+        let response = Response::new(Ok(Value::from(3)));
+        assert!(!response.is_error());
+        assert_eq!(response.ok_value(), Some(&Value::from(3)));
+        assert_eq!(response.error(), None);
+        let response = Response::err(Error::method_not_found());
+        assert!(response.is_error());
+        assert_eq!(response.ok_value(), None);
+        assert_eq!(response.error(), Some(&Error::method_not_found()));
+    }
+    #[test]
+    fn response_id() {
+        let response: Response = "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32700,\"message\":\"Parse error\"}}"
+            .parse().unwrap();
+        assert_eq!(response.id, Some(Value::Null));
+        assert_eq!(response.payload, Err(Error::parse_error()));
+        assert_eq!(Response::from_str("{\"jsonrpc\":\"2.0\",\"result\":true}"), Err(Error::invalid_request()));
+        assert_eq!(Response::from_str("{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32700,\"message\":\"Parse error\"}}"),
+            Err(Error::invalid_request()));
+    }
+    #[test]
+    fn error_data() {
+        let mut error = Error::invalid_params();
+        assert_eq!(error.message(), "Invalid params");
+        assert!(error.data().is_none());
+        assert!(error.data_as::<Vec<String>>().is_none());
+
+        error.set_data(Value::Array(vec![Value::from("name"), Value::from("age")]));
+        assert_eq!(error.data_as::<Vec<String>>().unwrap().unwrap(), vec!["name".to_string(), "age".to_string()]);
+        assert!(error.data_as::<u64>().unwrap().is_err());
+    }
+    #[test]
+    fn error_from_code() {
+        assert_eq!(Error::from_code(-32601), Error::method_not_found());
+        assert_eq!(Error::from_code(-32050), Error::new(-32050, "Server error", None));
+        assert_eq!(Error::from_code(12), Error::new(12, "Unknown error", None));
+    }
+    #[test]
+    fn params_empty() {
+        assert_eq!(Params::from_json(Value::Array(Vec::new())), Ok(Params::Positional(Vec::new())));
+        assert_eq!(Params::from_json(Value::Object(Map::new())), Ok(Params::Named(BTreeMap::new())));
+        assert_eq!(Params::from_json(Value::Null), Err(Error::invalid_request()));
+        let request: Request = "{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"params\":[],\"id\":1}".parse().unwrap();
+        assert_eq!(request.params, Some(Params::Positional(Vec::new())));
+    }
+    #[test]
+    fn params_single() {
+        let params = Params::Positional(vec![Value::from(7)]);
+        assert_eq!(params.single::<i64>(), Ok(7));
+        assert_eq!(params.single::<String>(), Err(Error::invalid_params()));
+        let mut map = BTreeMap::new();
+        map.insert(SINGLE_PARAM.to_string(), Value::from("text"));
+        assert_eq!(Params::Named(map.clone()).single::<String>(), Ok("text".to_string()));
+        map.insert("other".to_string(), Value::from(1));
+        assert_eq!(Params::Named(map).single::<String>(), Err(Error::invalid_params()));
+        assert_eq!(Params::Positional(Vec::new()).single::<Option<i64>>(), Err(Error::invalid_params()));
+        let params = Params::Positional(vec![Value::from(1), Value::from(2)]);
+        assert_eq!(params.single::<i64>(), Err(Error::invalid_params()));
+    }
+    #[test]
     fn params_number() {
         let json_text = "2465";
         let json = serde_json::from_str(json_text).unwrap();
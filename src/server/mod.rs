@@ -3,12 +3,79 @@
 //!
 
 pub mod stream;
+pub mod tcp;
+pub mod router;
+pub mod state;
 
-use serde_json;
 use transport::ServerTransport;
-use transport::ServerCallback;
+use transport::{ServerCallback, TransportError, WireLogger, Direction};
 use super::RequestHandler;
 use message::{Request, Response, Error, Value};
+use codec::{Codec, JSONCodec};
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+///
+/// Options that control how a server endpoint handles requests
+///
+#[derive(Clone)]
+pub struct ServerConfig {
+    ///
+    /// If true, requests with an id that is not a string, number, or null are rejected with an
+    /// invalid request error
+    ///
+    /// Regardless of this setting, a message with no id is a notification and a message with
+    /// any id (including null) is a request that receives a response.
+    ///
+    pub strict: bool,
+    ///
+    /// If true, a panic in the request handler is caught. A request that causes a panic gets
+    /// an internal error response with the panic message as its data, and the server continues
+    /// handling requests.
+    ///
+    /// If false, panics propagate and stop the transport.
+    ///
+    pub catch_panics: bool,
+    /// The codec used to decode requests and encode responses. The default is JSONCodec.
+    pub codec: Arc<dyn Codec>,
+    ///
+    /// The maximum number of elements in a batch request, or None for no limit
+    ///
+    /// A batch with more elements is not processed, and gets a single invalid request error
+    /// response. The default is None.
+    ///
+    pub max_batch_size: Option<usize>,
+    ///
+    /// A logger that receives every payload that the server receives and sends, or None for no
+    /// logging. This can be used to debug protocol problems. The default is None.
+    ///
+    pub wire_logger: Option<Arc<dyn WireLogger>>,
+    ///
+    /// The methods that the handler may receive, or None to allow all methods
+    ///
+    /// A request for a method that is not allowed gets a method not found error, and a
+    /// notification for a method that is not allowed is dropped, without calling the handler.
+    /// This can be used to expose some of the methods of a handler to untrusted clients. The
+    /// default is None.
+    ///
+    pub allowed_methods: Option<HashSet<String>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            strict: false,
+            catch_panics: true,
+            codec: Arc::new(JSONCodec),
+            max_batch_size: None,
+            wire_logger: None,
+            allowed_methods: None,
+        }
+    }
+}
 
 ///
 /// A server endpoint
@@ -21,13 +88,54 @@ pub struct ServerEndpoint<T> where T: ServerTransport {
 }
 
 impl<T> ServerEndpoint<T> where T: ServerTransport {
-    pub fn new<H>(transport: T, handler: H) -> ServerEndpoint<T> where H: RequestHandler {
-        let responder = Responder::new(handler);
+    ///
+    /// Creates an endpoint with the default configuration
+    ///
+    /// Returns an error if JSON payloads may contain the transport's delimiter (see with_config).
+    ///
+    pub fn new<H>(transport: T, handler: H) -> Result<ServerEndpoint<T>, TransportError> where H: RequestHandler {
+        ServerEndpoint::with_config(transport, handler, ServerConfig::default())
+    }
+
+    ///
+    /// Creates an endpoint with the provided configuration
+    ///
+    /// Returns an IOError with the kind InvalidInput if the configured codec's payloads may
+    /// contain the delimiter that the transport writes after each response, as with
+    /// PrettyJSONCodec and the default newline delimiter.
+    ///
+    pub fn with_config<H>(transport: T, handler: H, config: ServerConfig) -> Result<ServerEndpoint<T>, TransportError> where H: RequestHandler {
+        check_codec(&transport, &config)?;
+        let responder = Responder::new(handler, config);
         let mut transport = transport;
         transport.set_callback(responder);
-        ServerEndpoint {
-            transport: transport,
-        }
+        Ok(ServerEndpoint {
+            transport,
+        })
+    }
+
+    ///
+    /// Creates an endpoint that uses a separate handler for each connection, created by the
+    /// provided function
+    ///
+    /// With a transport that handles several connections, like TCPServerTransport, requests on
+    /// different connections are then handled at the same time. With a single shared handler,
+    /// as with new and with_config, they are handled one at a time.
+    ///
+    /// Returns an error if the configured codec's payloads may contain the transport's delimiter,
+    /// as with_config does.
+    ///
+    pub fn with_handler_factory<F, H>(transport: T, factory: F, config: ServerConfig) -> Result<ServerEndpoint<T>, TransportError>
+        where F: Fn() -> H, F: 'static + Send + Sync, H: RequestHandler {
+        check_codec(&transport, &config)?;
+        let mut transport = transport;
+        transport.set_callback_factory(Box::new(move || {
+            let callback: Box<dyn ServerCallback> = Box::new(Responder::new(factory(), config.clone()));
+            callback
+        }));
+        Ok(ServerEndpoint {
+            transport,
+        })
     }
 
     /// Runs the endpoint until a transport end-of-file condition occurs
@@ -36,20 +144,92 @@ impl<T> ServerEndpoint<T> where T: ServerTransport {
     }
 }
 
+/// Returns an error if responses encoded with the configured codec may contain the transport's delimiter
+fn check_codec<T>(transport: &T, config: &ServerConfig) -> Result<(), TransportError> where T: ServerTransport {
+    match transport.delimiter() {
+        Some(delimiter) if config.codec.may_contain(delimiter) => {
+            Err(TransportError::IOError(io::Error::new(io::ErrorKind::InvalidInput,
+                "Payloads encoded with the configured codec may contain the transport's delimiter")))
+        },
+        _ => Ok(()),
+    }
+}
+
 /// Interfaces between the transport mechanism and the application logic
 struct Responder<H> where H: RequestHandler {
     handler: H,
+    config: ServerConfig,
 }
 
 impl<H> Responder<H> where H: RequestHandler {
-    pub fn new(handler: H) -> Responder<H> {
-        Responder { handler: handler }
+    pub fn new(handler: H, config: ServerConfig) -> Responder<H> {
+        Responder {
+            handler,
+            config,
+        }
+    }
+
+    /// Returns true if the provided request ID is acceptable under the current configuration
+    fn id_allowed(&self, id: &Value) -> bool {
+        if self.config.strict {
+            matches!(*id, Value::String(_) | Value::Number(_) | Value::Null)
+        } else {
+            true
+        }
+    }
+
+    ///
+    /// Handles a batch of messages and returns an array of responses
+    ///
+    /// All the responses are returned together so that they are sent in one payload, as the
+    /// ServerCallback contract requires. Returns None if the batch contains only notifications.
+    ///
+    fn handle_batch(&mut self, elements: Vec<Value>) -> Option<Value> {
+        let too_large = matches!(self.config.max_batch_size, Some(max) if elements.len() > max);
+        if elements.is_empty() || too_large {
+            return Some(Response::new(Err(Error::invalid_request())).to_json());
+        }
+        let responses: Vec<Value> = elements.into_iter()
+            .filter_map(|element| self.handle_message(element))
+            .collect();
+        if responses.is_empty() {
+            None
+        } else {
+            Some(Value::Array(responses))
+        }
+    }
+
+    /// Handles one decoded message and returns an optional response
+    fn handle_message(&mut self, json: Value) -> Option<Value> {
+        // Extract the ID from the request for later use. An ID that is not allowed
+        // is not copied, so the error response will have a null ID.
+        let request_id: Option<Value> = match json {
+            Value::Object(ref map) => match map.get(&"id".to_string()) {
+                Some(id) if self.id_allowed(id) => Some(id.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+        self.handle_json(json).map(|mut json| {
+            // If the request JSON has an ID and the response has no ID or a null ID,
+            // assign the request's ID to the response
+            if let Value::Object(ref mut map) = json {
+                if let Some(id) = request_id {
+                    map.insert("id".to_string(), id);
+                }
+            }
+            json
+        })
     }
 
     /// Takes a JSON value, interprets it as a request or notification, and returns
     /// an optional reply
     fn handle_json(&mut self, json: Value) -> Option<Value> {
         match Request::from_json(json) {
+            Ok(ref request) if matches!(request.id, Some(ref id) if !self.id_allowed(id)) => {
+                let response = Response::new(Err(Error::invalid_request()));
+                Some(response.to_json())
+            },
             Ok(request) => {
                 let response = self.handle_request(request);
                 response.map(|response|{ response.to_json() })
@@ -63,51 +243,299 @@ impl<H> Responder<H> where H: RequestHandler {
         }
     }
 
+    /// Returns true if the provided method is acceptable under the current configuration
+    fn method_allowed(&self, method: &str) -> bool {
+        match self.config.allowed_methods {
+            Some(ref allowed) => allowed.contains(method),
+            None => true,
+        }
+    }
+
     fn handle_request(&mut self, request: Request) -> Option<Response> {
-        match request.id {
-            Some(_) => {
-                let result = self.handler.handle_request(request);
-                Some(Response::new(result))
-            },
-            None => {
-                self.handler.handle_notification(request);
+        if !self.method_allowed(&request.method) {
+            println!("Responder: Method {} is not allowed", request.method);
+            return if request.is_notification() {
                 None
-            },
+            } else {
+                Some(Response::new(Err(Error::method_not_found())))
+            };
+        }
+        if request.is_notification() {
+            let handler = &mut self.handler;
+            let result = Self::call_handler(self.config.catch_panics, move || handler.handle_notification(request));
+            if let Err(message) = result {
+                println!("Responder: Notification handler panicked: {:?}", message);
+            }
+            None
+        } else {
+            let handler = &mut self.handler;
+            let result = match Self::call_handler(self.config.catch_panics, move || handler.handle_request(request)) {
+                Ok(result) => result,
+                Err(message) => {
+                    let mut error = Error::internal_error();
+                    if let Some(message) = message {
+                        error.set_data(Value::String(message));
+                    }
+                    Err(error)
+                }
+            };
+            Some(Response::new(result))
+        }
+    }
+
+    ///
+    /// Calls a function that invokes the handler
+    ///
+    /// If catch_panics is true and the function panics, returns an error containing the
+    /// panic message if one is available.
+    ///
+    fn call_handler<F, R>(catch_panics: bool, f: F) -> Result<R, Option<String>> where F: FnOnce() -> R {
+        if catch_panics {
+            panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| panic_message(&payload))
+        } else {
+            Ok(f())
         }
     }
 }
 
+/// Extracts the message from a panic payload, if it has one
+fn panic_message(payload: &Box<dyn Any + Send>) -> Option<String> {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        Some(message.to_string())
+    } else {
+        payload.downcast_ref::<String>().cloned()
+    }
+}
+
 impl<H> ServerCallback for Responder<H> where H: RequestHandler {
-    fn handle_request(&mut self, request: String) -> Option<String> {
-        match serde_json::from_str(&request) {
-            Ok(json) => {
-                // Extract the ID from the request for later use
-                let request_id: Option<Value> = match json {
-                    Value::Object(ref map) => map.get(&"id".to_string()).map(|id| id.clone()),
-                    _ => None,
-                };
-                let result = self.handle_json(json);
-                match result {
-                    Some(mut json) => {
-                        // If the request JSON has an ID and the response has no ID or a null ID,
-                        // assign the request's ID to the response
-                        if let Value::Object(ref mut map) = json {
-                            if let Some(id) = request_id {
-                                map.insert("id".to_string(), id);
-                            }
-                        }
-                        let response_text = serde_json::to_string(&json).ok();
-                        response_text
-                    },
-                    None => None,
+    fn handle_request(&mut self, request: Vec<u8>) -> Option<Vec<u8>> {
+        if let Some(ref logger) = self.config.wire_logger {
+            logger.log_payload(Direction::Received, &request);
+        }
+        let response = self.respond(&request);
+        if let (Some(logger), Some(response)) = (self.config.wire_logger.as_ref(), response.as_ref()) {
+            logger.log_payload(Direction::Sent, response);
+        }
+        response
+    }
+}
+
+impl<H> Responder<H> where H: RequestHandler {
+    ///
+    /// Decodes and handles a payload, and returns the encoded response if there is one
+    ///
+    /// Unlike the client, the server decodes each payload as a single message, because it can
+    /// only send one response payload for each request payload. A payload that contains several
+    /// messages gets a parse error response.
+    ///
+    fn respond(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let response = match self.config.codec.decode(request) {
+            Ok(Value::Array(elements)) => self.handle_batch(elements),
+            Ok(json) => self.handle_message(json),
+            Err(_) => Some(Response::new(Err(Error::parse_error())).to_json()),
+        };
+        let json = response?;
+        match self.config.codec.encode(&json) {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                println!("Responder: Failed to encode response: {:?}", e);
+                // Report the failure to the client with a response that can be encoded
+                let mut error = Error::internal_error();
+                if let TransportError::EncodeError(message) = e {
+                    error.set_data(Value::String(message));
                 }
-            },
-            Err(_) => {
-                let err = Error::parse_error();
-                let response = Response::new(Err(err));
-                let response_text = serde_json::to_string(&response.to_json()).ok();
-                response_text
+                let mut response = Response::new(Err(error));
+                response.id = json.get("id").cloned();
+                self.config.codec.encode(&response.to_json()).ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use transport::ServerCallback;
+    use message::{Request, Error, Value};
+
+    /// A request handler made of a request function and a notification function
+    type TestHandler = (fn(Request) -> Result<Value, Error>, fn(Request));
+
+    fn responder(strict: bool) -> Responder<TestHandler> {
+        fn handle_request(_: Request) -> Result<Value, Error> {
+            Ok(Value::Bool(true))
+        }
+        fn handle_notification(_: Request) {}
+        let handler = (handle_request as fn(Request) -> Result<Value, Error>, handle_notification as fn(Request));
+        Responder::new(handler, ServerConfig { strict, ..ServerConfig::default() })
+    }
+
+    fn respond(strict: bool, request: &str) -> Option<Value> {
+        ServerCallback::handle_request(&mut responder(strict), request.as_bytes().to_vec())
+            .map(|response| serde_json::from_slice(&response).unwrap())
+    }
+
+    #[test]
+    fn null_id_is_request() {
+        for &strict in &[false, true] {
+            let response = respond(strict, "{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":null}")
+                .expect("Request with null ID got no response");
+            let map = response.as_object().unwrap();
+            assert_eq!(map.get("id"), Some(&Value::Null));
+            assert_eq!(map.get("result"), Some(&Value::Bool(true)));
+        }
+    }
+    #[test]
+    fn missing_id_is_notification() {
+        for &strict in &[false, true] {
+            assert!(respond(strict, "{\"jsonrpc\":\"2.0\",\"method\":\"m\"}").is_none());
+        }
+    }
+    #[test]
+    fn object_id_lenient() {
+        let response = respond(false, "{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":{}}")
+            .expect("Request got no response");
+        let map = response.as_object().unwrap();
+        assert!(map.get("id").unwrap().is_object());
+        assert_eq!(map.get("result"), Some(&Value::Bool(true)));
+    }
+    #[test]
+    fn handler_panic() {
+        fn handle_request(_: Request) -> Result<Value, Error> {
+            panic!("Handler failed")
+        }
+        fn handle_notification(_: Request) {}
+        let handler = (handle_request as fn(Request) -> Result<Value, Error>, handle_notification as fn(Request));
+        let mut responder = Responder::new(handler, ServerConfig::default());
+        let request = b"{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1}".to_vec();
+        let response = ServerCallback::handle_request(&mut responder, request)
+            .expect("Request got no response");
+        let response: Value = serde_json::from_slice(&response).unwrap();
+        let map = response.as_object().unwrap();
+        let mut expected = Error::internal_error();
+        expected.set_data(Value::String("Handler failed".to_string()));
+        assert_eq!(map.get("error"), Some(&expected.to_json()));
+    }
+    #[test]
+    fn encode_error() {
+        /// A codec that cannot encode successful responses
+        struct NoResults;
+        impl Codec for NoResults {
+            fn encode(&self, message: &Value) -> Result<Vec<u8>, TransportError> {
+                if message.get("result").is_some() {
+                    Err(TransportError::EncodeError("No results".to_string()))
+                } else {
+                    JSONCodec.encode(message)
+                }
+            }
+            fn decode(&self, payload: &[u8]) -> Result<Value, TransportError> {
+                JSONCodec.decode(payload)
             }
         }
+        let config = ServerConfig { codec: Arc::new(NoResults), ..ServerConfig::default() };
+        let mut responder = Responder::new(responder(false).handler, config);
+        let request = b"{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1}".to_vec();
+        let response = ServerCallback::handle_request(&mut responder, request)
+            .expect("Request got no response");
+        let response: Value = serde_json::from_slice(&response).unwrap();
+        let mut expected = Error::internal_error();
+        expected.set_data(Value::String("No results".to_string()));
+        assert_eq!(response.get("error"), Some(&expected.to_json()));
+        assert_eq!(response.get("id"), Some(&Value::from(1)));
+    }
+    #[test]
+    fn batch() {
+        let response = respond(false, "[{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\"},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":2}]")
+            .expect("Batch got no response");
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].as_object().unwrap().get("id"), Some(&Value::from(1)));
+        assert_eq!(responses[1].as_object().unwrap().get("id"), Some(&Value::from(2)));
+        // Only notifications
+        assert!(respond(false, "[{\"jsonrpc\":\"2.0\",\"method\":\"m\"}]").is_none());
+        // Empty
+        let response = respond(false, "[]").expect("Empty batch got no response");
+        assert!(response.as_object().unwrap().get("error").is_some());
+    }
+    #[test]
+    fn batch_mixed() {
+        let response = respond(false, "[{\"jsonrpc\":\"2.0\",\"method\":\"m\"},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":\"a\"},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\",\"params\":[1]},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":null},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\"}]")
+            .expect("Batch got no response");
+        let ids: Vec<Value> = response.as_array().unwrap().iter()
+            .map(|response| response.as_object().unwrap().get("id").unwrap().clone())
+            .collect();
+        // Only the requests get responses, with their own IDs
+        assert_eq!(ids, vec![Value::String("a".to_string()), Value::Null]);
+    }
+    #[test]
+    fn batch_nested() {
+        let response = respond(false, "[[{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1}],\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":2}]")
+            .expect("Batch got no response");
+        // A nested batch is an invalid message, not a second batch
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].get("error"), Some(&Error::invalid_request().to_json()));
+        assert_eq!(responses[1].get("id"), Some(&Value::from(2)));
+    }
+    #[test]
+    fn batch_too_large() {
+        let mut responder = responder(false);
+        responder.config.max_batch_size = Some(1);
+        let request = b"[{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":2}]".to_vec();
+        let response = ServerCallback::handle_request(&mut responder, request)
+            .expect("Batch got no response");
+        let response: Value = serde_json::from_slice(&response).unwrap();
+        let map = response.as_object().expect("Response is not a single object");
+        assert_eq!(map.get("error"), Some(&Error::invalid_request().to_json()));
+    }
+    #[test]
+    fn wire_logger() {
+        use std::sync::Mutex;
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let mut responder = responder(false);
+        let logger_logged = logged.clone();
+        responder.config.wire_logger = Some(Arc::new(move |direction: Direction, payload: &[u8]| {
+            logger_logged.lock().unwrap().push((direction, payload.to_vec()));
+        }));
+        let request = b"{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1}";
+        let response = ServerCallback::handle_request(&mut responder, request.to_vec()).unwrap();
+        ServerCallback::handle_request(&mut responder, b"{\"jsonrpc\":\"2.0\",\"method\":\"m\"}".to_vec());
+        assert_eq!(*logged.lock().unwrap(), vec![
+            (Direction::Received, request.to_vec()),
+            (Direction::Sent, response),
+            (Direction::Received, b"{\"jsonrpc\":\"2.0\",\"method\":\"m\"}".to_vec()),
+        ]);
+    }
+    #[test]
+    fn allowed_methods() {
+        let mut responder = responder(false);
+        responder.config.allowed_methods = Some(vec!["m".to_string()].into_iter().collect());
+        let request = b"{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1}".to_vec();
+        let response: Value = serde_json::from_slice(&ServerCallback::handle_request(&mut responder, request).unwrap()).unwrap();
+        assert_eq!(response.get("result"), Some(&Value::Bool(true)));
+        let request = b"{\"jsonrpc\":\"2.0\",\"method\":\"other\",\"id\":2}".to_vec();
+        let response: Value = serde_json::from_slice(&ServerCallback::handle_request(&mut responder, request).unwrap()).unwrap();
+        assert_eq!(response.get("id"), Some(&Value::from(2)));
+        assert_eq!(response.get("error"), Some(&Error::method_not_found().to_json()));
+        let notification = b"{\"jsonrpc\":\"2.0\",\"method\":\"other\"}".to_vec();
+        assert!(ServerCallback::handle_request(&mut responder, notification).is_none());
+    }
+    #[test]
+    fn object_id_strict() {
+        let response = respond(true, "{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":{}}")
+            .expect("Request got no response");
+        let map = response.as_object().unwrap();
+        assert_eq!(map.get("id"), Some(&Value::Null));
+        assert!(map.get("error").is_some());
+        assert!(map.get("result").is_none());
     }
 }
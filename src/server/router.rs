@@ -0,0 +1,608 @@
+//!
+//! Provides a request handler that dispatches requests to handlers by method
+//!
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+#[cfg(feature = "jsonschema")]
+use jsonschema;
+use message::{Request, Error, Value};
+use RequestHandler;
+
+///
+/// The result of a middleware function
+///
+pub enum Next {
+    /// Passes the request, which may have been modified, to the next middleware or the handler
+    Continue(Request),
+    /// Stops processing the request and responds with the provided result. For a notification,
+    /// the notification is dropped.
+    Respond(Result<Value, Error>),
+}
+
+///
+/// Trait for something that processes requests before they are dispatched
+///
+/// Middleware can be used for logging, authentication, or rewriting requests.
+///
+pub trait Middleware: 'static + Send + Sync {
+    /// Processes a request or notification
+    fn process(&self, request: Request) -> Next;
+}
+
+/// Middleware implementation for closures
+impl<F> Middleware for F where F: Fn(Request) -> Next, F: 'static + Send + Sync {
+    fn process(&self, request: Request) -> Next {
+        self(request)
+    }
+}
+
+///
+/// Trait for something that processes the results of requests before they are sent
+///
+/// Response middleware can be used to wrap errors, add data, or remove sensitive information.
+///
+pub trait ResponseMiddleware: 'static + Send + Sync {
+    ///
+    /// Processes the result of a request for the provided method
+    ///
+    /// id is the ID of the request, or null if the request was passed to the router without one.
+    ///
+    fn process(&self, method: &str, id: &Value, result: Result<Value, Error>) -> Result<Value, Error>;
+}
+
+/// ResponseMiddleware implementation for closures
+impl<F> ResponseMiddleware for F where F: Fn(&str, &Value, Result<Value, Error>) -> Result<Value, Error>, F: 'static + Send + Sync {
+    fn process(&self, method: &str, id: &Value, result: Result<Value, Error>) -> Result<Value, Error> {
+        self(method, id, result)
+    }
+}
+
+/// A function that removes sensitive information from the parameters of a request
+type Redactor = Arc<dyn Fn(&str, Value) -> Value + Send + Sync>;
+/// A function that writes a log line
+type LogOutput = Arc<dyn Fn(&str) + Send + Sync>;
+
+///
+/// Middleware that logs the method, ID, and parameters of each request and notification, and the
+/// outcome of each request
+///
+/// By default, lines are written to standard output and parameters are logged as they were
+/// received. A redaction function can replace sensitive parameters before they are logged.
+/// Add a logger to a MethodRouter with MethodRouter::add_request_logger.
+///
+#[derive(Clone)]
+pub struct RequestLogger {
+    /// The function that redacts parameters, if any
+    redactor: Option<Redactor>,
+    /// The function that writes each line
+    output: LogOutput,
+}
+
+impl RequestLogger {
+    /// Creates a logger that writes to standard output without redacting anything
+    pub fn new() -> RequestLogger {
+        RequestLogger {
+            redactor: None,
+            output: Arc::new(|line: &str| println!("{}", line)),
+        }
+    }
+
+    ///
+    /// Sets the function that removes sensitive information from parameters before they are
+    /// logged
+    ///
+    /// The function receives the method and the parameters as JSON and returns the parameters
+    /// to log. It does not change the parameters that are passed to the handler.
+    ///
+    pub fn redact<F>(mut self, redactor: F) -> Self where F: Fn(&str, Value) -> Value, F: 'static + Send + Sync {
+        self.redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    /// Sets the function that writes each line, instead of writing to standard output
+    pub fn output<F>(mut self, output: F) -> Self where F: Fn(&str), F: 'static + Send + Sync {
+        self.output = Arc::new(output);
+        self
+    }
+
+    /// Formats the parameters of a request, after redaction
+    fn format_params(&self, request: &Request) -> String {
+        match request.params {
+            Some(ref params) => {
+                let params = params.to_json();
+                match self.redactor {
+                    Some(ref redactor) => redactor(&request.method, params).to_string(),
+                    None => params.to_string(),
+                }
+            },
+            None => "none".to_string(),
+        }
+    }
+}
+
+impl Default for RequestLogger {
+    fn default() -> RequestLogger {
+        RequestLogger::new()
+    }
+}
+
+impl Middleware for RequestLogger {
+    fn process(&self, request: Request) -> Next {
+        let line = match request.id {
+            Some(ref id) => format!("RequestLogger: Request {} ID {} params {}", request.method, id,
+                self.format_params(&request)),
+            None => format!("RequestLogger: Notification {} params {}", request.method, self.format_params(&request)),
+        };
+        (self.output)(&line);
+        Next::Continue(request)
+    }
+}
+
+impl ResponseMiddleware for RequestLogger {
+    fn process(&self, method: &str, id: &Value, result: Result<Value, Error>) -> Result<Value, Error> {
+        let line = match result {
+            Ok(_) => format!("RequestLogger: Request {} ID {} succeeded", method, id),
+            Err(ref error) => format!("RequestLogger: Request {} ID {} failed with error {}: {}", method, id,
+                error.code(), error.message()),
+        };
+        (self.output)(&line);
+        result
+    }
+}
+
+/// A boxed function that handles requests for one method
+type MethodHandler = Box<dyn Fn(Request) -> Result<Value, Error> + Send + Sync>;
+/// A boxed function that handles notifications for one method
+type NotificationHandler = Box<dyn Fn(Request) + Send + Sync>;
+
+///
+/// What a MethodRouter does with a notification for a method that has not been added, when no
+/// fallback handler has been set
+///
+pub enum UnknownNotifications {
+    /// Drops the notification without logging it. This is the default.
+    Ignore,
+    /// Logs the method of the notification and drops it
+    Log,
+    /// Passes the notification to a function, which can log it at any level or handle it
+    Handle(Box<dyn Fn(Request) + Send + Sync>),
+}
+
+///
+/// A RequestHandler that calls a different function for each method
+///
+/// Requests and notifications for methods that have not been added are passed to the fallback
+/// handler, if one has been set. Otherwise, requests for unknown methods get a method not found
+/// error and notifications for unknown methods are handled as set with
+/// set_unknown_notifications, which ignores them by default.
+///
+/// Before a request or notification is dispatched, it is passed through each middleware in the
+/// order that they were added. The result of each request, including a result provided by
+/// middleware, is then passed through each response middleware in the reverse of the order that
+/// they were added. Response middleware does not run for notifications.
+///
+pub struct MethodRouter {
+    /// Request handlers by method
+    methods: HashMap<String, MethodHandler>,
+    /// Notification handlers by method
+    notifications: HashMap<String, NotificationHandler>,
+    /// The handler for methods that have not been added
+    fallback: Option<Box<dyn RequestHandler>>,
+    /// Middleware that processes requests before dispatch, in order
+    middleware: Vec<Box<dyn Middleware>>,
+    /// Middleware that processes results, in the order they were added
+    response_middleware: Vec<Box<dyn ResponseMiddleware>>,
+    /// What to do with notifications for unknown methods when there is no fallback
+    unknown_notifications: UnknownNotifications,
+}
+
+impl MethodRouter {
+    /// Creates a router with no methods
+    pub fn new() -> MethodRouter {
+        MethodRouter {
+            methods: HashMap::new(),
+            notifications: HashMap::new(),
+            fallback: None,
+            middleware: Vec::new(),
+            response_middleware: Vec::new(),
+            unknown_notifications: UnknownNotifications::Ignore,
+        }
+    }
+
+    /// Sets the function that handles requests for a method, replacing any existing function
+    pub fn add_method<F>(&mut self, method: &str, handler: F) where F: Fn(Request) -> Result<Value, Error>, F: 'static + Send + Sync {
+        self.methods.insert(method.to_string(), Box::new(handler));
+    }
+
+    ///
+    /// Sets a function that takes deserialized parameters and returns a serializable result as the
+    /// handler for a method, replacing any existing function
+    ///
+    /// The parameters are deserialized from an object for named parameters, an array for
+    /// positional parameters, or null if the request has no parameters. This matches the way that
+    /// ClientEndpoint::call serializes parameters. If the parameters cannot be deserialized, the
+    /// request gets an invalid params error with the reason in its data.
+    ///
+    pub fn add_typed_method<P, R, F>(&mut self, method: &str, handler: F)
+        where P: DeserializeOwned, R: Serialize, F: Fn(P) -> Result<R, Error>, F: 'static + Send + Sync {
+        self.add_method(method, move |request: Request| {
+            let params = request.params.map(|params| params.to_json()).unwrap_or(Value::Null);
+            let params = serde_json::from_value(params).map_err(|e| {
+                let mut error = Error::invalid_params();
+                error.set_data(Value::String(e.to_string()));
+                error
+            })?;
+            let result = handler(params)?;
+            serde_json::to_value(result).map_err(|e| {
+                let mut error = Error::internal_error();
+                error.set_data(Value::String(e.to_string()));
+                error
+            })
+        });
+    }
+
+    ///
+    /// Sets the function that handles requests for a method, replacing any existing function, and
+    /// checks the parameters of each request against a JSON Schema before calling it
+    ///
+    /// The schema is applied to an object for named parameters, an array for positional
+    /// parameters, or null if the request has no parameters. If the parameters do not match, the
+    /// request gets an invalid params error without calling the handler. The data of the error is
+    /// an array with a message for each problem, prefixed with the location of the problem.
+    ///
+    /// Returns an error with a description of the problem if the schema is not valid.
+    ///
+    #[cfg(feature = "jsonschema")]
+    pub fn add_method_with_schema<F>(&mut self, method: &str, schema: &Value, handler: F) -> Result<(), String>
+        where F: Fn(Request) -> Result<Value, Error>, F: 'static + Send + Sync {
+        let validator = jsonschema::validator_for(schema).map_err(|e| e.to_string())?;
+        self.add_method(method, move |request: Request| {
+            let params = request.params.as_ref().map(|params| params.to_json()).unwrap_or(Value::Null);
+            let problems: Vec<Value> = validator.iter_errors(&params)
+                .map(|e| Value::String(format!("{}: {}", e.instance_path, e)))
+                .collect();
+            if !problems.is_empty() {
+                let mut error = Error::invalid_params();
+                error.set_data(Value::Array(problems));
+                return Err(error);
+            }
+            handler(request)
+        });
+        Ok(())
+    }
+
+    /// Sets the function that handles notifications for a method, replacing any existing function
+    pub fn add_notification<F>(&mut self, method: &str, handler: F) where F: Fn(Request), F: 'static + Send + Sync {
+        self.notifications.insert(method.to_string(), Box::new(handler));
+    }
+
+    ///
+    /// Sets the handler for requests and notifications for methods that have not been added
+    ///
+    /// This can be used, for example, to forward unknown methods to another server.
+    ///
+    pub fn set_fallback<H>(&mut self, handler: H) where H: RequestHandler {
+        self.fallback = Some(Box::new(handler));
+    }
+
+    ///
+    /// Sets what to do with notifications for methods that have not been added
+    ///
+    /// This applies only when no fallback handler has been set. Because notifications get no
+    /// response, logging them can reveal a mismatch between the method names that a client
+    /// sends and the methods that have been added.
+    ///
+    pub fn set_unknown_notifications(&mut self, unknown: UnknownNotifications) {
+        self.unknown_notifications = unknown;
+    }
+
+    /// Adds middleware that runs after all the middleware that has already been added
+    pub fn add_middleware<M>(&mut self, middleware: M) where M: Middleware {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Adds response middleware that runs before all the response middleware that has already been added
+    pub fn add_response_middleware<M>(&mut self, middleware: M) where M: ResponseMiddleware {
+        self.response_middleware.push(Box::new(middleware));
+    }
+
+    ///
+    /// Adds a RequestLogger as both middleware and response middleware
+    ///
+    /// To log requests as they were received and results as they are sent, add the logger
+    /// before any other middleware.
+    ///
+    pub fn add_request_logger(&mut self, logger: RequestLogger) {
+        self.add_middleware(logger.clone());
+        self.add_response_middleware(logger);
+    }
+
+    /// Passes a request through the middleware
+    fn run_middleware(&self, request: Request) -> Next {
+        let mut request = request;
+        for middleware in &self.middleware {
+            match middleware.process(request) {
+                Next::Continue(next) => request = next,
+                respond => return respond,
+            }
+        }
+        Next::Continue(request)
+    }
+
+    /// Passes a request through the middleware and then to its handler
+    fn dispatch(&mut self, request: Request) -> Result<Value, Error> {
+        let request = match self.run_middleware(request) {
+            Next::Continue(request) => request,
+            Next::Respond(result) => return result,
+        };
+        if let Some(handler) = self.methods.get(&request.method) {
+            return handler(request);
+        }
+        match self.fallback {
+            Some(ref mut fallback) => fallback.handle_request(request),
+            None => Err(Error::method_not_found()),
+        }
+    }
+}
+
+///
+/// Adds methods of a service to a MethodRouter
+///
+/// The service is moved into an Arc that each method shares, so it must be Send and Sync. Each
+/// method is added with MethodRouter::add_typed_method under the name of the Rust method. A
+/// method must take &self and one parameter that can be deserialized, and return
+/// Result<R, Error> where R can be serialized. Methods with several parameters can take a tuple,
+/// which is deserialized from positional parameters. A method with one positional parameter takes
+/// a tuple with one element, like (i64,), which is deserialized from an array like [5] and matches
+/// ClientEndpoint::call with &(5,). A parameter that is not a tuple is deserialized from the whole
+/// array or object.
+///
+/// ```ignore
+/// struct Calculator;
+/// impl Calculator {
+///     fn add(&self, (a, b): (i64, i64)) -> Result<i64, Error> { Ok(a + b) }
+///     fn negate(&self, (value,): (i64,)) -> Result<i64, Error> { Ok(-value) }
+/// }
+///
+/// let mut router = MethodRouter::new();
+/// add_service_methods!(router, Calculator, [add, negate]);
+/// ```
+///
+#[macro_export]
+macro_rules! add_service_methods {
+    ($router:expr, $service:expr, [$($method:ident),* $(,)*]) => {{
+        let service = ::std::sync::Arc::new($service);
+        $(
+            {
+                let service = service.clone();
+                $router.add_typed_method(stringify!($method), move |params| service.$method(params));
+            }
+        )*
+    }};
+}
+
+impl Default for MethodRouter {
+    fn default() -> MethodRouter {
+        MethodRouter::new()
+    }
+}
+
+impl RequestHandler for MethodRouter {
+    fn handle_request(&mut self, request: Request) -> Result<Value, Error> {
+        let method = request.method.clone();
+        let id = request.id.clone().unwrap_or(Value::Null);
+        let mut result = self.dispatch(request);
+        for middleware in self.response_middleware.iter().rev() {
+            result = middleware.process(&method, &id, result);
+        }
+        result
+    }
+
+    fn handle_notification(&mut self, notification: Request) {
+        let notification = match self.run_middleware(notification) {
+            Next::Continue(notification) => notification,
+            Next::Respond(_) => return,
+        };
+        if let Some(handler) = self.notifications.get(&notification.method) {
+            return handler(notification);
+        }
+        if let Some(ref mut fallback) = self.fallback {
+            return fallback.handle_notification(notification);
+        }
+        match self.unknown_notifications {
+            UnknownNotifications::Ignore => {},
+            UnknownNotifications::Log => {
+                println!("MethodRouter: Ignoring notification for unknown method {}", notification.method);
+            },
+            UnknownNotifications::Handle(ref handler) => handler(notification),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::{Request, Error, Value, Params, error_codes};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use RequestHandler;
+
+    #[test]
+    fn service_methods() {
+        struct Calculator {
+            offset: i64,
+        }
+        impl Calculator {
+            fn add(&self, (a, b): (i64, i64)) -> Result<i64, Error> {
+                Ok(a + b + self.offset)
+            }
+            fn negate(&self, (value,): (i64,)) -> Result<i64, Error> {
+                Ok(-value)
+            }
+        }
+
+        let mut router = MethodRouter::new();
+        add_service_methods!(router, Calculator { offset: 1 }, [add, negate]);
+        let params = Params::Positional(vec![Value::from(2), Value::from(3)]);
+        assert_eq!(router.handle_request(Request::new("add", Some(params))), Ok(Value::from(6)));
+        let params = Params::Positional(vec![Value::from(5)]);
+        assert_eq!(router.handle_request(Request::new("negate", Some(params))), Ok(Value::from(-5)));
+        let error = router.handle_request(Request::new("negate", None)).unwrap_err();
+        assert_eq!(error.code(), error_codes::INVALID_PARAMS);
+        let params = Params::Positional(vec![Value::from("a")]);
+        let error = router.handle_request(Request::new("add", Some(params))).unwrap_err();
+        assert_eq!(error.code(), error_codes::INVALID_PARAMS);
+        assert!(error.to_json().get("data").is_some());
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn schema() {
+        let schema = json_schema();
+        let mut router = MethodRouter::new();
+        router.add_method_with_schema("add", &schema, |_| Ok(Value::Bool(true))).unwrap();
+        let params = Params::Positional(vec![Value::from(2), Value::from(3)]);
+        assert_eq!(router.handle_request(Request::new("add", Some(params))), Ok(Value::Bool(true)));
+
+        let params = Params::Positional(vec![Value::from(2), Value::from("a")]);
+        let error = router.handle_request(Request::new("add", Some(params))).unwrap_err();
+        assert_eq!(error.code(), error_codes::INVALID_PARAMS);
+        let problems = error.data().and_then(Value::as_array).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].as_str().unwrap().starts_with("/1: "));
+        let error = router.handle_request(Request::new("add", None)).unwrap_err();
+        assert_eq!(error.code(), error_codes::INVALID_PARAMS);
+
+        let invalid = serde_json::from_str(r#"{"type": 7}"#).unwrap();
+        assert!(router.add_method_with_schema("invalid", &invalid, |_| Ok(Value::Null)).is_err());
+        assert_eq!(router.handle_request(Request::new("invalid", None)), Err(Error::method_not_found()));
+    }
+
+    /// Returns a schema that accepts an array of two integers
+    #[cfg(feature = "jsonschema")]
+    fn json_schema() -> Value {
+        serde_json::from_str(r#"{
+            "type": "array",
+            "items": { "type": "integer" },
+            "minItems": 2,
+            "maxItems": 2
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn request_logger() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let output_lines = lines.clone();
+        let logger = RequestLogger::new()
+            .redact(|method: &str, mut params: Value| {
+                if method == "login" {
+                    params["password"] = Value::from("<redacted>");
+                }
+                params
+            })
+            .output(move |line: &str| output_lines.lock().unwrap().push(line.to_string()));
+        let mut router = MethodRouter::new();
+        router.add_request_logger(logger);
+        router.add_method("login", |request: Request| {
+            // The handler still receives the real password
+            let params = request.params.unwrap().to_json();
+            Ok(Value::Bool(params["password"] == "secret"))
+        });
+
+        let mut params = BTreeMap::new();
+        params.insert("user".to_string(), Value::from("sam"));
+        params.insert("password".to_string(), Value::from("secret"));
+        let mut request = Request::new("login", Some(Params::Named(params)));
+        request.id = Some(Value::from(7));
+        assert_eq!(router.handle_request(request), Ok(Value::Bool(true)));
+        let mut request = Request::new("logout", None);
+        request.id = Some(Value::from(8));
+        assert_eq!(router.handle_request(request), Err(Error::method_not_found()));
+        router.handle_notification(Request::new("ping", Some(Params::Positional(vec![Value::from(1)]))));
+
+        assert_eq!(*lines.lock().unwrap(), vec![
+            r#"RequestLogger: Request login ID 7 params {"password":"<redacted>","user":"sam"}"#.to_string(),
+            "RequestLogger: Request login ID 7 succeeded".to_string(),
+            "RequestLogger: Request logout ID 8 params none".to_string(),
+            "RequestLogger: Request logout ID 8 failed with error -32601: Method not found".to_string(),
+            "RequestLogger: Notification ping params [1]".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn fallback() {
+        let mut router = MethodRouter::new();
+        router.add_method("known", |_| Ok(Value::Bool(true)));
+        assert_eq!(router.handle_request(Request::new("known", None)), Ok(Value::Bool(true)));
+        assert_eq!(router.handle_request(Request::new("unknown", None)), Err(Error::method_not_found()));
+
+        router.set_fallback((|request: Request| Ok(Value::String(request.method)), |_: Request| {}));
+        assert_eq!(router.handle_request(Request::new("known", None)), Ok(Value::Bool(true)));
+        assert_eq!(router.handle_request(Request::new("unknown", None)), Ok(Value::String("unknown".to_string())));
+    }
+
+    #[test]
+    fn unknown_notifications() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MethodRouter::new();
+        router.add_notification("known", |_| {});
+        router.set_unknown_notifications(UnknownNotifications::Log);
+        router.handle_notification(Request::new("unknown", None));
+
+        let handler_received = received.clone();
+        router.set_unknown_notifications(UnknownNotifications::Handle(Box::new(move |notification: Request| {
+            handler_received.lock().unwrap().push(notification.method);
+        })));
+        router.handle_notification(Request::new("known", None));
+        router.handle_notification(Request::new("unknown", None));
+        assert_eq!(*received.lock().unwrap(), vec!["unknown".to_string()]);
+
+        // The fallback handler takes precedence
+        router.set_fallback((|_: Request| Ok(Value::Null), |_: Request| {}));
+        router.handle_notification(Request::new("other", None));
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn middleware() {
+        let mut router = MethodRouter::new();
+        router.add_method("new", |_| Ok(Value::Bool(true)));
+        // Rewrites old to new
+        router.add_middleware(|mut request: Request| {
+            if request.method == "old" {
+                request.method = "new".to_string();
+            }
+            Next::Continue(request)
+        });
+        // Rejects forbidden
+        router.add_middleware(|request: Request| {
+            if request.method == "forbidden" {
+                Next::Respond(Err(Error::invalid_request()))
+            } else {
+                Next::Continue(request)
+            }
+        });
+        assert_eq!(router.handle_request(Request::new("old", None)), Ok(Value::Bool(true)));
+        assert_eq!(router.handle_request(Request::new("forbidden", None)), Err(Error::invalid_request()));
+    }
+
+    #[test]
+    fn response_middleware() {
+        let mut router = MethodRouter::new();
+        router.add_method("m", |_| Ok(Value::String("result".to_string())));
+        router.add_response_middleware(|_: &str, id: &Value, result: Result<Value, Error>| {
+            result.map(|value| Value::String(format!("{} first {}", value.as_str().unwrap(), id)))
+        });
+        router.add_response_middleware(|method: &str, _: &Value, result: Result<Value, Error>| {
+            result.map(|value| Value::String(format!("{} {}", value.as_str().unwrap(), method)))
+        });
+        // The last middleware added runs first
+        let mut request = Request::new("m", None);
+        request.id = Some(Value::from(3));
+        assert_eq!(router.handle_request(request), Ok(Value::String("result m first 3".to_string())));
+    }
+}
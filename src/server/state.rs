@@ -0,0 +1,122 @@
+//!
+//! Provides a request handler that gives its functions access to a shared state
+//!
+
+use std::sync::{Arc, Mutex, PoisonError};
+use message::{Request, Error, Value};
+use RequestHandler;
+
+///
+/// A RequestHandler that passes a shared state to its request and notification functions
+///
+/// The state is kept in an Arc<Mutex<S>> and locked while each function runs, so both functions
+/// can modify it. The state can also be shared with other code, such as other handlers or a
+/// background thread, by creating the handler with from_shared.
+///
+/// If a function panics while the state is locked, the mutex is poisoned. When the server catches
+/// panics (see ServerConfig::catch_panics), later requests still get the state, which the panicking
+/// function may have left partly modified.
+///
+pub struct StatefulHandler<S, F, G> {
+    /// The state
+    state: Arc<Mutex<S>>,
+    /// The function that handles requests
+    request_fn: F,
+    /// The function that handles notifications
+    notification_fn: G,
+}
+
+impl<S, F, G> StatefulHandler<S, F, G> where S: 'static + Send,
+    F: Fn(&mut S, Request) -> Result<Value, Error>, F: 'static + Send + Sync,
+    G: Fn(&mut S, Request), G: 'static + Send + Sync {
+
+    /// Creates a handler that owns the provided state
+    pub fn new(state: S, request_fn: F, notification_fn: G) -> StatefulHandler<S, F, G> {
+        StatefulHandler::from_shared(Arc::new(Mutex::new(state)), request_fn, notification_fn)
+    }
+
+    /// Creates a handler that uses a state that may also be used elsewhere
+    pub fn from_shared(state: Arc<Mutex<S>>, request_fn: F, notification_fn: G) -> StatefulHandler<S, F, G> {
+        StatefulHandler {
+            state,
+            request_fn,
+            notification_fn,
+        }
+    }
+
+    /// Returns a reference to the state
+    pub fn state(&self) -> Arc<Mutex<S>> {
+        self.state.clone()
+    }
+}
+
+impl<S, F, G> RequestHandler for StatefulHandler<S, F, G> where S: 'static + Send,
+    F: Fn(&mut S, Request) -> Result<Value, Error>, F: 'static + Send + Sync,
+    G: Fn(&mut S, Request), G: 'static + Send + Sync {
+
+    fn handle_request(&mut self, request: Request) -> Result<Value, Error> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        (self.request_fn)(&mut state, request)
+    }
+    fn handle_notification(&mut self, notification: Request) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        (self.notification_fn)(&mut state, notification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::Params;
+    use server::ServerConfig;
+    use server::stream::serve_connection_with_config;
+
+    fn add(total: &mut i64, request: Request) -> Result<Value, Error> {
+        match request.params {
+            Some(Params::Positional(ref values)) if values.len() == 1 => {
+                *total += values[0].as_i64().ok_or_else(Error::invalid_params)?;
+                Ok(Value::from(*total))
+            },
+            _ => Err(Error::invalid_params()),
+        }
+    }
+
+    #[test]
+    fn shared_state() {
+        let state = Arc::new(Mutex::new(0i64));
+        let mut handler = StatefulHandler::from_shared(state.clone(), add, |total: &mut i64, _| *total = 0);
+
+        let request = Request::new("add", Some(Params::Positional(vec![Value::from(3)])));
+        assert_eq!(handler.handle_request(request.clone()).unwrap(), Value::from(3));
+        assert_eq!(handler.handle_request(request).unwrap(), Value::from(6));
+        assert_eq!(*state.lock().unwrap(), 6);
+
+        handler.handle_notification(Request::new("reset", None));
+        assert_eq!(*handler.state().lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn panic_while_locked() {
+        fn add_or_panic(total: &mut i64, request: Request) -> Result<Value, Error> {
+            if request.method == "panic" {
+                *total = -1;
+                panic!("Handler failed");
+            }
+            add(total, request)
+        }
+        let handler = StatefulHandler::new(0i64, add_or_panic, |_: &mut i64, _| {});
+        let state = handler.state();
+        let input = "{\"jsonrpc\":\"2.0\",\"method\":\"panic\",\"id\":1}\n\
+            {\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[3],\"id\":2}\n";
+        let mut output = Vec::new();
+        let config = ServerConfig { catch_panics: true, ..ServerConfig::default() };
+        serve_connection_with_config(input.as_bytes(), &mut output, handler, config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let responses: Vec<&str> = output.lines().collect();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].contains("Handler failed"));
+        // The request after the panic still gets the state
+        assert_eq!(responses[1], "{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":2}");
+        assert!(state.is_poisoned());
+    }
+}
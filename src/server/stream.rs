@@ -1,132 +1,305 @@
 //!
 //! Provides a basic ServerTransport implementation
 //!
+//! Each payload is followed by a delimiter, which is a newline (\n) by default. A payload must
+//! not contain the delimiter, so messages are encoded as compact JSON, which escapes line breaks
+//! inside strings. With the default delimiter, lines that are read may end with either \n or
+//! \r\n. Other delimiters, like NUL (0) or the ASCII record separator (0x1e), can be used with
+//! ServerStreamTransport::with_delimiter.
+//!
 
 use std::io;
-use std::io::{Read, Write, Lines, BufWriter, BufRead, BufReader};
+use std::io::{Read, Write, BufWriter};
 use transport::{ServerTransport, ServerCallback, TransportError};
+use transport::delimited::{Payloads, DEFAULT_DELIMITER};
+use super::{Responder, ServerConfig};
+use RequestHandler;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::boxed::Box;
-use std::thread::{Builder, JoinHandle};
+use std::mem;
+use spawn::{Spawn, ThreadSpawner};
+
+/// Counter used to give each transport's reader thread a unique name
+static NEXT_TRANSPORT_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+/// A function that is called when a reader thread terminates
+type TerminationHandler = Box<dyn FnOnce() + Send>;
+
+/// Whether a reader thread is running, shared between the transport and the thread
+enum Termination {
+    /// The thread is running. Contains the handler to call when it terminates, if one has been set.
+    Running(Option<TerminationHandler>),
+    /// The thread has terminated
+    Terminated,
+}
+
+/// Calls the termination handler when it is dropped at the end of the reader thread
+struct TerminationGuard {
+    /// The state shared with the transport
+    termination: Arc<Mutex<Termination>>,
+    /// Disconnected when this is dropped, which lets ServerStreamTransport::run return
+    _done: Sender<()>,
+}
 
-    /// The character that separates payloads in a stream
-    const SEPARATOR: u8 = '\n' as u8;
+impl Drop for TerminationGuard {
+    fn drop(&mut self) {
+        // This may run while the thread is panicking, so do not panic if the mutex is poisoned
+        let state = match self.termination.lock() {
+            Ok(mut termination) => mem::replace(&mut *termination, Termination::Terminated),
+            Err(_) => return,
+        };
+        if let Termination::Running(Some(handler)) = state {
+            handler();
+        }
+    }
+}
 
 ///
 /// A server transport that uses a Read stream and a Write stream
 ///
+/// Requests are handled one at a time in the order they are read, so responses are always
+/// written in the same order as the requests they answer. Clients that send several requests
+/// without waiting for responses can rely on this ordering.
+///
 pub struct ServerStreamTransport {
     /// A channel used to communicate with the reader thread.
     /// A callback can be sent to set the callback to use.
     /// When the channel is closed, the reader thread may terminate.
-    channel: Sender<Box<ServerCallback>>,
-    /// The handle used to wait for the reader thread to terminate
-    handle: JoinHandle<()>,
+    channel: Sender<Box<dyn ServerCallback>>,
+    /// A channel that is disconnected when the reader thread terminates
+    done: Receiver<()>,
+    /// Whether the reader thread is running, and its termination handler
+    termination: Arc<Mutex<Termination>>,
+    /// The delimiter that separates payloads
+    delimiter: u8,
 }
 
 impl ServerStreamTransport {
-    pub fn new<R, W>(input: R, output: W) -> Result<ServerStreamTransport, io::Error> where R: 'static + Read + Send, W: 'static + Write + Send {
+    ///
+    /// Creates a transport that reads requests from input and writes responses to output
+    ///
+    /// Returns an error if the reader thread could not be started.
+    ///
+    pub fn new<R, W>(input: R, output: W) -> Result<ServerStreamTransport, TransportError> where R: 'static + Read + Send, W: 'static + Write + Send {
+        ServerStreamTransport::with_delimiter(input, output, DEFAULT_DELIMITER)
+    }
+
+    ///
+    /// Creates a transport that reads requests from input and writes responses to output, with
+    /// payloads separated by the provided delimiter instead of a newline
+    ///
+    /// JSON payloads may contain any printable byte, so with the JSON codecs the delimiter must be
+    /// a control character like NUL (0). ServerEndpoint returns an error if the codec's payloads
+    /// may contain the delimiter.
+    ///
+    pub fn with_delimiter<R, W>(input: R, output: W, delimiter: u8) -> Result<ServerStreamTransport, TransportError> where R: 'static + Read + Send, W: 'static + Write + Send {
+        ServerStreamTransport::with_spawner(input, output, delimiter, &ThreadSpawner)
+    }
+
+    ///
+    /// Creates a transport that reads requests from input and writes responses to output, with
+    /// payloads separated by the provided delimiter, and uses the provided Spawn to start the
+    /// reader
+    ///
+    pub fn with_spawner<R, W>(input: R, output: W, delimiter: u8, spawner: &dyn Spawn) -> Result<ServerStreamTransport, TransportError>
+        where R: 'static + Read + Send, W: 'static + Write + Send {
         let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel();
+        let termination = Arc::new(Mutex::new(Termination::Running(None)));
+        let guard = TerminationGuard {
+            termination: termination.clone(),
+            _done: done_tx,
+        };
 
-        let mut reader = Reader::new(input, output, rx);
-        let handle = try!(Builder::new().name("ServerStreamTransport reader".to_string()).spawn(move || {
+        let mut reader = Reader::new(input, output, rx, delimiter);
+        let number = NEXT_TRANSPORT_NUMBER.fetch_add(1, Ordering::Relaxed);
+        let name = format!("ServerStreamTransport {} reader", number);
+        spawner.spawn(name, Box::new(move || {
+            // The guard is dropped when this returns or panics
+            let _guard = guard;
             reader.run();
-        }));
+        })).map_err(TransportError::IOError)?;
 
         Ok(ServerStreamTransport {
             channel: tx,
-            handle: handle,
+            done: done_rx,
+            termination,
+            delimiter,
         })
     }
+
+    ///
+    /// Sets a function to call when the reader thread terminates
+    ///
+    /// The reader thread terminates when it reaches the end of its input or fails to read or
+    /// write, for example because the client disconnected. This can be used to clean up state
+    /// associated with the connection. The function is called on the reader thread. If the
+    /// thread has already terminated, the function is called immediately.
+    ///
+    /// This replaces any function that was set earlier.
+    ///
+    pub fn set_termination_handler<F>(&mut self, handler: F) where F: FnOnce(), F: 'static + Send {
+        let mut termination = self.termination.lock().expect("Termination mutex poisoned");
+        match *termination {
+            Termination::Running(ref mut current) => *current = Some(Box::new(handler)),
+            Termination::Terminated => {
+                drop(termination);
+                handler();
+            },
+        }
+    }
 }
 
 impl ServerTransport for ServerStreamTransport {
     fn set_callback<C>(&mut self, callback: C) where C: ServerCallback {
         // Send it to the thread
-        match self.channel.send(Box::new(callback)) {
-            Err(_) => println!("ServerStreamTransport: Reader thread has terminated"),
-            Ok(()) => {},
+        if self.channel.send(Box::new(callback)).is_err() {
+            println!("ServerStreamTransport: Reader thread has terminated");
         }
     }
+    fn delimiter(&self) -> Option<u8> {
+        Some(self.delimiter)
+    }
     fn run(self) {
-        let _ = self.handle.join();
+        // This returns an error when the reader thread drops the sender
+        let _ = self.done.recv();
+    }
+}
+
+///
+/// Handles requests read from input and writes responses to output on the current thread
+///
+/// Unlike ServerStreamTransport, this does not start any threads. It returns when the end of
+/// the input is reached, or returns an error if reading or writing fails.
+///
+pub fn serve_connection<R, W, H>(input: R, output: W, handler: H) -> Result<(), TransportError>
+    where R: Read, W: Write, H: RequestHandler {
+    serve_connection_with_config(input, output, handler, ServerConfig::default())
+}
+
+///
+/// Handles requests read from input and writes responses to output on the current thread,
+/// using the provided configuration
+///
+/// Payloads are separated by newlines, so this returns an error without reading anything if the
+/// configured codec's payloads may contain a newline.
+///
+pub fn serve_connection_with_config<R, W, H>(input: R, output: W, handler: H, config: ServerConfig) -> Result<(), TransportError>
+    where R: Read, W: Write, H: RequestHandler {
+    if config.codec.may_contain(DEFAULT_DELIMITER) {
+        return Err(TransportError::IOError(io::Error::new(io::ErrorKind::InvalidInput,
+            "Payloads encoded with the configured codec may contain newlines")));
+    }
+    let mut responder = Responder::new(handler, config);
+    let mut output = BufWriter::new(output);
+    for line in Payloads::new(input, DEFAULT_DELIMITER) {
+        let line = line?;
+        if let Some(response) = ServerCallback::handle_request(&mut responder, line) {
+            write_payload(&mut output, &response, DEFAULT_DELIMITER)?;
+        }
     }
+    Ok(())
+}
+
+/// Writes a payload and a delimiter, and flushes the output
+fn write_payload<W>(output: &mut W, payload: &[u8], delimiter: u8) -> Result<(), io::Error> where W: Write {
+    output.write_all(payload)?;
+    output.write_all(&[delimiter])?;
+    output.flush()?;
+    Ok(())
 }
 
 struct Reader<R, W> where R: 'static + Read + Send, W: 'static + Write + Send {
-    /// The iterator used to read lines from the input
-    lines: Lines<BufReader<R>>,
+    /// The iterator used to read payloads from the input
+    lines: Payloads<R>,
+    /// The delimiter written after each response
+    delimiter: u8,
     /// The writer used to send output
     writer: BufWriter<W>,
     /// The channel used to receive callbacks from the transport object
-    channel: Receiver<Box<ServerCallback>>,
+    channel: Receiver<Box<dyn ServerCallback>>,
     /// The callback used to handle requests
-    callback: Option<Box<ServerCallback>>,
+    callback: Option<Box<dyn ServerCallback>>,
 }
 
 impl<R, W> Reader<R, W> where R: 'static + Read + Send, W: 'static + Write + Send {
-    pub fn new(input: R, output: W, channel: Receiver<Box<ServerCallback>>) -> Reader<R, W> {
+    pub fn new(input: R, output: W, channel: Receiver<Box<dyn ServerCallback>>, delimiter: u8) -> Reader<R, W> {
         Reader {
-            lines: BufReader::new(input).lines(),
+            lines: Payloads::new(input, delimiter),
+            delimiter,
             writer: BufWriter::new(output),
-            channel: channel,
+            channel,
             callback: None,
         }
     }
 
-    pub fn handle_read_line(&mut self, line: String) -> Option<String> {
+    pub fn handle_read_line(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
         match self.callback {
             Some(ref mut callback) => callback.handle_request(line),
             None => None,
         }
     }
 
-    pub fn send_response(&mut self, response: &str) -> Result<(), io::Error> {
-        try!(self.writer.write_all(response.as_bytes()));
-        try!(self.writer.write_all(&[SEPARATOR]));
-        try!(self.writer.flush());
-        Ok(())
+    pub fn send_response(&mut self, response: &[u8]) -> Result<(), io::Error> {
+        write_payload(&mut self.writer, response, self.delimiter)
     }
 
     /// Thread entry point
     pub fn run(&mut self) {
         loop {
-            // Check for a new callback or close request
-            match self.channel.try_recv() {
-                Ok(new_callback) => self.callback = Some(new_callback),
-                Err(TryRecvError::Disconnected) => return,
-                Err(TryRecvError::Empty) => {},
-            };
-            // Read a line and get a Result<String, TransportError>
+            // Read a line and get a Result<Vec<u8>, TransportError>
             let line_result = match self.lines.next() {
                 Some(Ok(line)) => Ok(line),
                 Some(Err(ioe)) => Err(TransportError::from(ioe)),
                 None => Err(TransportError::EndOfFile),
             };
-            match line_result {
+            // Check for a new callback or close request. This is done after reading so that
+            // a callback that was set while waiting for input is used to handle that input.
+            match self.channel.try_recv() {
+                Ok(new_callback) => self.callback = Some(new_callback),
+                Err(TryRecvError::Disconnected) => return,
+                Err(TryRecvError::Empty) => {},
+            };
+            let keep_running = match line_result {
                 Ok(line) => {
                     let response = self.handle_read_line(line);
-                    if let Some(response) = response {
-                        // Write response
-                        match self.send_response(&response) {
-                            Ok(()) => {},
-                            Err(e) => self.handle_transport_error(TransportError::from(e)),
-                        }
+                    match response.map(|response| self.send_response(&response)) {
+                        Some(Err(e)) => self.handle_transport_error(TransportError::from(e)),
+                        _ => true,
                     }
                 },
                 Err(e) => self.handle_transport_error(e),
             };
+            if !keep_running {
+                return;
+            }
         }
     }
 
-    fn handle_transport_error(&mut self, e: TransportError) {
+    /// Handles an error from reading or writing, and returns true if the reader should keep running
+    fn handle_transport_error(&mut self, e: TransportError) -> bool {
         match e {
             // Handle some errors by ignoring this line and proceeding
             TransportError::TimedOut
-            | TransportError::Interrupted
-            | TransportError::ParseError => {},
-            // Handle EOF and other IO errors by terminating
-            _ => panic!(format!("IO error: {:?}", e)),
-        };
+            | TransportError::Interrupted => true,
+            // A payload that could not be read cannot be answered, because its ID is unknown
+            TransportError::ParseError(message) => {
+                println!("ServerStreamTransport: Skipping invalid payload: {}", message);
+                true
+            },
+            // The end of the input was reached, or the client disconnected (possibly while a
+            // response was being written)
+            TransportError::EndOfFile => {
+                println!("ServerStreamTransport: Connection closed");
+                false
+            },
+            // Stop reading after other errors, because the stream may not be usable
+            _ => {
+                println!("ServerStreamTransport: Stopping after error: {:?}", e);
+                false
+            },
+        }
     }
 }
@@ -0,0 +1,518 @@
+//!
+//! Provides a ServerTransport implementation that accepts TCP connections
+//!
+
+use std::io;
+use std::io::{Read, Write, BufWriter};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr, Shutdown, Ipv4Addr, Ipv6Addr};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spawn::{Spawn, ThreadSpawner};
+use chrono::Duration;
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+use transport::{ServerTransport, ServerCallback, CallbackFactory, TransportError};
+use transport::delimited::{Payloads, DEFAULT_DELIMITER};
+
+/// Counter used to give each connection thread a unique name
+static NEXT_CONNECTION_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Options that control how the listening socket and connections are set up
+///
+#[derive(Debug, Clone)]
+pub struct BindOptions {
+    /// If true, SO_REUSEADDR is set so that a restarted server can bind to an address that
+    /// still has connections in the TIME_WAIT state
+    pub reuse_address: bool,
+    /// If true, SO_REUSEPORT is set. This has no effect on platforms other than Unix.
+    pub reuse_port: bool,
+    /// The maximum length of the queue of pending connections
+    pub backlog: i32,
+    ///
+    /// If this is not None, a connection is closed when a complete payload has not been received
+    /// for this long. This frees the resources used by abandoned connections and by clients that
+    /// send data very slowly. The default is None.
+    ///
+    pub idle_timeout: Option<Duration>,
+    ///
+    /// If this is not None, the maximum number of connections that can be open at the same time.
+    /// When this many connections are open, new connections are closed immediately after they
+    /// are accepted. The default is None.
+    ///
+    pub max_connections: Option<usize>,
+    ///
+    /// The byte that separates payloads on each connection. The default is a newline, as with
+    /// ServerStreamTransport. ServerEndpoint returns an error if the codec's payloads may contain
+    /// this byte, so with the JSON codecs it must be a control character.
+    ///
+    pub delimiter: u8,
+}
+
+impl Default for BindOptions {
+    fn default() -> BindOptions {
+        BindOptions {
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 128,
+            idle_timeout: None,
+            max_connections: None,
+            delimiter: DEFAULT_DELIMITER,
+        }
+    }
+}
+
+///
+/// A server transport that listens for TCP connections
+///
+/// Each connection is handled on its own thread. Payloads are separated by newlines by default,
+/// as with ServerStreamTransport, or by the delimiter in BindOptions.
+///
+/// Requests on one connection are handled one at a time, so responses on each connection are
+/// written in the same order as the requests they answer. Requests on different connections may
+/// be handled in any order.
+///
+/// A callback set with set_callback is shared by all connections, so only one request is handled
+/// at a time. With set_callback_factory (which ServerEndpoint::with_handler_factory uses), each
+/// connection gets its own callback and requests on different connections are handled at the
+/// same time.
+///
+pub struct TCPServerTransport {
+    /// The socket that accepts connections
+    listener: TcpListener,
+    /// The source of the callbacks used to handle requests
+    callbacks: Option<Callbacks>,
+    /// The idle timeout for connections
+    idle_timeout: Option<StdDuration>,
+    /// The byte that separates payloads
+    delimiter: u8,
+    /// The maximum number of open connections
+    max_connections: Option<usize>,
+    /// The number of open connections
+    open_connections: Arc<AtomicUsize>,
+    /// Whether the transport is draining, and the open connections
+    drain: Arc<DrainState>,
+    /// The address that the listener is bound to
+    address: SocketAddr,
+    /// Starts the connection threads
+    spawner: Box<dyn Spawn>,
+}
+
+impl TCPServerTransport {
+    /// Creates a transport that listens on the provided address with the default options
+    pub fn bind<A>(address: A) -> Result<TCPServerTransport, TransportError> where A: ToSocketAddrs {
+        TCPServerTransport::bind_with_options(address, &BindOptions::default())
+    }
+
+    ///
+    /// Creates a transport that listens on the provided address with the provided options
+    ///
+    /// If the address resolves to more than one socket address, each one is tried in order until
+    /// one succeeds.
+    ///
+    pub fn bind_with_options<A>(address: A, options: &BindOptions) -> Result<TCPServerTransport, TransportError> where A: ToSocketAddrs {
+        idle_timeout_to_std(options)?;
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "Address did not resolve to any socket addresses");
+        for address in address.to_socket_addrs()? {
+            match listen(&address, options) {
+                Ok(listener) => return TCPServerTransport::from_listener(listener, options),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(TransportError::from(last_error))
+    }
+
+    ///
+    /// Creates a transport that accepts connections from a socket that is already listening
+    ///
+    /// This can be used with a socket that was inherited from a parent process, for example with
+    /// systemd socket activation. On Unix, a socket that was inherited as a file descriptor can
+    /// be converted with TcpListener::from_raw_fd.
+    ///
+    /// The socket options in options (reuse_address, reuse_port, and backlog) are not applied
+    /// because the socket has already been set up. The other options are used.
+    ///
+    pub fn from_listener(listener: TcpListener, options: &BindOptions) -> Result<TCPServerTransport, TransportError> {
+        let idle_timeout = idle_timeout_to_std(options)?;
+        let address = listener.local_addr()?;
+        Ok(TCPServerTransport {
+            listener,
+            callbacks: None,
+            idle_timeout,
+            delimiter: options.delimiter,
+            max_connections: options.max_connections,
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            drain: Arc::new(DrainState::default()),
+            address,
+            spawner: Box::new(ThreadSpawner),
+        })
+    }
+
+    /// Sets the Spawn used to start a thread for each connection. The default is ThreadSpawner.
+    pub fn set_spawner<S>(&mut self, spawner: S) where S: Spawn {
+        self.spawner = Box::new(spawner);
+    }
+
+    /// Returns the address that this transport is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr, TransportError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    ///
+    /// Returns a handle that can be used to shut down this transport after it has started running
+    ///
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            drain: self.drain.clone(),
+            open_connections: self.open_connections.clone(),
+            address: self.address,
+        }
+    }
+}
+
+///
+/// The state shared between a transport, its connections, and its shutdown handles
+///
+#[derive(Default)]
+struct DrainState {
+    /// True after the transport has started draining
+    draining: AtomicBool,
+    /// The open connections, by connection number. These are used to stop reading from the
+    /// connections when draining starts.
+    connections: Mutex<HashMap<usize, TcpStream>>,
+}
+
+impl DrainState {
+    /// Returns true if the transport has started draining
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Shuts down part or all of every open connection
+    fn shutdown_connections(&self, how: Shutdown) {
+        let connections = self.connections.lock().expect("Connection mutex poisoned");
+        for stream in connections.values() {
+            let _ = stream.shutdown(how);
+        }
+    }
+}
+
+///
+/// Shuts down a TCPServerTransport
+///
+/// Created by TCPServerTransport::shutdown_handle. A handle can be cloned and used from any thread.
+///
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    /// The state shared with the transport
+    drain: Arc<DrainState>,
+    /// The number of open connections
+    open_connections: Arc<AtomicUsize>,
+    /// The address that the transport is listening on
+    address: SocketAddr,
+}
+
+impl ShutdownHandle {
+    ///
+    /// Stops accepting connections and requests, and waits for requests that are being handled
+    /// to finish
+    ///
+    /// The transport stops accepting connections, so its run function returns. Each open
+    /// connection finishes handling its current request, if any, and writes the response. It then
+    /// closes without reading any more requests. A request that has only been partly received is
+    /// discarded.
+    ///
+    /// Returns true if all connections closed within the timeout. Otherwise, the remaining
+    /// connections are closed without waiting for their responses and this returns false.
+    ///
+    pub fn drain(&self, timeout: &Duration) -> bool {
+        println!("TCPServerTransport: Draining");
+        self.drain.draining.store(true, Ordering::SeqCst);
+        // Wake up the thread that is waiting for a connection so that it can stop
+        let _ = TcpStream::connect(wake_address(self.address));
+        self.drain.shutdown_connections(Shutdown::Read);
+
+        let end = Instant::now() + timeout.to_std().unwrap_or_else(|_| StdDuration::from_millis(0));
+        while self.open_connections.load(Ordering::SeqCst) != 0 {
+            if Instant::now() >= end {
+                println!("TCPServerTransport: Drain timeout expired; closing remaining connections");
+                self.drain.shutdown_connections(Shutdown::Both);
+                return false;
+            }
+            thread::sleep(StdDuration::from_millis(10));
+        }
+        true
+    }
+}
+
+/// Returns an address that can be used to connect to a listener bound to the provided address
+fn wake_address(address: SocketAddr) -> SocketAddr {
+    let mut address = address;
+    if address.ip().is_unspecified() {
+        match address {
+            SocketAddr::V4(_) => address.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => address.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    address
+}
+
+/// Creates a listening socket with the provided options
+fn listen(address: &SocketAddr, options: &BindOptions) -> Result<TcpListener, io::Error> {
+    let builder = match *address {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(options.reuse_address)?;
+    if options.reuse_port {
+        set_reuse_port(&builder)?;
+    }
+    builder.bind(address)?;
+    builder.listen(options.backlog)
+}
+
+/// Converts the idle timeout of options into a standard duration
+fn idle_timeout_to_std(options: &BindOptions) -> Result<Option<StdDuration>, io::Error> {
+    match options.idle_timeout {
+        Some(timeout) => timeout.to_std().map(Some).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Idle timeout is negative")
+        }),
+        None => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+fn set_reuse_port(builder: &TcpBuilder) -> Result<(), io::Error> {
+    builder.reuse_port(true).map(|_| ())
+}
+#[cfg(not(unix))]
+fn set_reuse_port(_builder: &TcpBuilder) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Where connections get the callbacks that handle their requests
+enum Callbacks {
+    /// One callback shared by all connections
+    Shared(Arc<Mutex<Box<dyn ServerCallback>>>),
+    /// A function that creates a callback for each connection
+    Factory(CallbackFactory),
+}
+
+impl Callbacks {
+    /// Returns the callback for a new connection
+    fn for_connection(&self) -> ConnectionCallback {
+        match *self {
+            Callbacks::Shared(ref callback) => ConnectionCallback::Shared(callback.clone()),
+            Callbacks::Factory(ref factory) => ConnectionCallback::Own(factory()),
+        }
+    }
+}
+
+/// The callback that handles requests on one connection
+enum ConnectionCallback {
+    /// A callback shared with other connections, which is locked while it handles each request
+    Shared(Arc<Mutex<Box<dyn ServerCallback>>>),
+    /// A callback that only this connection uses
+    Own(Box<dyn ServerCallback>),
+}
+
+impl ConnectionCallback {
+    /// Handles a payload and returns the response, if any
+    fn handle_request(&mut self, request: Vec<u8>) -> Option<Vec<u8>> {
+        match *self {
+            ConnectionCallback::Shared(ref callback) => {
+                callback.lock().expect("Callback mutex poisoned").handle_request(request)
+            },
+            ConnectionCallback::Own(ref mut callback) => callback.handle_request(request),
+        }
+    }
+}
+
+impl ServerTransport for TCPServerTransport {
+    fn set_callback<C>(&mut self, callback: C) where C: ServerCallback {
+        self.callbacks = Some(Callbacks::Shared(Arc::new(Mutex::new(Box::new(callback)))));
+    }
+
+    /// Creates a callback for each connection when it is accepted
+    fn set_callback_factory(&mut self, factory: CallbackFactory) {
+        self.callbacks = Some(Callbacks::Factory(factory));
+    }
+
+    fn delimiter(&self) -> Option<u8> {
+        Some(self.delimiter)
+    }
+
+    ///
+    /// Accepts connections and starts a thread to handle each one
+    ///
+    /// This function returns when ShutdownHandle::drain is called, or if no callback has been set.
+    ///
+    fn run(self) {
+        let callbacks = match self.callbacks {
+            Some(ref callbacks) => callbacks,
+            None => {
+                println!("TCPServerTransport: No callback set");
+                return;
+            },
+        };
+        for stream in self.listener.incoming() {
+            if self.drain.is_draining() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let open = ConnectionCount::increment(self.open_connections.clone());
+                    if matches!(self.max_connections, Some(max) if open.count > max) {
+                        // Dropping the stream and the count closes the connection
+                        println!("TCPServerTransport: Too many connections; closing new connection");
+                        continue;
+                    }
+                    if let Err(e) = Connection::start(stream, callbacks.for_connection(), self.idle_timeout, self.delimiter, open, self.drain.clone(), &*self.spawner) {
+                        println!("TCPServerTransport: Failed to start connection: {:?}", e);
+                    }
+                },
+                Err(e) => println!("TCPServerTransport: Failed to accept connection: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Handles requests received on one connection
+struct Connection {
+    /// The connection
+    stream: TcpStream,
+    /// The callback used to handle requests
+    callback: ConnectionCallback,
+    /// The idle timeout
+    idle_timeout: Option<StdDuration>,
+    /// The byte that separates payloads
+    delimiter: u8,
+    /// The number of this connection, used to remove it from the open connections
+    number: usize,
+    /// The state shared with the transport
+    drain: Arc<DrainState>,
+    /// Counts this connection as open until it is dropped
+    _open: ConnectionCount,
+}
+
+impl Connection {
+    /// Starts a thread to handle requests on a connection
+    fn start(stream: TcpStream, callback: ConnectionCallback, idle_timeout: Option<StdDuration>, delimiter: u8,
+        open: ConnectionCount, drain: Arc<DrainState>, spawner: &dyn Spawn) -> Result<(), TransportError> {
+        let number = NEXT_CONNECTION_NUMBER.fetch_add(1, Ordering::Relaxed);
+        // Register the connection so that draining can stop reading from it. A connection that
+        // is registered after draining starts stops before reading anything.
+        drain.connections.lock().expect("Connection mutex poisoned").insert(number, stream.try_clone()?);
+        let connection = Connection {
+            stream,
+            callback,
+            idle_timeout,
+            delimiter,
+            number,
+            drain,
+            _open: open,
+        };
+        let name = format!("TCPServerTransport connection {}", number);
+        spawner.spawn(name, Box::new(move || {
+            if let Err(e) = connection.run() {
+                println!("TCPServerTransport: Connection closed: {:?}", e);
+            }
+        })).map_err(TransportError::IOError)?;
+        Ok(())
+    }
+
+    /// Handles requests until the connection is closed or the idle timeout expires
+    fn run(mut self) -> Result<(), TransportError> {
+        let mut input = Payloads::new(IdleReader {
+            stream: self.stream.try_clone()?,
+            timeout: self.idle_timeout,
+            deadline: None,
+        }, self.delimiter);
+        let mut output = BufWriter::new(self.stream.try_clone()?);
+        while !self.drain.is_draining() {
+            input.get_mut().reset_deadline();
+            let payload = match input.next().map(|payload| payload.map_err(TransportError::from)) {
+                Some(Ok(payload)) => payload,
+                Some(Err(e)) => return Err(e),
+                None => break,
+            };
+            if let Some(response) = self.callback.handle_request(payload) {
+                output.write_all(&response)?;
+                output.write_all(&[self.delimiter])?;
+                output.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.drain.connections.lock() {
+            connections.remove(&self.number);
+        }
+    }
+}
+
+///
+/// Counts a connection as open until it is dropped
+///
+struct ConnectionCount {
+    /// The number of open connections, shared with the transport
+    open: Arc<AtomicUsize>,
+    /// The number of open connections, including this one, when this one was opened
+    count: usize,
+}
+
+impl ConnectionCount {
+    /// Increments the number of open connections
+    fn increment(open: Arc<AtomicUsize>) -> ConnectionCount {
+        let count = open.fetch_add(1, Ordering::SeqCst) + 1;
+        ConnectionCount { open, count }
+    }
+}
+
+impl Drop for ConnectionCount {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+///
+/// Reads from a connection, failing with a timed out error if the idle timeout expires
+///
+/// The deadline covers all the reads needed to receive one payload, so a client cannot keep
+/// a connection open by sending a payload one byte at a time.
+///
+struct IdleReader {
+    /// The connection
+    stream: TcpStream,
+    /// The idle timeout, or None if connections never time out
+    timeout: Option<StdDuration>,
+    /// The time when the current read times out
+    deadline: Option<Instant>,
+}
+
+impl IdleReader {
+    /// Restarts the idle timeout. This is called before reading each payload.
+    fn reset_deadline(&mut self) {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+}
+
+impl Read for IdleReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Connection idle timeout expired"));
+            }
+            self.stream.set_read_timeout(Some(deadline - now))?;
+        }
+        self.stream.read(buf)
+    }
+}
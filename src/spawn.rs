@@ -0,0 +1,45 @@
+//!
+//! Provides an abstraction over the way that transports and endpoints start threads
+//!
+
+use std::io;
+use std::thread;
+
+///
+/// Trait for something that starts tasks that run in the background
+///
+/// Transports and endpoints use a Spawn to start their reader, writer, and request threads.
+/// ThreadSpawner, which starts a new thread for each task, is used by default. Applications that
+/// manage their own threads can provide a different implementation.
+///
+/// A task may block for a long time, for example while reading from a stream, so it should not
+/// be run on a thread that other tasks need in order to make progress.
+///
+pub trait Spawn: 'static + Send + Sync {
+    ///
+    /// Starts running a task
+    ///
+    /// name describes the task and may be used as a thread name. Returns an error if the task
+    /// could not be started.
+    ///
+    fn spawn(&self, name: String, task: Box<dyn FnOnce() + Send>) -> Result<(), io::Error>;
+}
+
+/// Spawn implementation for closures
+impl<F> Spawn for F where F: Fn(String, Box<dyn FnOnce() + Send>) -> Result<(), io::Error>, F: 'static + Send + Sync {
+    fn spawn(&self, name: String, task: Box<dyn FnOnce() + Send>) -> Result<(), io::Error> {
+        self(name, task)
+    }
+}
+
+///
+/// A Spawn that runs each task on a new thread with the name of the task
+///
+#[derive(Debug, Clone, Default)]
+pub struct ThreadSpawner;
+
+impl Spawn for ThreadSpawner {
+    fn spawn(&self, name: String, task: Box<dyn FnOnce() + Send>) -> Result<(), io::Error> {
+        thread::Builder::new().name(name).spawn(task).map(|_| ())
+    }
+}
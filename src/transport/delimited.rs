@@ -0,0 +1,71 @@
+//!
+//! Provides a reader that splits a stream into payloads separated by a delimiter byte
+//!
+
+use std::io;
+use std::io::{Read, BufRead, BufReader};
+
+/// The delimiter that the stream transports use by default
+pub const DEFAULT_DELIMITER: u8 = b'\n';
+
+///
+/// An iterator over the payloads in a stream
+///
+/// Each payload ends with the delimiter, which is removed. The last payload in the stream does
+/// not need to end with the delimiter. If the delimiter is a newline, a carriage return before it
+/// is also removed, so lines that end with \r\n are accepted.
+///
+pub struct Payloads<R> {
+    /// The input
+    input: BufReader<R>,
+    /// The byte that ends each payload
+    delimiter: u8,
+}
+
+impl<R> Payloads<R> where R: Read {
+    /// Creates an iterator over the payloads in input that are separated by delimiter
+    pub fn new(input: R, delimiter: u8) -> Payloads<R> {
+        Payloads {
+            input: BufReader::new(input),
+            delimiter,
+        }
+    }
+
+    /// Returns a mutable reference to the input
+    pub fn get_mut(&mut self) -> &mut R {
+        self.input.get_mut()
+    }
+}
+
+impl<R> Iterator for Payloads<R> where R: Read {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut payload = Vec::new();
+        match self.input.read_until(self.delimiter, &mut payload) {
+            Ok(0) => return None,
+            Ok(_) => {},
+            Err(e) => return Some(Err(e)),
+        }
+        if payload.last() == Some(&self.delimiter) {
+            payload.pop();
+            if self.delimiter == b'\n' && payload.last() == Some(&b'\r') {
+                payload.pop();
+            }
+        }
+        Some(Ok(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let payloads: Vec<Vec<u8>> = Payloads::new(&b"a\0b\r\0c"[..], 0).map(Result::unwrap).collect();
+        assert_eq!(payloads, vec![&b"a"[..], b"b\r", b"c"]);
+        let payloads: Vec<Vec<u8>> = Payloads::new(&b"a\r\nb\n"[..], b'\n').map(Result::unwrap).collect();
+        assert_eq!(payloads, vec![&b"a"[..], b"b"]);
+    }
+}
@@ -3,8 +3,11 @@
 //!
 //!
 
+pub mod delimited;
+
 use std::io;
 use std::convert::From;
+use serde_json;
 
 ///
 /// Errors that a transport layer can report
@@ -18,14 +21,20 @@ pub enum TransportError {
     TimedOut,
     /// The thread was interrupted while waiting
     Interrupted,
-    /// Data received could not be parsed
-    ParseError,
-    /// Data to be sent could not be encoded
-    EncodeError,
+    /// Data received could not be parsed. Contains a description of the problem.
+    ParseError(String),
+    /// Data to be sent could not be encoded. Contains a description of the problem.
+    EncodeError(String),
     /// Something could not be found
     NotFound,
     /// The transport layer does not have a required callback set
     MissingCallback,
+    /// The client has reached its limit on the number of requests waiting for responses
+    TooManyPendingRequests,
+    /// A request has the same ID as a request that is waiting for a response
+    DuplicateId,
+    /// The transport has replaced its connection, so requests sent earlier will not get responses
+    Reconnected,
     /// A different error
     IOError(io::Error),
     /// A different error that is not an io::Error,
@@ -52,11 +61,27 @@ impl From<io::Error> for TransportError {
 
             io::ErrorKind::Interrupted => TransportError::Interrupted,
 
+            io::ErrorKind::InvalidData => TransportError::ParseError(io_err.to_string()),
+
             _ => TransportError::IOError(io_err),
         }
     }
 }
 
+/// Creates a TransportError::ParseError from a serde_json::Error that occurred while decoding
+impl From<serde_json::Error> for TransportError {
+    fn from(json_err: serde_json::Error) -> Self {
+        TransportError::ParseError(json_err.to_string())
+    }
+}
+
+impl TransportError {
+    /// Creates a TransportError from a serde_json::Error that occurred while encoding
+    pub fn from_encode_error(json_err: serde_json::Error) -> Self {
+        TransportError::EncodeError(json_err.to_string())
+    }
+}
+
 ///
 /// Trait for something to be notified when a payload from the server is received
 ///
@@ -64,15 +89,48 @@ pub trait PayloadHandler: 'static + Send + Sync {
     ///
     /// Called when a response from the server is received
     ///
-    fn payload_received(&mut self, result: Result<String, TransportError>);
+    fn payload_received(&mut self, result: Result<Vec<u8>, TransportError>);
 }
 /// PayloadHandler implementation for closures
-impl<F> PayloadHandler for F where F: Fn(Result<String, TransportError>), F: 'static + Send + Sync {
-    fn payload_received(&mut self, result: Result<String, TransportError>) {
+impl<F> PayloadHandler for F where F: Fn(Result<Vec<u8>, TransportError>), F: 'static + Send + Sync {
+    fn payload_received(&mut self, result: Result<Vec<u8>, TransportError>) {
         self(result)
     }
 }
 
+///
+/// The direction of a payload passed to a WireLogger
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The payload is being sent
+    Sent,
+    /// The payload was received
+    Received,
+}
+
+///
+/// Trait for something that is given every payload that an endpoint sends or receives
+///
+/// This is intended for debugging protocol problems. Payloads are passed exactly as they are
+/// given to or received from the transport, without framing like delimiters or HTTP headers.
+/// The logger may be called from several threads. Payloads are bytes, because a codec may use a
+/// binary format; String::from_utf8_lossy can be used to show text payloads.
+///
+pub trait WireLogger: 'static + Send + Sync {
+    ///
+    /// Called with a payload that is about to be sent or has just been received
+    ///
+    fn log_payload(&self, direction: Direction, payload: &[u8]);
+}
+
+/// WireLogger implementation for closures
+impl<F> WireLogger for F where F: Fn(Direction, &[u8]), F: 'static + Send + Sync {
+    fn log_payload(&self, direction: Direction, payload: &[u8]) {
+        self(direction, payload)
+    }
+}
+
 ///
 /// Trait for a transport layer used by a client
 ///
@@ -93,7 +151,46 @@ pub trait ClientTransport : 'static + Send {
     ///
     /// This function must not block.
     ///
-    fn send(&mut self, payload: &str) -> Result<(), TransportError>;
+    fn send(&mut self, payload: &[u8]) -> Result<(), TransportError>;
+
+    ///
+    /// Sends several payloads
+    ///
+    /// Transports that buffer their output can override this to write all the payloads before
+    /// flushing once. The default implementation calls send for each payload and stops at the
+    /// first error.
+    ///
+    fn send_all(&mut self, payloads: &[Vec<u8>]) -> Result<(), TransportError> {
+        for payload in payloads {
+            self.send(payload)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Closes the sending side of the connection while continuing to receive payloads
+    ///
+    /// The server sees an end of file after the payloads that have already been sent, and can
+    /// still send responses to them. After this is called, sending fails with
+    /// TransportError::EndOfFile.
+    ///
+    /// The default implementation returns an IOError with the kind Unsupported.
+    ///
+    fn close_send(&mut self) -> Result<(), TransportError> {
+        Err(TransportError::IOError(io::Error::new(io::ErrorKind::Unsupported,
+            "This transport cannot close its sending side")))
+    }
+
+    ///
+    /// Returns the byte that this transport writes after each payload, if it separates payloads
+    /// with a delimiter
+    ///
+    /// ClientEndpointBuilder uses this to reject a codec whose payloads may contain the
+    /// delimiter. The default implementation returns None.
+    ///
+    fn delimiter(&self) -> Option<u8> {
+        None
+    }
 }
 
 ///
@@ -101,20 +198,38 @@ pub trait ClientTransport : 'static + Send {
 ///
 /// This trait includes Send and Sync so that transport layers can use multiple threads.
 ///
+/// Each received payload produces at most one response payload. For a batch, the callback
+/// handles every message in the batch and assembles the responses into one array, so a
+/// transport never needs to know whether a payload is a batch. Transports send the returned
+/// response, if any, as a single frame.
+///
 pub trait ServerCallback: 'static + Send + Sync {
     ///
-    /// Handles a received request. Returns an optional response to send back to the client.
+    /// Handles a received payload. Returns an optional response to send back to the client.
     ///
-    fn handle_request(&mut self, request: String) -> Option<String>;
+    /// None means that nothing should be sent, for example because the payload was a notification
+    /// or a batch of only notifications.
+    ///
+    fn handle_request(&mut self, request: Vec<u8>) -> Option<Vec<u8>>;
 }
 
 /// ServerCallback implementation for closures
-impl<F> ServerCallback for F where F: Fn(String) -> Option<String>, F: 'static + Send + Sync {
-    fn handle_request(&mut self, request: String) -> Option<String> {
+impl<F> ServerCallback for F where F: Fn(Vec<u8>) -> Option<Vec<u8>>, F: 'static + Send + Sync {
+    fn handle_request(&mut self, request: Vec<u8>) -> Option<Vec<u8>> {
         self(request)
     }
 }
 
+/// ServerCallback implementation for boxed callbacks
+impl ServerCallback for Box<dyn ServerCallback> {
+    fn handle_request(&mut self, request: Vec<u8>) -> Option<Vec<u8>> {
+        (**self).handle_request(request)
+    }
+}
+
+/// A function that creates callbacks, for transports that handle each connection separately
+pub type CallbackFactory = Box<dyn Fn() -> Box<dyn ServerCallback> + Send + Sync>;
+
 ///
 /// Trait for a transport layer used by a server
 ///
@@ -124,6 +239,27 @@ pub trait ServerTransport : 'static + Send {
     ///
     fn set_callback<C>(&mut self, callback: C) where C: ServerCallback;
     ///
+    /// Sets a function that creates the callbacks that this transport layer will use to respond
+    /// to requests
+    ///
+    /// A transport that handles several connections can create a callback for each connection,
+    /// so that requests on different connections are handled at the same time. The default
+    /// implementation creates one callback and passes it to set_callback.
+    ///
+    fn set_callback_factory(&mut self, factory: CallbackFactory) where Self: Sized {
+        self.set_callback(factory());
+    }
+    ///
+    /// Returns the byte that this transport writes after each response, if it separates payloads
+    /// with a delimiter
+    ///
+    /// ServerEndpoint uses this to reject a codec whose payloads may contain the delimiter. The
+    /// default implementation returns None.
+    ///
+    fn delimiter(&self) -> Option<u8> {
+        None
+    }
+    ///
     /// Runs the transport mechanism and returns when an end of file is reached
     ///
     fn run(self);
@@ -0,0 +1,549 @@
+//!
+//! Tests that send requests from a client endpoint to a mock server
+//!
+
+extern crate jsonrpc2;
+extern crate chrono;
+#[cfg(feature = "futures")]
+extern crate futures;
+
+use jsonrpc2::client::{ClientEndpoint, CallError, PayloadError, ResponseHandler};
+use jsonrpc2::client::mock::{MockServer, MockResponse};
+use jsonrpc2::client::failover::FailoverClientTransport;
+use jsonrpc2::client::id::SequentialIds;
+use jsonrpc2::client::retry::RetryPolicy;
+use jsonrpc2::codec::{Codec, JSONCodec};
+use jsonrpc2::transport::{TransportError, Direction};
+use jsonrpc2::message::{error_codes, Request, Response, Notification, Params, Value};
+use chrono::Duration;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+#[test]
+fn canned_result() {
+    let server = MockServer::new();
+    server.respond_to_method("add", MockResponse::Result(Value::from(3)));
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+
+    let sum: u64 = client.call("add", &(1, 2), &Duration::seconds(1)).unwrap();
+    assert_eq!(sum, 3);
+
+    let mut expected = Request::new("add", Some(Params::Positional(vec![Value::from(1), Value::from(2)])));
+    expected.set_id(Value::from(0));
+    assert_eq!(server.received(), vec![expected]);
+}
+
+#[test]
+fn unknown_method() {
+    let server = MockServer::new();
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+
+    match client.call::<_, Value>("missing", &(), &Duration::seconds(1)) {
+        Err(CallError::Rpc(ref error)) => assert_eq!(error.code(), error_codes::METHOD_NOT_FOUND),
+        other => panic!("Expected method not found error, got {:?}", other),
+    }
+}
+
+#[test]
+fn no_response() {
+    let server = MockServer::new();
+    server.respond_to_method("hang", MockResponse::NoResponse);
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+
+    match client.call::<_, Value>("hang", &(), &Duration::milliseconds(50)) {
+        Err(CallError::Transport(_)) => {},
+        other => panic!("Expected transport error, got {:?}", other),
+    }
+}
+
+#[test]
+fn batch() {
+    let server = MockServer::new();
+    server.respond_to_method("fast", MockResponse::Result(Value::from(1)));
+    server.respond_to_method("slow", MockResponse::Delayed(Duration::milliseconds(20),
+        Box::new(MockResponse::Result(Value::from(2)))));
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+    let (tx, rx) = channel();
+    let mut requests: Vec<(Request, Box<dyn ResponseHandler>)> = Vec::new();
+    for method in &["fast", "slow", "missing"] {
+        let tx = Mutex::new(tx.clone());
+        requests.push((Request::new(method, None), Box::new(move |response: Response| {
+            tx.lock().unwrap().send(response).unwrap();
+        })));
+    }
+    let ids = client.send_batch(requests).unwrap();
+    let mut responses: Vec<Response> = (0..3).map(|_| rx.recv_timeout(StdDuration::from_secs(1)).unwrap()).collect();
+    responses.sort_by_key(|response| response.id.as_ref().and_then(Value::as_u64));
+    let response_ids: Vec<u64> = responses.iter().map(|response| response.id.as_ref().unwrap().as_u64().unwrap()).collect();
+    assert_eq!(response_ids, ids);
+    assert_eq!(responses[0].payload, Ok(Value::from(1)));
+    assert_eq!(responses[1].payload, Ok(Value::from(2)));
+    assert_eq!(responses[2].error().map(|error| error.code()), Some(error_codes::METHOD_NOT_FOUND));
+    assert_eq!(server.received().len(), 3);
+
+    // The writer is still running after the batch
+    let result: i64 = client.call("fast", &(), &Duration::seconds(1)).unwrap();
+    assert_eq!(result, 1);
+}
+
+#[test]
+fn send_failure() {
+    let server = MockServer::new();
+    server.respond_to_method("lost", MockResponse::SendFailure);
+    server.respond_to_method("after", MockResponse::Result(Value::Bool(true)));
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+    match client.call::<_, Value>("lost", &(), &Duration::milliseconds(200)) {
+        Err(CallError::Transport(TransportError::TimedOut)) => {},
+        other => panic!("Expected timeout, got {:?}", other),
+    }
+    // The writer has stopped, so the endpoint is disconnected
+    assert!(!client.is_connected());
+    match client.call::<_, Value>("after", &(), &Duration::seconds(1)) {
+        Err(CallError::Transport(TransportError::EndOfFile)) => {},
+        other => panic!("Expected end of file, got {:?}", other),
+    }
+    assert_eq!(server.received().len(), 1);
+}
+
+#[test]
+fn cancel() {
+    let server = MockServer::new();
+    server.respond_to_method("hang", MockResponse::NoResponse);
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+    client.set_cancel_method(Some("$/cancelRequest"));
+
+    let (tx, rx) = channel();
+    let id = client.send_request(Request::new("hang", None), move |response: Response| {
+        tx.send(response).unwrap();
+    }).unwrap();
+    assert!(client.cancel(id).unwrap());
+    assert!(!client.cancel(id).unwrap());
+
+    let response = rx.recv().unwrap();
+    assert_eq!(response.payload.unwrap_err().code(), error_codes::REQUEST_CANCELLED);
+    // The notification is sent on the client's writer thread
+    let mut received = server.received();
+    for _ in 0..1000 {
+        if received.len() == 2 {
+            break;
+        }
+        thread::sleep(StdDuration::from_millis(1));
+        received = server.received();
+    }
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[1].method, "$/cancelRequest");
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn response_future() {
+    let server = MockServer::new();
+    server.respond_to_method("answer", MockResponse::Result(Value::from(42)));
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+
+    let response = futures::executor::block_on(client.send_request_future(Request::new("answer", None))).unwrap();
+    assert_eq!(response.payload, Ok(Value::from(42)));
+}
+
+#[test]
+fn send_raw() {
+    let server = MockServer::new();
+    server.respond_to_method("raw", MockResponse::Result(Value::Bool(true)));
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+
+    let (tx, rx) = channel();
+    let id = client.send_raw(b"{\"jsonrpc\":\"2.0\",\"method\":\"raw\",\"id\":1000}", move |response: Response| {
+        tx.send(response).unwrap();
+    }).unwrap();
+    assert_eq!(id, 1000);
+    let response = rx.recv_timeout(StdDuration::from_secs(1)).unwrap();
+    assert_eq!(response.payload, Ok(Value::Bool(true)));
+
+    // No ID
+    assert!(client.send_raw(b"{\"jsonrpc\":\"2.0\",\"method\":\"raw\"}", |_: Response| {}).is_err());
+}
+
+#[test]
+fn failover() {
+    let first = MockServer::new();
+    first.respond_to_method("m", MockResponse::SendFailure);
+    let second = MockServer::new();
+    second.respond_to_method("m", MockResponse::Delayed(Duration::milliseconds(50), Box::new(MockResponse::Result(Value::Bool(true)))));
+    let transport = FailoverClientTransport::new(vec![first.transport(), second.transport()]);
+    let client = ClientEndpoint::new(transport).unwrap();
+
+    for _ in 0..2 {
+        let result: bool = client.call("m", &(), &Duration::seconds(1)).unwrap();
+        assert!(result);
+    }
+    // The first server is not used after it fails
+    assert_eq!(first.received().len(), 1);
+    assert_eq!(second.received().len(), 2);
+}
+
+#[test]
+fn rate_limit() {
+    let server = MockServer::new();
+    let client = ClientEndpoint::with_rate_limit(server.transport(), 20.0, 2).unwrap();
+    let start = Instant::now();
+    for _ in 0..4 {
+        client.send_notification(Notification::new("m", None)).unwrap();
+    }
+    // Two notifications are sent immediately, and the others wait 50 milliseconds each
+    assert!(start.elapsed() >= StdDuration::from_millis(100));
+}
+
+#[test]
+fn builder() {
+    let server = MockServer::new();
+    server.respond_to_method("wait", MockResponse::NoResponse);
+    let client = ClientEndpoint::builder(server.transport())
+        .id_generator(SequentialIds::starting_at(100))
+        .max_pending_requests(1)
+        .build()
+        .unwrap();
+    assert_eq!(client.send_request(Request::new("wait", None), |_: Response| {}).unwrap(), 100);
+    match client.send_request(Request::new("wait", None), |_: Response| {}) {
+        Err(TransportError::TooManyPendingRequests) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn duplicate_id() {
+    let server = MockServer::new();
+    server.respond_to_method("wait", MockResponse::NoResponse);
+    let client = ClientEndpoint::builder(server.transport())
+        .id_generator(|| 7)
+        .build()
+        .unwrap();
+    client.send_request(Request::new("wait", None), |_: Response| {}).unwrap();
+    match client.send_request(Request::new("wait", None), |_: Response| {}) {
+        Err(TransportError::DuplicateId) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    assert_eq!(client.pending_requests(), 1);
+}
+
+#[test]
+fn method_timeout() {
+    let server = MockServer::new();
+    server.respond_to_method("slow", MockResponse::NoResponse);
+    let client = ClientEndpoint::builder(server.transport())
+        .default_timeout(Duration::seconds(10))
+        .method_timeout("slow", Duration::milliseconds(100))
+        .build()
+        .unwrap();
+    assert_eq!(client.timeout_for("fast"), Duration::seconds(10));
+    let start = Instant::now();
+    match client.call_with_default_timeout::<_, Value>("slow", &()) {
+        Err(CallError::Transport(TransportError::TimedOut)) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    assert!(start.elapsed() < StdDuration::from_secs(5));
+}
+
+#[test]
+fn send_request_with_id() {
+    let server = MockServer::new();
+    server.respond_to_method("m", MockResponse::Result(Value::Bool(true)));
+    server.respond_to_method("wait", MockResponse::NoResponse);
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+
+    let (tx, rx) = channel();
+    client.send_request_with_id(Request::new("m", None), Value::from("proxy-1"), move |response: Response| {
+        tx.send(response).unwrap();
+    }).unwrap();
+    let response = rx.recv_timeout(StdDuration::from_secs(1)).unwrap();
+    assert_eq!(response.id, Some(Value::from("proxy-1")));
+    assert_eq!(response.payload, Ok(Value::Bool(true)));
+
+    client.send_request_with_id(Request::new("wait", None), Value::from("proxy-2"), |_: Response| {}).unwrap();
+    match client.send_request_with_id(Request::new("wait", None), Value::from("proxy-2"), |_: Response| {}) {
+        Err(TransportError::DuplicateId) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match client.send_request_with_id(Request::new("wait", None), Value::Null, |_: Response| {}) {
+        Err(TransportError::EncodeError(_)) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn null_id_error() {
+    let parse_error = "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32700,\"message\":\"Parse error\"}}";
+    let server = MockServer::new();
+    server.respond_to_method("garbled", MockResponse::Delayed(Duration::milliseconds(50),
+        Box::new(MockResponse::Raw(parse_error.to_string()))));
+    server.respond_to_method("wait", MockResponse::NoResponse);
+    let (error_tx, error_rx) = channel();
+    let error_tx = Mutex::new(error_tx);
+    let client = ClientEndpoint::builder(server.transport())
+        .invalid_payload_handler(move |_: &[u8], error: PayloadError| {
+            error_tx.lock().unwrap().send(error).unwrap();
+        })
+        .build()
+        .unwrap();
+
+    // With one pending request, the error is the response to that request
+    match client.call::<_, Value>("garbled", &(), &Duration::seconds(1)) {
+        Err(CallError::Rpc(ref error)) => assert_eq!(error.code(), error_codes::PARSE_ERROR),
+        other => panic!("Expected parse error, got {:?}", other),
+    }
+
+    // With more than one pending request, the error cannot be matched
+    client.send_request(Request::new("wait", None), |_: Response| {}).unwrap();
+    client.send_request(Request::new("garbled", None), |_: Response| {}).unwrap();
+    let error = error_rx.recv_timeout(StdDuration::from_secs(1)).unwrap();
+    assert_eq!(error, PayloadError::AmbiguousNullId);
+    assert_eq!(client.pending_requests(), 2);
+}
+
+#[test]
+fn wait_idle() {
+    let server = MockServer::new();
+    server.respond_to_method("slow", MockResponse::Delayed(Duration::milliseconds(100), Box::new(MockResponse::Result(Value::from(1)))));
+    server.respond_to_method("hang", MockResponse::NoResponse);
+    let client = Arc::new(ClientEndpoint::new(server.transport()).unwrap());
+    client.wait_idle(&Duration::seconds(1)).unwrap();
+
+    client.send_request(Request::new("slow", None), |_: Response| {}).unwrap();
+    client.send_request(Request::new("slow", None), |_: Response| {}).unwrap();
+    match client.wait_idle(&Duration::milliseconds(10)) {
+        Err(TransportError::TimedOut) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    client.wait_idle(&Duration::seconds(5)).unwrap();
+    assert_eq!(client.pending_requests(), 0);
+
+    // Cancelling the last pending request also wakes a waiting thread
+    let id = client.send_request(Request::new("hang", None), |_: Response| {}).unwrap();
+    let waiter = {
+        let client = client.clone();
+        thread::spawn(move || client.wait_idle(&Duration::seconds(5)))
+    };
+    thread::sleep(StdDuration::from_millis(50));
+    let start = Instant::now();
+    assert!(client.cancel(id).unwrap());
+    waiter.join().unwrap().unwrap();
+    assert!(start.elapsed() < StdDuration::from_secs(1));
+}
+
+#[test]
+fn float_id() {
+    // Some serializers, like JavaScript's, write integral IDs as floating-point numbers
+    let response = "{\"jsonrpc\":\"2.0\",\"id\":1.0,\"result\":true}";
+    let server = MockServer::new();
+    server.respond_to_method("float", MockResponse::Raw(response.to_string()));
+    let client = ClientEndpoint::builder(server.transport())
+        .id_generator(SequentialIds::starting_at(1))
+        .build()
+        .unwrap();
+    let result: bool = client.call("float", &(), &Duration::seconds(1)).unwrap();
+    assert!(result);
+    assert_eq!(client.pending_requests(), 0);
+}
+
+#[test]
+fn ping() {
+    let server = MockServer::new();
+    server.respond_to_method("health", MockResponse::Result(Value::from("ok")));
+    server.respond_to_method("hang", MockResponse::NoResponse);
+
+    // The default method is not implemented, but the error response still shows that the server is responding
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+    client.ping(&Duration::seconds(1)).unwrap();
+    assert_eq!(server.received()[0].method, "rpc.ping");
+
+    client.set_ping_method("hang");
+    match client.ping(&Duration::milliseconds(50)) {
+        Err(TransportError::TimedOut) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    let client = ClientEndpoint::builder(server.transport()).ping_method("health").build().unwrap();
+    client.ping(&Duration::seconds(1)).unwrap();
+}
+
+#[test]
+fn wire_logger() {
+    let server = MockServer::new();
+    server.respond_to_method("m", MockResponse::Result(Value::from(1)));
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let client = ClientEndpoint::builder(server.transport())
+        .wire_logger(move |direction: Direction, payload: &[u8]| {
+            tx.lock().unwrap().send((direction, payload.to_vec())).unwrap();
+        })
+        .build()
+        .unwrap();
+    let _: Value = client.call("m", &(), &Duration::seconds(1)).unwrap();
+
+    let sent = rx.recv_timeout(StdDuration::from_secs(1)).unwrap();
+    assert_eq!(sent, (Direction::Sent, b"{\"id\":0,\"jsonrpc\":\"2.0\",\"method\":\"m\"}".to_vec()));
+    let received = rx.recv_timeout(StdDuration::from_secs(1)).unwrap();
+    assert_eq!(received, (Direction::Received, b"{\"id\":0,\"jsonrpc\":\"2.0\",\"result\":1}".to_vec()));
+}
+
+#[test]
+fn reassign_handler() {
+    let server = MockServer::new();
+    server.respond_to_method("m", MockResponse::Delayed(Duration::milliseconds(50), Box::new(MockResponse::Result(Value::from(1)))));
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+
+    let (old_tx, old_rx) = channel();
+    let id = client.send_request(Request::new("m", None), move |response: Response| {
+        old_tx.send(response).unwrap();
+    }).unwrap();
+    let (new_tx, new_rx) = channel();
+    assert!(client.reassign_handler(id, move |response: Response| {
+        new_tx.send(response).unwrap();
+    }));
+
+    let response = new_rx.recv_timeout(StdDuration::from_secs(1)).unwrap();
+    assert_eq!(response.payload, Ok(Value::from(1)));
+    // The old handler was dropped without being called
+    assert!(old_rx.recv().is_err());
+    assert!(!client.reassign_handler(id, |_: Response| {}));
+}
+
+#[test]
+fn concatenated_messages() {
+    let response = "{\"jsonrpc\":\"2.0\",\"id\":0,\"result\":1}";
+    let notification = "{\"jsonrpc\":\"2.0\",\"method\":\"progress\"}";
+    let server = MockServer::new();
+    server.respond_to_method("m", MockResponse::Raw(format!("{}{} {{\"id", response, notification)));
+    let (notification_tx, notification_rx) = channel();
+    let notification_tx = Mutex::new(notification_tx);
+    let (error_tx, error_rx) = channel();
+    let error_tx = Mutex::new(error_tx);
+    let client = ClientEndpoint::builder(server.transport())
+        .notification_handler(move |notification: Request| {
+            notification_tx.lock().unwrap().send(notification.method).unwrap();
+        })
+        .invalid_payload_handler(move |_: &[u8], error: PayloadError| {
+            error_tx.lock().unwrap().send(error).unwrap();
+        })
+        .build()
+        .unwrap();
+
+    // Each message in the payload is handled, and the invalid part at the end is reported
+    let result: u64 = client.call("m", &(), &Duration::seconds(1)).unwrap();
+    assert_eq!(result, 1);
+    assert_eq!(notification_rx.recv_timeout(StdDuration::from_secs(1)).unwrap(), "progress");
+    match error_rx.recv_timeout(StdDuration::from_secs(1)).unwrap() {
+        PayloadError::InvalidJson(_) => {},
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
+
+fn retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::milliseconds(10),
+        max_backoff: Duration::milliseconds(20),
+    }
+}
+
+#[test]
+fn idempotent_retries() {
+    let server = MockServer::new();
+    server.respond_to_id(Value::from(0), MockResponse::NoResponse);
+    server.respond_to_id(Value::from(1), MockResponse::NoResponse);
+    server.respond_to_id(Value::from(2), MockResponse::Result(Value::from(5)));
+    let client = ClientEndpoint::builder(server.transport()).retry_policy(retry_policy()).build().unwrap();
+
+    let request = Request::new("add", Some(Params::Positional(vec![Value::from(2), Value::from(3)])));
+    let response = client.send_idempotent_request_sync(request, &Duration::milliseconds(50)).unwrap();
+    assert_eq!(response.ok_value(), Some(&Value::from(5)));
+    // Each attempt is sent with a new ID
+    let ids: Vec<Value> = server.received().iter().map(|request| request.id.clone().unwrap()).collect();
+    assert_eq!(ids, vec![Value::from(0), Value::from(1), Value::from(2)]);
+}
+
+#[test]
+fn idempotent_attempts_limited() {
+    let server = MockServer::new();
+    server.respond_to_method("hang", MockResponse::NoResponse);
+    let client = ClientEndpoint::builder(server.transport()).retry_policy(retry_policy()).build().unwrap();
+
+    match client.send_idempotent_request_sync(Request::new("hang", None), &Duration::milliseconds(50)) {
+        Err(TransportError::TimedOut) => {},
+        other => panic!("Expected timeout, got {:?}", other),
+    }
+    assert_eq!(server.received().len(), 3);
+
+    client.set_retry_policy(RetryPolicy { max_attempts: 1, ..retry_policy() });
+    match client.send_idempotent_request_sync(Request::new("hang", None), &Duration::milliseconds(50)) {
+        Err(TransportError::TimedOut) => {},
+        other => panic!("Expected timeout, got {:?}", other),
+    }
+    assert_eq!(server.received().len(), 4);
+}
+
+#[test]
+fn idempotent_error_response_not_retried() {
+    let server = MockServer::new();
+    let client = ClientEndpoint::builder(server.transport()).retry_policy(retry_policy()).build().unwrap();
+
+    let response = client.send_idempotent_request_sync(Request::new("missing", None), &Duration::seconds(1)).unwrap();
+    assert_eq!(response.error().map(|error| error.code()), Some(error_codes::METHOD_NOT_FOUND));
+    assert_eq!(server.received().len(), 1);
+}
+
+/// A codec that fails to encode messages and counts the attempts
+struct FailingCodec(Arc<AtomicUsize>);
+
+impl Codec for FailingCodec {
+    fn encode(&self, _message: &Value) -> Result<Vec<u8>, TransportError> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Err(TransportError::EncodeError("Cannot encode".to_string()))
+    }
+    fn decode(&self, payload: &[u8]) -> Result<Value, TransportError> {
+        JSONCodec.decode(payload)
+    }
+}
+
+#[test]
+fn idempotent_encode_error_not_retried() {
+    let server = MockServer::new();
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let client = ClientEndpoint::builder(server.transport())
+        .codec(FailingCodec(attempts.clone()))
+        .retry_policy(retry_policy())
+        .build()
+        .unwrap();
+
+    match client.send_idempotent_request_sync(Request::new("add", None), &Duration::seconds(1)) {
+        Err(TransportError::EncodeError(_)) => {},
+        other => panic!("Expected encode error, got {:?}", other),
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    assert!(server.received().is_empty());
+}
+
+#[test]
+fn send_timeouts() {
+    let server = MockServer::new();
+    server.respond_to_method("add", MockResponse::Result(Value::from(3)));
+    let client = ClientEndpoint::new(server.transport()).unwrap();
+    let request = Request::new("add", Some(Params::Named(BTreeMap::new())));
+
+    client.send_request_sync(request.clone(), &Duration::seconds(1)).unwrap();
+    client.set_send_timeouts(true);
+    client.send_request_sync(request.clone(), &Duration::seconds(1)).unwrap();
+    client.set_send_timeouts(false);
+    client.send_request_sync(request, &Duration::seconds(1)).unwrap();
+
+    let timeouts: Vec<Option<Duration>> = server.received().iter().map(Request::timeout).collect();
+    assert_eq!(timeouts, vec![None, Some(Duration::seconds(1)), None]);
+
+    // A request without parameters is sent without parameters
+    client.set_send_timeouts(true);
+    client.send_request_sync(Request::new("add", None), &Duration::seconds(1)).unwrap();
+    assert!(server.received()[3].params.is_none());
+}
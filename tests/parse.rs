@@ -0,0 +1,167 @@
+//!
+//! Property tests that feed arbitrary input through the message parsers and the server, which
+//! must return errors instead of panicking
+//!
+
+extern crate jsonrpc2;
+extern crate proptest;
+
+use jsonrpc2::codec::{Codec, JSONCodec, JSONRPC1Codec};
+use jsonrpc2::message::{Request, Response, BatchResponse, Params, Error, Value};
+use jsonrpc2::server::ServerEndpoint;
+use jsonrpc2::server::stream::{ServerStreamTransport, serve_connection};
+use proptest::prelude::*;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+
+/// Generates arbitrary JSON values, biased toward the keys that the parsers look for
+fn arb_json() -> impl Strategy<Value = Value> {
+    let key = prop_oneof![
+        Just("jsonrpc".to_string()),
+        Just("method".to_string()),
+        Just("params".to_string()),
+        Just("id".to_string()),
+        Just("result".to_string()),
+        Just("error".to_string()),
+        Just("code".to_string()),
+        Just("message".to_string()),
+        Just("data".to_string()),
+        ".*",
+    ];
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        any::<f64>().prop_map(Value::from),
+        ".*".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(4, 64, 8, move |inner| prop_oneof![
+        prop::collection::vec(inner.clone(), 0..8).prop_map(Value::Array),
+        prop::collection::vec((key.clone(), inner), 0..8)
+            .prop_map(|entries| Value::Object(entries.into_iter().collect())),
+    ])
+}
+
+/// Handles requests by echoing their parameters
+fn echo(request: Request) -> Result<Value, Error> {
+    Ok(request.params.map(|params| params.to_json()).unwrap_or(Value::Null))
+}
+
+/// A request that is sent after arbitrary input to check that the server still responds
+const PROBE: &str = "{\"jsonrpc\":\"2.0\",\"method\":\"probe\",\"params\":[],\"id\":\"probe\"}";
+
+/// Input that blocks until data is sent through a channel, and ends when the sender is dropped
+struct ChannelInput {
+    /// Channel that receives the data
+    channel: Receiver<Vec<u8>>,
+    /// Data that has been received but not yet read
+    buffer: io::Cursor<Vec<u8>>,
+}
+
+impl Read for ChannelInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = self.buffer.read(buf)?;
+            if count != 0 || buf.is_empty() {
+                return Ok(count);
+            }
+            match self.channel.recv() {
+                Ok(data) => self.buffer = io::Cursor::new(data),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Output that can be read after the server has finished
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///
+/// Sends input and then a probe request through a ServerStreamTransport, and returns the
+/// responses after the reader thread stops
+///
+fn serve_stream(input: &[u8]) -> String {
+    let (tx, rx) = channel();
+    let output = SharedOutput::default();
+    let transport = ServerStreamTransport::new(ChannelInput { channel: rx, buffer: io::Cursor::new(Vec::new()) },
+        output.clone()).unwrap();
+    let server = ServerEndpoint::new(transport, (echo, |_: Request| {})).unwrap();
+    let mut data = input.to_vec();
+    data.push(b'\n');
+    data.extend_from_slice(PROBE.as_bytes());
+    data.push(b'\n');
+    tx.send(data).unwrap();
+    drop(tx);
+    server.run();
+    let output = output.0.lock().unwrap().clone();
+    String::from_utf8(output).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn parse_arbitrary_text(text in ".*") {
+        let _ = text.parse::<Request>();
+        let _ = text.parse::<Response>();
+        let _ = JSONCodec.decode(text.as_bytes());
+        let _ = JSONRPC1Codec.decode(text.as_bytes());
+    }
+
+    #[test]
+    fn parse_arbitrary_json(json in arb_json()) {
+        let _ = Request::from_json(json.clone());
+        let _ = Error::from_json(json.clone());
+        let _ = Params::from_json(json.clone());
+        let _ = BatchResponse::from_json(json.clone());
+        if let Value::Object(map) = json.clone() {
+            let _ = Response::from_json(map.into_iter().collect());
+        }
+        let _ = JSONRPC1Codec.decode(json.to_string().as_bytes());
+    }
+
+    #[test]
+    fn serve_arbitrary_bytes(input in prop::collection::vec(any::<u8>(), 0..512)) {
+        let mut output = Vec::new();
+        let result = serve_connection(&input[..], &mut output, (echo, |_: Request| {}));
+        prop_assert!(result.is_ok(), "serve_connection failed: {:?}", result);
+        // Every reply must be valid JSON
+        for line in String::from_utf8(output).unwrap().lines() {
+            prop_assert!(JSONCodec.decode(line.as_bytes()).is_ok(), "Invalid reply {:?}", line);
+        }
+
+        // The reader thread must still be running to answer the probe
+        let output = serve_stream(&input);
+        for line in output.lines() {
+            prop_assert!(JSONCodec.decode(line.as_bytes()).is_ok(), "Invalid reply {:?}", line);
+        }
+        let last = output.lines().last().map(|line| JSONCodec.decode(line.as_bytes()).unwrap());
+        prop_assert_eq!(last.as_ref().and_then(|reply| reply.get("id")), Some(&Value::from("probe")));
+    }
+
+    #[test]
+    fn serve_arbitrary_json(messages in prop::collection::vec(arb_json(), 0..8)) {
+        let mut input = String::new();
+        for message in &messages {
+            input.push_str(&message.to_string());
+            input.push('\n');
+        }
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, (echo, |_: Request| {})).unwrap();
+        for line in String::from_utf8(output).unwrap().lines() {
+            prop_assert!(JSONCodec.decode(line.as_bytes()).is_ok(), "Invalid reply {:?}", line);
+        }
+    }
+}
@@ -0,0 +1,695 @@
+//!
+//! Tests that send requests from a client endpoint to a server endpoint through the stream
+//! transports
+//!
+
+extern crate jsonrpc2;
+extern crate chrono;
+
+use jsonrpc2::client::{ClientEndpoint, CallError, ResponseHandler, PayloadError};
+use jsonrpc2::client::stream::ClientStreamTransport;
+use jsonrpc2::client::reconnect::ReconnectingClientTransport;
+use jsonrpc2::server::{ServerEndpoint, ServerConfig};
+use jsonrpc2::server::stream::{ServerStreamTransport, serve_connection, serve_connection_with_config};
+use jsonrpc2::message::{Request, Response, Notification, Params, Error, Value};
+use jsonrpc2::RequestHandler;
+use jsonrpc2::spawn::{Spawn, ThreadSpawner};
+use jsonrpc2::transport::{ClientTransport, TransportError, Direction};
+use jsonrpc2::codec::{Codec, JSONCodec, PrettyJSONCodec};
+use chrono::{Duration, Local};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+/// The reading end of an in-memory pipe
+struct PipeReader {
+    /// Channel that receives written data
+    channel: Receiver<Vec<u8>>,
+    /// Data that has been received but not yet read
+    buffer: Vec<u8>,
+    /// The position in the buffer of the next byte to read
+    position: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            match self.channel.recv() {
+                Ok(data) => {
+                    self.buffer = data;
+                    self.position = 0;
+                },
+                // Writer has been dropped
+                Err(_) => return Ok(0),
+            }
+        }
+        let count = (&self.buffer[self.position..]).read(buf)?;
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// The writing end of an in-memory pipe
+struct PipeWriter {
+    /// Channel that sends written data
+    channel: Sender<Vec<u8>>,
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.channel.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Pipe reader has been dropped")),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Creates an in-memory pipe
+fn pipe() -> (PipeReader, PipeWriter) {
+    let (tx, rx) = channel();
+    let reader = PipeReader {
+        channel: rx,
+        buffer: Vec::new(),
+        position: 0,
+    };
+    (reader, PipeWriter { channel: tx })
+}
+
+/// Creates a server with the provided handler and a client connected to it
+fn connect<H>(handler: H) -> (ClientEndpoint, ServerEndpoint<ServerStreamTransport>) where H: RequestHandler {
+    let (server_input, client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::new(server_input, server_output).unwrap();
+    let server = ServerEndpoint::new(server_transport, handler).unwrap();
+    let client_transport = ClientStreamTransport::new(client_input, client_output).unwrap();
+    let client = ClientEndpoint::new(client_transport).unwrap();
+    (client, server)
+}
+
+/// Handles requests. The add method returns the sum of its positional parameters.
+fn handle_request(request: Request) -> Result<Value, Error> {
+    match request.method.as_ref() {
+        "add" => match request.params {
+            Some(Params::Positional(values)) => {
+                let mut sum = 0;
+                for value in values {
+                    sum += value.as_u64().ok_or(Error::invalid_params())?;
+                }
+                Ok(Value::from(sum))
+            },
+            _ => Err(Error::invalid_params()),
+        },
+        _ => Err(Error::method_not_found()),
+    }
+}
+
+fn timeout() -> Duration {
+    Duration::seconds(5)
+}
+
+#[test]
+fn request_result() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let params = Params::Positional(vec![Value::from(2), Value::from(3)]);
+    let response = client.send_request_sync(Request::new("add", Some(params)), &timeout()).unwrap();
+    assert_eq!(response.payload.unwrap(), Value::from(5));
+}
+
+#[test]
+fn request_only_handler() {
+    let (client, _server) = connect(handle_request);
+    client.send_notification(Notification::new("add", None)).unwrap();
+    let sum: u64 = client.call("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(sum, 5);
+}
+
+#[test]
+fn request_error() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let response = client.send_request_sync(Request::new("subtract", None), &timeout()).unwrap();
+    let error = response.payload.unwrap_err();
+    assert_eq!(error.to_json(), Error::method_not_found().to_json());
+}
+
+#[test]
+fn call() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let sum: u64 = client.call("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(sum, 5);
+}
+
+#[test]
+fn call_error() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    match client.call::<_, u64>("subtract", &(2, 3), &timeout()) {
+        Err(CallError::Rpc(error)) => assert_eq!(error.to_json(), Error::method_not_found().to_json()),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn call_with_id() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let (id, sum) = client.call_with_id::<_, u64>("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(id, Value::from(0));
+    assert_eq!(sum.unwrap(), 5);
+
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let (id, result) = client.call_with_id::<_, u64>("subtract", &(2, 3), &timeout()).unwrap();
+    assert_eq!(id, Value::from(0));
+    match result {
+        Err(CallError::Rpc(error)) => assert_eq!(error.to_json(), Error::method_not_found().to_json()),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn many_calls() {
+    // Responses can arrive very quickly, so each handler must be registered before its request is sent
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    for i in 0..200u64 {
+        let sum: u64 = client.call("add", &(i, 1), &timeout()).unwrap();
+        assert_eq!(sum, i + 1);
+    }
+}
+
+#[test]
+fn batch() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let (tx, rx) = channel();
+    let mut requests: Vec<(Request, Box<dyn ResponseHandler>)> = Vec::new();
+    for i in 0..3 {
+        let tx = Mutex::new(tx.clone());
+        let params = Params::Positional(vec![Value::from(i), Value::from(1)]);
+        requests.push((Request::new("add", Some(params)), Box::new(move |response: Response| {
+            tx.lock().unwrap().send(response.payload.unwrap()).unwrap();
+        })));
+    }
+    let ids = client.send_batch(requests).unwrap();
+    assert_eq!(ids.len(), 3);
+    let mut sums: Vec<u64> = (0..3)
+        .map(|_| rx.recv_timeout(timeout().to_std().unwrap()).unwrap().as_u64().unwrap())
+        .collect();
+    sums.sort();
+    assert_eq!(sums, vec![1, 2, 3]);
+}
+
+#[test]
+fn batch_sync() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let requests = vec![
+        Request::new("add", Some(Params::Positional(vec![Value::from(2), Value::from(3)]))),
+        Request::new("subtract", None),
+    ];
+    let batch = client.send_batch_sync(requests, &timeout()).unwrap();
+    assert_eq!(batch.len(), 2);
+    assert!(batch.has_errors());
+    assert_eq!(batch.get(&Value::from(0)).unwrap().payload, Ok(Value::from(5)));
+    let errors: Vec<_> = batch.errors().collect();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].id, Some(Value::from(1)));
+}
+
+#[test]
+fn call_all() {
+    let (client, _server) = connect((handle_request, |_: Request| {}));
+    let requests = vec![
+        Request::new("add", Some(Params::Positional(vec![Value::from(2), Value::from(3)]))),
+        Request::new("subtract", None),
+        Request::new("add", Some(Params::Positional(vec![Value::from(4)]))),
+    ];
+    let results = client.call_all(requests, &timeout());
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().unwrap().payload, Ok(Value::from(5)));
+    assert_eq!(results[1].as_ref().unwrap().payload, Err(Error::method_not_found()));
+    assert_eq!(results[2].as_ref().unwrap().payload, Ok(Value::from(4)));
+}
+
+#[test]
+fn notification() {
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let handle_notification = move |notification: Request| {
+        tx.lock().unwrap().send(notification.method).unwrap();
+    };
+    let (client, _server) = connect((handle_request, handle_notification));
+    client.send_notification(Notification::new("notify", None)).unwrap();
+    let method = rx.recv_timeout(timeout().to_std().unwrap()).unwrap();
+    assert_eq!(method, "notify");
+}
+
+#[test]
+fn nul_delimiter() {
+    let (server_input, client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::with_delimiter(server_input, server_output, 0).unwrap();
+    let _server = ServerEndpoint::new(server_transport, (handle_request, |_: Request| {})).unwrap();
+    let client_transport = ClientStreamTransport::with_delimiter(client_input, client_output, 0).unwrap();
+    let client = ClientEndpoint::new(client_transport).unwrap();
+    let sum: u64 = client.call("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(sum, 5);
+}
+
+#[test]
+fn pretty_json() {
+    let (server_input, client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::with_delimiter(server_input, server_output, 0).unwrap();
+    let config = ServerConfig { codec: Arc::new(PrettyJSONCodec), ..ServerConfig::default() };
+    let _server = ServerEndpoint::with_config(server_transport, (handle_request, |_: Request| {}), config).unwrap();
+    let client_transport = ClientStreamTransport::with_delimiter(client_input, client_output, 0).unwrap();
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let client = ClientEndpoint::builder(client_transport)
+        .codec(PrettyJSONCodec)
+        .wire_logger(move |_: Direction, payload: &[u8]| tx.lock().unwrap().send(String::from_utf8_lossy(payload).into_owned()).unwrap())
+        .build()
+        .unwrap();
+    let sum: u64 = client.call("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(sum, 5);
+    // The request and the response are both indented
+    for _ in 0..2 {
+        assert!(rx.recv_timeout(timeout().to_std().unwrap()).unwrap().contains("\n  "));
+    }
+}
+
+/// A codec whose payloads are not text: compact JSON after a 0xff byte, which never appears in UTF-8
+struct MarkedCodec;
+
+impl Codec for MarkedCodec {
+    fn encode(&self, message: &Value) -> Result<Vec<u8>, TransportError> {
+        let mut payload = vec![0xff];
+        payload.extend(JSONCodec.encode(message)?);
+        Ok(payload)
+    }
+    fn decode(&self, payload: &[u8]) -> Result<Value, TransportError> {
+        match payload.split_first() {
+            Some((&0xff, json)) => JSONCodec.decode(json),
+            _ => Err(TransportError::ParseError("Missing marker".to_string())),
+        }
+    }
+    fn may_contain(&self, delimiter: u8) -> bool {
+        delimiter == 0xff
+    }
+}
+
+#[test]
+fn binary_codec() {
+    let (server_input, client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::new(server_input, server_output).unwrap();
+    let config = ServerConfig { codec: Arc::new(MarkedCodec), ..ServerConfig::default() };
+    let _server = ServerEndpoint::with_config(server_transport, (handle_request, |_: Request| {}), config).unwrap();
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let client = ClientEndpoint::builder(ClientStreamTransport::new(client_input, client_output).unwrap())
+        .codec(MarkedCodec)
+        .wire_logger(move |_: Direction, payload: &[u8]| tx.lock().unwrap().send(payload.to_vec()).unwrap())
+        .build()
+        .unwrap();
+    let sum: u64 = client.call("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(sum, 5);
+    // The request and the response are both passed through unchanged
+    for _ in 0..2 {
+        assert_eq!(rx.recv_timeout(timeout().to_std().unwrap()).unwrap()[0], 0xff);
+    }
+}
+
+#[test]
+fn pretty_json_newline_rejected() {
+    let (_server_input, client_output) = pipe();
+    let (client_input, _server_output) = pipe();
+    let client_transport = ClientStreamTransport::new(client_input, client_output).unwrap();
+    match ClientEndpoint::builder(client_transport).codec(PrettyJSONCodec).build() {
+        Err(TransportError::IOError(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Endpoint created with a newline delimiter"),
+    }
+
+    let config = ServerConfig { codec: Arc::new(PrettyJSONCodec), ..ServerConfig::default() };
+    let mut output = Vec::new();
+    let input = "{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[2,3],\"id\":1}\n";
+    let result = serve_connection_with_config(input.as_bytes(), &mut output, (handle_request, |_: Request| {}), config);
+    assert!(result.is_err());
+    assert!(output.is_empty());
+}
+
+#[test]
+fn pretty_json_newline_rejected_server() {
+    let (server_input, _client_output) = pipe();
+    let (_client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::new(server_input, server_output).unwrap();
+    let config = ServerConfig { codec: Arc::new(PrettyJSONCodec), ..ServerConfig::default() };
+    match ServerEndpoint::with_config(server_transport, (handle_request, |_: Request| {}), config) {
+        Err(TransportError::IOError(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Endpoint created with a newline delimiter"),
+    }
+}
+
+#[test]
+fn printable_delimiter_rejected() {
+    // JSON payloads can contain any printable character, so only control characters are accepted
+    for &delimiter in b"},\" " {
+        let (_server_input, client_output) = pipe();
+        let (client_input, _server_output) = pipe();
+        let client_transport = ClientStreamTransport::with_delimiter(client_input, client_output, delimiter).unwrap();
+        match ClientEndpoint::new(client_transport) {
+            Err(TransportError::IOError(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Client created with delimiter {:?}", delimiter as char),
+        }
+
+        let (server_input, _client_output) = pipe();
+        let (_client_input, server_output) = pipe();
+        let server_transport = ServerStreamTransport::with_delimiter(server_input, server_output, delimiter).unwrap();
+        match ServerEndpoint::new(server_transport, (handle_request, |_: Request| {})) {
+            Err(TransportError::IOError(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Server created with delimiter {:?}", delimiter as char),
+        }
+    }
+}
+
+#[test]
+fn custom_spawner() {
+    let names = Arc::new(Mutex::new(Vec::new()));
+    let spawner = {
+        let names = names.clone();
+        move |name: String, task: Box<dyn FnOnce() + Send>| {
+            names.lock().unwrap().push(name.clone());
+            ThreadSpawner.spawn(name, task)
+        }
+    };
+    let (server_input, client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::with_spawner(server_input, server_output, b'\n', &spawner).unwrap();
+    let _server = ServerEndpoint::new(server_transport, (handle_request, |_: Request| {})).unwrap();
+    let client_transport = ClientStreamTransport::with_spawner(client_input, client_output, b'\n', &spawner).unwrap();
+    let client = ClientEndpoint::builder(client_transport).spawner(spawner).build().unwrap();
+    let sum: u64 = client.call("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(sum, 5);
+    // The server reader, the client reader, and the client writer
+    assert_eq!(names.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn server_termination_handler() {
+    let (server_input, client_output) = pipe();
+    let (_client_input, server_output) = pipe();
+    let mut server_transport = ServerStreamTransport::new(server_input, server_output).unwrap();
+    let (tx, rx) = channel();
+    server_transport.set_termination_handler(move || tx.send(()).unwrap());
+    let _server = ServerEndpoint::new(server_transport, (handle_request, |_: Request| {})).unwrap();
+    // Disconnect the client
+    drop(client_output);
+    rx.recv_timeout(timeout().to_std().unwrap()).unwrap();
+}
+
+/// A reader that fails with an error that the transport does not handle specially
+struct FailingReader;
+
+impl Read for FailingReader {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Read failed"))
+    }
+}
+
+#[test]
+fn server_read_error() {
+    // Runs the reader and reports whether it panicked
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let spawner = move |name: String, task: Box<dyn FnOnce() + Send>| {
+        let tx = tx.lock().unwrap().clone();
+        ThreadSpawner.spawn(name, Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(task));
+            tx.send(result.is_ok()).unwrap();
+        }))
+    };
+    let server_transport = ServerStreamTransport::with_spawner(FailingReader, io::sink(), b'\n', &spawner).unwrap();
+    let _server = ServerEndpoint::new(server_transport, (handle_request, |_: Request| {})).unwrap();
+    // The reader stops after the error
+    assert!(rx.recv_timeout(timeout().to_std().unwrap()).unwrap(), "Server reader panicked");
+}
+
+#[test]
+fn server_client_disconnected_before_response() {
+    // Runs the reader and reports whether it panicked
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let spawner = move |name: String, task: Box<dyn FnOnce() + Send>| {
+        let tx = tx.lock().unwrap().clone();
+        ThreadSpawner.spawn(name, Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(task));
+            tx.send(result.is_ok()).unwrap();
+        }))
+    };
+    let (server_input, mut client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::with_spawner(server_input, server_output, b'\n', &spawner).unwrap();
+    let _server = ServerEndpoint::new(server_transport, (handle_request, |_: Request| {})).unwrap();
+    // The client sends a request and disconnects before the response is written
+    drop(client_input);
+    client_output.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}\n").unwrap();
+    assert!(rx.recv_timeout(timeout().to_std().unwrap()).unwrap(), "Server reader panicked");
+}
+
+#[test]
+fn server_invalid_utf8() {
+    let (server_input, mut client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server_transport = ServerStreamTransport::new(server_input, server_output).unwrap();
+    let _server = ServerEndpoint::new(server_transport, (handle_request, |_: Request| {})).unwrap();
+    // The codec cannot decode the invalid payload, and the reader continues with the next one
+    client_output.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"\xff\xfe\",\"id\":1}\n").unwrap();
+    client_output.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":2}\n").unwrap();
+    let mut client_input = BufReader::new(client_input);
+    let mut response = String::new();
+    client_input.read_line(&mut response).unwrap();
+    assert_eq!(response, "{\"error\":{\"code\":-32700,\"message\":\"Parse error\"},\"id\":null,\"jsonrpc\":\"2.0\"}\n");
+    response.clear();
+    client_input.read_line(&mut response).unwrap();
+    assert_eq!(response, "{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":3}\n");
+}
+
+#[test]
+fn serve_connection_invalid_utf8() {
+    let input = b"\xff\n{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}\n";
+    let mut output = Vec::new();
+    serve_connection(&input[..], &mut output, (handle_request, |_: Request| {})).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "{\"error\":{\"code\":-32700,\"message\":\"Parse error\"},\"id\":null,\"jsonrpc\":\"2.0\"}\n\
+        {\"id\":1,\"jsonrpc\":\"2.0\",\"result\":3}\n");
+}
+
+#[test]
+fn serve_connection_crlf() {
+    let input = "{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}\r\n";
+    let mut output = Vec::new();
+    serve_connection(input.as_bytes(), &mut output, (handle_request, |_: Request| {})).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":3}\n");
+}
+
+#[test]
+fn client_crlf() {
+    let input: &'static [u8] = b"{\"jsonrpc\":\"2.0\",\"result\":3,\"id\":1}\r\n";
+    let mut transport = ClientStreamTransport::new(input, io::sink()).unwrap();
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    transport.set_payload_handler(move |payload: Result<Vec<u8>, TransportError>| {
+        if let Ok(payload) = payload {
+            tx.lock().unwrap().send(payload).unwrap();
+        }
+    });
+    let payload = rx.recv_timeout(timeout().to_std().unwrap()).unwrap();
+    assert_eq!(payload, b"{\"jsonrpc\":\"2.0\",\"result\":3,\"id\":1}");
+}
+
+#[test]
+fn invalid_payloads() {
+    let input: &'static [u8] = b"not json\n\
+        [1]\n\
+        {\"jsonrpc\":\"2.0\",\"result\":1}\n\
+        {\"jsonrpc\":\"2.0\",\"result\":1,\"error\":{\"code\":1,\"message\":\"m\"},\"id\":1}\n\
+        {\"jsonrpc\":\"2.0\",\"id\":1}\n\
+        {\"jsonrpc\":\"2.0\",\"error\":1,\"id\":1}\n\
+        {\"jsonrpc\":\"2.0\",\"result\":1,\"id\":99}\n";
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let _client = ClientEndpoint::builder(ClientStreamTransport::new(input, io::sink()).unwrap())
+        .invalid_payload_handler(move |_: &[u8], error: PayloadError| tx.lock().unwrap().send(error).unwrap())
+        .build()
+        .unwrap();
+    let wait = timeout().to_std().unwrap();
+    match rx.recv_timeout(wait).unwrap() {
+        PayloadError::InvalidJson(_) => {},
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert_eq!(rx.recv_timeout(wait).unwrap(), PayloadError::NotAnObject);
+    assert_eq!(rx.recv_timeout(wait).unwrap(), PayloadError::MissingId);
+    assert_eq!(rx.recv_timeout(wait).unwrap(), PayloadError::ResultAndError);
+    assert_eq!(rx.recv_timeout(wait).unwrap(), PayloadError::NoResultOrError);
+    assert_eq!(rx.recv_timeout(wait).unwrap(), PayloadError::InvalidError);
+    assert_eq!(rx.recv_timeout(wait).unwrap(), PayloadError::UnknownId(Value::from(99)));
+}
+
+#[test]
+fn disconnect() {
+    let (_, client_output) = pipe();
+    let transport = ClientStreamTransport::new(io::empty(), client_output).unwrap();
+    let client = ClientEndpoint::new(transport).unwrap();
+    let end = Local::now() + timeout();
+    while client.is_connected() && Local::now() < end {
+        thread::sleep(StdDuration::from_millis(1));
+    }
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn close_send() {
+    let (server_input, client_output) = pipe();
+    let (client_input, server_output) = pipe();
+    let server = thread::spawn(move || {
+        serve_connection(server_input, server_output, (handle_request, |_: Request| {})).unwrap();
+    });
+    let client = ClientEndpoint::new(ClientStreamTransport::new(client_input, client_output).unwrap()).unwrap();
+    let (tx, rx) = channel();
+    for i in 0..3 {
+        let tx = Mutex::new(tx.clone());
+        let params = Params::Positional(vec![Value::from(i), Value::from(1)]);
+        client.send_request(Request::new("add", Some(params)), move |response: Response| {
+            tx.lock().unwrap().send(response.payload).unwrap();
+        }).unwrap();
+    }
+    client.close_send().unwrap();
+    // The server reaches the end of its input after answering the requests
+    server.join().unwrap();
+    let mut results: Vec<u64> = (0..3)
+        .map(|_| rx.recv_timeout(StdDuration::from_secs(5)).unwrap().unwrap().as_u64().unwrap())
+        .collect();
+    results.sort();
+    assert_eq!(results, vec![1, 2, 3]);
+    match client.send_notification(Notification::new("add", None)) {
+        Err(TransportError::EndOfFile) => {},
+        other => panic!("Expected EndOfFile, got {:?}", other),
+    }
+    client.close_send().unwrap();
+}
+
+#[test]
+fn close_send_concurrent() {
+    for _ in 0..20 {
+        let (server_input, client_output) = pipe();
+        let (client_input, server_output) = pipe();
+        let received = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let received = received.clone();
+            thread::spawn(move || {
+                let count = move |_: Request| { received.fetch_add(1, Ordering::SeqCst); };
+                serve_connection(server_input, server_output, (handle_request, count)).unwrap();
+            })
+        };
+        let client = Arc::new(ClientEndpoint::new(ClientStreamTransport::new(client_input, client_output).unwrap()).unwrap());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4).map(|i| {
+            let client = client.clone();
+            let sent = sent.clone();
+            thread::spawn(move || {
+                if i < 2 {
+                    client.close_send().unwrap();
+                } else {
+                    for _ in 0..50 {
+                        match client.send_notification(Notification::new("add", None)) {
+                            Ok(()) => { sent.fetch_add(1, Ordering::SeqCst); },
+                            Err(TransportError::EndOfFile) => {},
+                            other => panic!("Unexpected result {:?}", other),
+                        }
+                    }
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        server.join().unwrap();
+        // Every notification that was accepted was written before the output was closed
+        assert_eq!(received.load(Ordering::SeqCst), sent.load(Ordering::SeqCst));
+    }
+}
+
+#[test]
+fn serve_connection_until_eof() {
+    let input = "{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}\n\
+                 {\"jsonrpc\":\"2.0\",\"method\":\"notify\"}\n";
+    let mut output = Vec::new();
+    serve_connection(input.as_bytes(), &mut output, (handle_request, |_: Request| {})).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":3}\n");
+}
+
+#[test]
+fn subscribe() {
+    let (server_input, client_output) = pipe();
+    let (client_input, mut server_output) = pipe();
+    let client = ClientEndpoint::new(ClientStreamTransport::new(client_input, client_output).unwrap()).unwrap();
+    // Responds to the subscribe request, then sends notifications for this and another subscription
+    thread::spawn(move || {
+        let mut lines = BufReader::new(server_input).lines();
+        let request: Request = lines.next().unwrap().unwrap().parse().unwrap();
+        assert_eq!(request.method, "subscribe");
+        thread::sleep(StdDuration::from_millis(50));
+        let id = request.id.unwrap();
+        writeln!(server_output, "{{\"jsonrpc\":\"2.0\",\"result\":\"s1\",\"id\":{}}}", id).unwrap();
+        for (subscription, result) in &[("s1", 1), ("s2", 2), ("s1", 3)] {
+            writeln!(server_output, "{{\"jsonrpc\":\"2.0\",\"method\":\"subscription\",\
+                \"params\":{{\"subscription\":\"{}\",\"result\":{}}}}}", subscription, result).unwrap();
+        }
+    });
+    let (id, notifications) = client.subscribe("subscribe", None, &timeout()).unwrap();
+    assert_eq!(id, Value::from("s1"));
+    let wait = timeout().to_std().unwrap();
+    assert_eq!(notifications.recv_timeout(wait).unwrap(), Value::from(1));
+    assert_eq!(notifications.recv_timeout(wait).unwrap(), Value::from(3));
+}
+
+#[test]
+fn reconnect() {
+    let mut connections = 0;
+    let transport = ReconnectingClientTransport::new(move || {
+        let (server_input, client_output) = pipe();
+        let (client_input, mut server_output) = pipe();
+        connections += 1;
+        let first = connections == 1;
+        // The first server closes the connection after reading one request. Later servers
+        // respond to each request.
+        thread::spawn(move || {
+            for line in BufReader::new(server_input).lines() {
+                let request: Request = line.unwrap().parse().unwrap();
+                thread::sleep(StdDuration::from_millis(50));
+                if first {
+                    return;
+                }
+                let id = request.id.unwrap();
+                writeln!(server_output, "{{\"jsonrpc\":\"2.0\",\"result\":5,\"id\":{}}}", id).unwrap();
+            }
+        });
+        ClientStreamTransport::new(client_input, client_output)
+    }).unwrap();
+    let client = ClientEndpoint::new(transport).unwrap();
+    let params = Params::Positional(vec![Value::from(2), Value::from(3)]);
+    let response = client.send_request_sync(Request::new("add", Some(params)), &timeout()).unwrap();
+    assert_eq!(response.payload.unwrap_err().code(), Error::internal_error().code());
+    let sum: u64 = client.call("add", &(2, 3), &timeout()).unwrap();
+    assert_eq!(sum, 5);
+    assert!(client.is_connected());
+}
@@ -0,0 +1,223 @@
+//!
+//! Tests that send requests to a server that uses the TCP transport
+//!
+
+extern crate jsonrpc2;
+extern crate chrono;
+
+use jsonrpc2::client::ClientEndpoint;
+use jsonrpc2::client::stream::{ClientStreamTransport, TcpWriteHalf};
+use jsonrpc2::server::{ServerEndpoint, ServerConfig};
+use jsonrpc2::server::tcp::{TCPServerTransport, BindOptions};
+use jsonrpc2::message::{Request, Response, Error, Value};
+use std::io::{Write, BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+use chrono::Duration;
+
+fn handle_request(request: Request) -> Result<Value, Error> {
+    Ok(Value::String(request.method))
+}
+
+/// Sends a request on a connection and returns the response line
+fn send(stream: &mut TcpStream, request: &str) -> String {
+    stream.write_all(request.as_bytes()).unwrap();
+    stream.write_all(b"\n").unwrap();
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn multiple_connections() {
+    let options = BindOptions {
+        reuse_port: true,
+        ..BindOptions::default()
+    };
+    let transport = TCPServerTransport::bind_with_options("127.0.0.1:0", &options).unwrap();
+    let address = transport.local_addr().unwrap();
+    let server = ServerEndpoint::new(transport, (handle_request, |_: Request| {})).unwrap();
+    thread::spawn(move || server.run());
+
+    let mut first = TcpStream::connect(address).unwrap();
+    let mut second = TcpStream::connect(address).unwrap();
+    let response = send(&mut second, "{\"jsonrpc\":\"2.0\",\"method\":\"second\",\"id\":2}");
+    assert_eq!(response, "{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":\"second\"}\n");
+    let response = send(&mut first, "{\"jsonrpc\":\"2.0\",\"method\":\"first\",\"id\":1}");
+    assert_eq!(response, "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"first\"}\n");
+}
+
+#[test]
+fn from_listener() {
+    // A listener that was set up elsewhere, like one inherited from systemd
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let transport = TCPServerTransport::from_listener(listener, &BindOptions::default()).unwrap();
+    assert_eq!(transport.local_addr().unwrap(), address);
+    let server = ServerEndpoint::new(transport, (handle_request, |_: Request| {})).unwrap();
+    thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    let response = send(&mut stream, "{\"jsonrpc\":\"2.0\",\"method\":\"inherited\",\"id\":1}");
+    assert_eq!(response, "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"inherited\"}\n");
+}
+
+#[test]
+fn close_send() {
+    let transport = TCPServerTransport::bind("127.0.0.1:0").unwrap();
+    let address = transport.local_addr().unwrap();
+    let server = ServerEndpoint::new(transport, (handle_request, |_: Request| {})).unwrap();
+    thread::spawn(move || server.run());
+
+    let stream = TcpStream::connect(address).unwrap();
+    let transport = ClientStreamTransport::new(stream.try_clone().unwrap(), TcpWriteHalf(stream)).unwrap();
+    let client = ClientEndpoint::new(transport).unwrap();
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    client.send_request(Request::new("last", None), move |response: Response| {
+        tx.lock().unwrap().send(response.payload).unwrap();
+    }).unwrap();
+    client.close_send().unwrap();
+    let result = rx.recv_timeout(StdDuration::from_secs(5)).unwrap();
+    assert_eq!(result, Ok(Value::String("last".to_string())));
+    // The server closes the connection after it reads the end of file
+    let end = Instant::now() + StdDuration::from_secs(5);
+    while client.is_connected() && Instant::now() < end {
+        thread::sleep(StdDuration::from_millis(1));
+    }
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn concurrent_connections() {
+    fn handle(request: Request) -> Result<Value, Error> {
+        if request.method == "slow" {
+            thread::sleep(StdDuration::from_millis(2000));
+        }
+        handle_request(request)
+    }
+    let transport = TCPServerTransport::bind("127.0.0.1:0").unwrap();
+    let address = transport.local_addr().unwrap();
+    let server = ServerEndpoint::with_handler_factory(transport, || (handle, |_: Request| {}), ServerConfig::default()).unwrap();
+    thread::spawn(move || server.run());
+
+    let mut slow = TcpStream::connect(address).unwrap();
+    slow.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"slow\",\"id\":1}\n").unwrap();
+    thread::sleep(StdDuration::from_millis(50));
+    // A request on another connection does not wait for the slow request
+    let start = Instant::now();
+    let mut fast = TcpStream::connect(address).unwrap();
+    let response = send(&mut fast, "{\"jsonrpc\":\"2.0\",\"method\":\"fast\",\"id\":2}");
+    assert_eq!(response, "{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":\"fast\"}\n");
+    assert!(start.elapsed() < StdDuration::from_millis(1000));
+}
+
+#[test]
+fn delimiter() {
+    let options = BindOptions {
+        delimiter: 0,
+        ..BindOptions::default()
+    };
+    let transport = TCPServerTransport::bind_with_options("127.0.0.1:0", &options).unwrap();
+    let address = transport.local_addr().unwrap();
+    let server = ServerEndpoint::new(transport, (handle_request, |_: Request| {})).unwrap();
+    thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"{\"jsonrpc\":\"2.0\",\n\"method\":\"nul\",\"id\":1}\0").unwrap();
+    let mut response = Vec::new();
+    BufReader::new(stream).read_until(0, &mut response).unwrap();
+    assert_eq!(response, b"{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"nul\"}\0");
+}
+
+#[test]
+fn idle_timeout() {
+    let options = BindOptions {
+        idle_timeout: Some(Duration::milliseconds(100)),
+        ..BindOptions::default()
+    };
+    let transport = TCPServerTransport::bind_with_options("127.0.0.1:0", &options).unwrap();
+    let address = transport.local_addr().unwrap();
+    let server = ServerEndpoint::new(transport, (handle_request, |_: Request| {})).unwrap();
+    thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    let response = send(&mut stream, "{\"jsonrpc\":\"2.0\",\"method\":\"m\",\"id\":1}");
+    assert_eq!(response, "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"m\"}\n");
+    // Send part of a payload and then wait for the server to close the connection
+    stream.write_all(b"{\"jsonrpc\"").unwrap();
+    stream.set_read_timeout(Some(StdDuration::from_secs(5))).unwrap();
+    let mut rest = String::new();
+    assert_eq!(BufReader::new(stream).read_line(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn max_connections() {
+    let options = BindOptions {
+        max_connections: Some(1),
+        ..BindOptions::default()
+    };
+    let transport = TCPServerTransport::bind_with_options("127.0.0.1:0", &options).unwrap();
+    let address = transport.local_addr().unwrap();
+    let server = ServerEndpoint::new(transport, (handle_request, |_: Request| {})).unwrap();
+    thread::spawn(move || server.run());
+
+    let mut first = TcpStream::connect(address).unwrap();
+    let response = send(&mut first, "{\"jsonrpc\":\"2.0\",\"method\":\"first\",\"id\":1}");
+    assert_eq!(response, "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"first\"}\n");
+    // The server closes a second connection without reading from it
+    let second = TcpStream::connect(address).unwrap();
+    second.set_read_timeout(Some(StdDuration::from_secs(5))).unwrap();
+    let mut rest = String::new();
+    assert_eq!(BufReader::new(second).read_line(&mut rest).unwrap_or(0), 0);
+
+    // After the first connection closes, the server accepts another one
+    drop(first);
+    for _ in 0..50 {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"third\",\"id\":3}\n").unwrap();
+        let mut response = String::new();
+        if BufReader::new(stream).read_line(&mut response).unwrap_or(0) != 0 {
+            assert_eq!(response, "{\"id\":3,\"jsonrpc\":\"2.0\",\"result\":\"third\"}\n");
+            return;
+        }
+        thread::sleep(StdDuration::from_millis(20));
+    }
+    panic!("Server did not accept a connection after the first one closed");
+}
+
+#[test]
+fn drain() {
+    fn slow(request: Request) -> Result<Value, Error> {
+        thread::sleep(StdDuration::from_millis(200));
+        handle_request(request)
+    }
+    let transport = TCPServerTransport::bind("127.0.0.1:0").unwrap();
+    let address = transport.local_addr().unwrap();
+    let shutdown = transport.shutdown_handle();
+    let server = ServerEndpoint::new(transport, (slow, |_: Request| {})).unwrap();
+    let server_thread = thread::spawn(move || server.run());
+
+    // One connection has a request in progress, and the other is idle
+    let mut busy = TcpStream::connect(address).unwrap();
+    busy.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"busy\",\"id\":1}\n").unwrap();
+    let idle = TcpStream::connect(address).unwrap();
+    thread::sleep(StdDuration::from_millis(50));
+
+    assert!(shutdown.drain(&Duration::seconds(5)));
+    server_thread.join().unwrap();
+
+    // The request in progress still gets its response, and then both connections are closed
+    busy.set_read_timeout(Some(StdDuration::from_secs(5))).unwrap();
+    let mut busy = BufReader::new(busy);
+    let mut response = String::new();
+    busy.read_line(&mut response).unwrap();
+    assert_eq!(response, "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"busy\"}\n");
+    assert_eq!(busy.read_line(&mut response).unwrap_or(0), 0);
+    idle.set_read_timeout(Some(StdDuration::from_secs(5))).unwrap();
+    let mut rest = String::new();
+    assert_eq!(BufReader::new(idle).read_line(&mut rest).unwrap_or(0), 0);
+}